@@ -0,0 +1,10 @@
+-- Sessions are keyed by sha256 of the k1 from the cookie, so the table
+-- can't be used to hijack sessions of logged users.
+create table sessions(
+    id text primary key,
+    key text not null,
+    permissions jsonb not null,
+    timeout timestamp not null
+);
+
+create index sessions_timeout_idx on sessions(timeout);
@@ -0,0 +1,7 @@
+alter table sessions
+    add column created_at timestamp not null default (now() at time zone 'utc'),
+    add column last_activity timestamp not null default (now() at time zone 'utc'),
+    add column ip text,
+    add column user_agent text;
+
+create index sessions_key_idx on sessions(key);
\ No newline at end of file
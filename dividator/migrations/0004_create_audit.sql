@@ -0,0 +1,11 @@
+create table audit(
+    id bigserial primary key,
+    created_at timestamp not null default (now() at time zone 'utc'),
+    kind text not null,
+    key text,
+    ip text,
+    details text
+);
+
+create index audit_created_at_idx on audit(created_at);
+create index audit_key_idx on audit(key);
\ No newline at end of file
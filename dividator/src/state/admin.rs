@@ -38,3 +38,21 @@ impl From<AddAdmin> for AdminInfo {
         }
     }
 }
+
+/// Action to move admin account to another wallet. Both wallets sign
+/// the same k1 nonce to prove the consent.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RotateAdmin {
+    /// Public key of the current admin
+    pub old_key: PublicKey,
+    /// Public key of the new admin wallet
+    pub new_key: PublicKey,
+    /// 32 byte hex encoded nonce that both wallets signed
+    pub k1: K1,
+    /// Der encoded signature of the k1 by the current admin
+    pub old_signature: Signature,
+    /// Der encoded signature of the k1 by the new admin wallet
+    pub new_signature: Signature,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
@@ -1,23 +1,55 @@
 pub mod admin;
+pub mod round;
+pub mod schedule;
+pub mod token;
+pub mod user;
 
-use admin::AddAdmin;
+use admin::{AddAdmin, RotateAdmin};
 pub use admin::{AdminInfo, PublicKey, K1};
 use append_db::State;
 use append_db_postgres::HasUpdateTag;
 use append_db_postgres::VersionedState;
+use chrono::NaiveDateTime;
+use round::{
+    AddCorrection, AddExclusion, AddPayment, AddRound, Amount, ApproveRound, CancelRound,
+    PaymentStatus, RemoveExclusion, RemoveRound, SetAdjustments, SetSnapshot, UpdateRound,
+};
+pub use round::{RoundId, RoundInfo, RoundStatus};
+use schedule::{AddSchedule, RemoveSchedule, RunSchedule};
+pub use schedule::{ScheduleId, ScheduleInfo, SchedulePeriod};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use thiserror::Error;
+use token::{AddToken, RevokeToken};
+pub use token::{TokenId, TokenInfo};
+use user::{AddUser, RemoveUser, SetPermissions};
+pub use user::{Permission, UserInfo};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
 pub struct SystemState {
     pub admin: Option<AdminInfo>,
+    /// Linked wallets besides the admin one
+    #[serde(default)]
+    pub users: HashMap<PublicKey, UserInfo>,
+    /// API tokens for machine clients
+    #[serde(default)]
+    pub tokens: HashMap<TokenId, TokenInfo>,
+    /// Dividend distribution rounds
+    #[serde(default)]
+    pub rounds: BTreeMap<RoundId, RoundInfo>,
+    /// Schedules that create draft rounds periodically
+    #[serde(default)]
+    pub schedules: BTreeMap<ScheduleId, ScheduleInfo>,
 }
 
 impl SystemState {
     pub fn new() -> Self {
         SystemState {
             admin: None,
+            users: HashMap::new(),
+            tokens: HashMap::new(),
+            rounds: BTreeMap::new(),
+            schedules: BTreeMap::new(),
         }
     }
 
@@ -29,6 +61,35 @@ impl SystemState {
     pub fn admin_key(&self) -> Option<PublicKey> {
         self.admin.as_ref().map(|v| v.key.clone())
     }
+
+    /// Return permissions of the linked key. Admin key always has `Admin`
+    /// permission. Returns `None` if the key is not known.
+    pub fn permissions(&self, key: &str) -> Option<BTreeSet<Permission>> {
+        match &self.admin {
+            Some(admin) if admin.key == key => Some(BTreeSet::from([Permission::Admin])),
+            _ => self.users.get(key).map(|v| v.permissions.clone()),
+        }
+    }
+
+    /// Id for the next round
+    pub fn next_round_id(&self) -> RoundId {
+        self.rounds.keys().max().map(|v| v + 1).unwrap_or(1)
+    }
+
+    /// Id for the next schedule
+    pub fn next_schedule_id(&self) -> ScheduleId {
+        self.schedules.keys().max().map(|v| v + 1).unwrap_or(1)
+    }
+
+    /// Find the round that is in one of the given statuses
+    fn round_in(&mut self, id: RoundId, statuses: &[RoundStatus]) -> Result<&mut RoundInfo, Error> {
+        let round = self.rounds.get_mut(&id).ok_or(Error::UnknownRound)?;
+        if statuses.contains(&round.status) {
+            Ok(round)
+        } else {
+            Err(Error::RoundStatus(round.status))
+        }
+    }
 }
 
 impl Default for SystemState {
@@ -41,6 +102,46 @@ impl Default for SystemState {
 pub enum Error {
     #[error("We already has admin account linked")]
     AdminRegistered,
+    #[error("There is no admin account linked")]
+    NoAdmin,
+    #[error("The key is not the current admin key")]
+    NotAdmin,
+    #[error("User with the key is already linked")]
+    UserRegistered,
+    #[error("User with the key is not known")]
+    UnknownUser,
+    #[error("Permissions of the admin key cannot be changed")]
+    AdminPermissions,
+    #[error("Token with the id already exists")]
+    TokenExists,
+    #[error("Token with the id is not known")]
+    UnknownToken,
+    #[error("Round with the id already exists")]
+    RoundExists,
+    #[error("Round with the id is not known")]
+    UnknownRound,
+    #[error("The action is not allowed for {0} round")]
+    RoundStatus(RoundStatus),
+    #[error("Round has no holders to pay")]
+    NoPayouts,
+    #[error("Payouts sum to {0} that is more than the round total {1}")]
+    PayoutsExceedTotal(Amount, Amount),
+    #[error("Round has payments and can't be cancelled")]
+    HasPayments,
+    #[error("The account is already paid successfully")]
+    AlreadyPaid,
+    #[error("The account is not excluded from the round")]
+    UnknownExclusion,
+    #[error("The account has no payout in the round")]
+    UnknownPayout,
+    #[error("Round is already corrected by round {0}, correct that one instead")]
+    CorrectionExists(RoundId),
+    #[error("Schedule with the id already exists")]
+    ScheduleExists,
+    #[error("Schedule with the id is not known")]
+    UnknownSchedule,
+    #[error("Schedule already created round for the period ending at {0}")]
+    ScheduleRan(NaiveDateTime),
 }
 
 /// All updates of database goes through that updates
@@ -51,6 +152,47 @@ pub enum SystemUpdate {
     /// Cleanup admin information. Can be done only via CLI.
     /// Empty tuple is required to make deriving happy.
     CleanAdmin(()),
+    /// Move admin account to another wallet, signed by both wallets.
+    /// Normal way to change admin key, `CleanAdmin` is for emergency.
+    RotateAdmin(RotateAdmin),
+    /// Link new user that accepted an invite
+    AddUser(AddUser),
+    /// Replace permissions of linked user
+    SetPermissions(SetPermissions),
+    /// Unlink user from the system
+    RemoveUser(RemoveUser),
+    /// Mint API token
+    AddToken(AddToken),
+    /// Revoke API token
+    RevokeToken(RevokeToken),
+    /// Create draft round
+    AddRound(AddRound),
+    /// Change name, asset or total of the draft round
+    UpdateRound(UpdateRound),
+    /// Delete the draft round
+    RemoveRound(RemoveRound),
+    /// Replace snapshot of the draft round
+    SetSnapshot(SetSnapshot),
+    /// Exclude holder from the draft round
+    AddExclusion(AddExclusion),
+    /// Return excluded holder to the draft round
+    RemoveExclusion(RemoveExclusion),
+    /// Replace manual adjustments of the draft round payouts
+    SetAdjustments(SetAdjustments),
+    /// Fix payouts of the draft round
+    ApproveRound(ApproveRound),
+    /// Cancel the round that is not paid yet
+    CancelRound(CancelRound),
+    /// Record payment of the approved round
+    AddPayment(AddPayment),
+    /// Create draft round that corrects the paid one
+    AddCorrection(AddCorrection),
+    /// Create schedule of draft rounds
+    AddSchedule(AddSchedule),
+    /// Stop the schedule
+    RemoveSchedule(RemoveSchedule),
+    /// Create draft round of the schedule for the ended period
+    RunSchedule(RunSchedule),
 }
 
 impl State for SystemState {
@@ -68,7 +210,580 @@ impl State for SystemState {
             SystemUpdate::CleanAdmin(_) => {
                 self.admin = None;
             }
+            SystemUpdate::RotateAdmin(_) if !self.has_admin() => {
+                return Err(Error::NoAdmin);
+            }
+            SystemUpdate::RotateAdmin(v) if self.admin_key() != Some(v.old_key.clone()) => {
+                return Err(Error::NotAdmin);
+            }
+            SystemUpdate::RotateAdmin(v) if self.users.contains_key(&v.new_key) => {
+                return Err(Error::UserRegistered);
+            }
+            SystemUpdate::RotateAdmin(v) => {
+                self.admin = Some(AdminInfo {
+                    key: v.new_key,
+                    created_at: v.timestamp,
+                });
+            }
+            SystemUpdate::AddUser(v)
+                if self.admin_key() == Some(v.key.clone()) || self.users.contains_key(&v.key) =>
+            {
+                return Err(Error::UserRegistered);
+            }
+            SystemUpdate::AddUser(v) => {
+                self.users.insert(v.key.clone(), v.into());
+            }
+            SystemUpdate::SetPermissions(v) if self.admin_key() == Some(v.key.clone()) => {
+                return Err(Error::AdminPermissions);
+            }
+            SystemUpdate::SetPermissions(v) => {
+                let user = self.users.get_mut(&v.key).ok_or(Error::UnknownUser)?;
+                user.permissions = v.permissions;
+            }
+            SystemUpdate::RemoveUser(v) => {
+                self.users.remove(&v.key).ok_or(Error::UnknownUser)?;
+            }
+            SystemUpdate::AddToken(v) if self.tokens.contains_key(&v.id) => {
+                return Err(Error::TokenExists);
+            }
+            SystemUpdate::AddToken(v) => {
+                self.tokens.insert(v.id.clone(), v.into());
+            }
+            SystemUpdate::RevokeToken(v) => {
+                self.tokens.remove(&v.id).ok_or(Error::UnknownToken)?;
+            }
+            SystemUpdate::AddRound(v) if self.rounds.contains_key(&v.id) => {
+                return Err(Error::RoundExists);
+            }
+            SystemUpdate::AddRound(v) => {
+                self.rounds.insert(v.id, v.into());
+            }
+            SystemUpdate::UpdateRound(v) => {
+                let round = self.round_in(v.id, &[RoundStatus::Draft])?;
+                round.name = v.name;
+                round.asset = v.asset;
+                round.total = v.total;
+            }
+            SystemUpdate::RemoveRound(v) => {
+                self.round_in(v.id, &[RoundStatus::Draft])?;
+                self.rounds.remove(&v.id);
+            }
+            SystemUpdate::SetSnapshot(v) => {
+                self.round_in(v.round, &[RoundStatus::Draft])?.snapshot = Some(v.snapshot);
+            }
+            SystemUpdate::AddExclusion(v) => {
+                let round = self.round_in(v.round, &[RoundStatus::Draft])?;
+                round.exclusions.insert(v.account, v.reason);
+            }
+            SystemUpdate::RemoveExclusion(v) => {
+                let round = self.round_in(v.round, &[RoundStatus::Draft])?;
+                round
+                    .exclusions
+                    .remove(&v.account)
+                    .ok_or(Error::UnknownExclusion)?;
+            }
+            SystemUpdate::SetAdjustments(v) => {
+                self.round_in(v.round, &[RoundStatus::Draft])?.adjustments = v.adjustments;
+            }
+            SystemUpdate::ApproveRound(v) => {
+                let round = self.round_in(v.round, &[RoundStatus::Draft])?;
+                let totals = round.totals();
+                if totals.payees == 0 {
+                    return Err(Error::NoPayouts);
+                }
+                // Adjustments and payments of the corrected round can add
+                // more than the total
+                if totals.overspend > 0 {
+                    let spent = totals.payout_total + totals.previous;
+                    return Err(Error::PayoutsExceedTotal(spent, round.total));
+                }
+                round.status = RoundStatus::Approved;
+                round.approved_by = Some(v.key);
+            }
+            SystemUpdate::CancelRound(v) => {
+                let round = self.round_in(v.round, &[RoundStatus::Draft, RoundStatus::Approved])?;
+                if !round.payments.is_empty() {
+                    return Err(Error::HasPayments);
+                }
+                round.status = RoundStatus::Cancelled;
+                round.cancelled_by = Some(v.key);
+            }
+            SystemUpdate::AddPayment(v) => {
+                let round = self.round_in(v.round, &[RoundStatus::Approved])?;
+                round.payout(&v.account).ok_or(Error::UnknownPayout)?;
+                let paid = round.payments.get(&v.account).map(|p| p.status);
+                if paid == Some(PaymentStatus::Success) {
+                    return Err(Error::AlreadyPaid);
+                }
+                round.payments.insert(v.account, v.payment);
+                if round.fully_paid() {
+                    round.status = RoundStatus::Paid;
+                }
+            }
+            SystemUpdate::AddCorrection(v) if self.rounds.contains_key(&v.id) => {
+                return Err(Error::RoundExists);
+            }
+            SystemUpdate::AddCorrection(v) => {
+                // Payments of paid rounds can't change anymore, and the
+                // next correction is made to the live one, so every
+                // payment is counted once
+                self.round_in(v.parent, &[RoundStatus::Paid])?;
+                if let Some(other) = self
+                    .rounds
+                    .values()
+                    .find(|r| r.corrects == Some(v.parent) && r.status != RoundStatus::Cancelled)
+                {
+                    return Err(Error::CorrectionExists(other.id));
+                }
+                let round = self.rounds[&v.parent].correction(v);
+                self.rounds.insert(round.id, round);
+            }
+            SystemUpdate::AddSchedule(v) if self.schedules.contains_key(&v.id) => {
+                return Err(Error::ScheduleExists);
+            }
+            SystemUpdate::AddSchedule(v) => {
+                self.schedules.insert(v.id, v.into());
+            }
+            SystemUpdate::RemoveSchedule(v) => {
+                self.schedules.remove(&v.id).ok_or(Error::UnknownSchedule)?;
+            }
+            SystemUpdate::RunSchedule(v) if self.rounds.contains_key(&v.round) => {
+                return Err(Error::RoundExists);
+            }
+            SystemUpdate::RunSchedule(v) => {
+                let schedule = self
+                    .schedules
+                    .get_mut(&v.schedule)
+                    .ok_or(Error::UnknownSchedule)?;
+                // Each period gets one round even if the runner retries
+                if let Some(last) = schedule.last_run.filter(|t| *t >= v.run_at) {
+                    return Err(Error::ScheduleRan(last));
+                }
+                schedule.last_run = Some(v.run_at);
+                schedule.last_round = Some(v.round);
+                let round = AddRound {
+                    id: v.round,
+                    name: schedule.round_name(v.run_at),
+                    asset: schedule.asset.clone(),
+                    total: schedule.total,
+                    created_by: schedule.created_by.clone(),
+                    timestamp: v.timestamp,
+                };
+                self.rounds.insert(v.round, round.into());
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::round::{Payment, Snapshot};
+    use super::*;
+
+    fn time() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(0, 0)
+    }
+
+    /// Draft round with the snapshot of two equal holders and adjustments
+    fn draft(state: &mut SystemState, id: RoundId, adjustments: &[(&str, i64)]) {
+        state
+            .update(SystemUpdate::AddRound(AddRound {
+                id,
+                name: "Test".to_owned(),
+                asset: "DIV".to_owned(),
+                total: 100,
+                created_by: "key".to_owned(),
+                timestamp: time(),
+            }))
+            .unwrap();
+        state
+            .update(SystemUpdate::SetSnapshot(SetSnapshot {
+                round: id,
+                snapshot: Snapshot {
+                    taken_at: time(),
+                    balances: BTreeMap::from([("A".to_owned(), 1), ("B".to_owned(), 1)]),
+                },
+                timestamp: time(),
+            }))
+            .unwrap();
+        adjust(state, id, adjustments);
+    }
+
+    /// Approved round with the snapshot and adjustments
+    fn approved(state: &mut SystemState, id: RoundId, adjustments: &[(&str, i64)]) {
+        draft(state, id, adjustments);
+        approve(state, id).unwrap();
+    }
+
+    fn adjust(state: &mut SystemState, id: RoundId, adjustments: &[(&str, i64)]) {
+        state
+            .update(SystemUpdate::SetAdjustments(SetAdjustments {
+                round: id,
+                adjustments: adjustments
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), *v))
+                    .collect(),
+                timestamp: time(),
+            }))
+            .unwrap();
+    }
+
+    fn approve(state: &mut SystemState, id: RoundId) -> Result<(), Error> {
+        state.update(SystemUpdate::ApproveRound(ApproveRound {
+            round: id,
+            key: "signer".to_owned(),
+            timestamp: time(),
+        }))
+    }
+
+    /// Record successful payment of the payout
+    fn pay(state: &mut SystemState, id: RoundId, account: &str) -> Result<(), Error> {
+        pay_with(state, id, account, PaymentStatus::Success)
+    }
+
+    fn pay_with(
+        state: &mut SystemState,
+        id: RoundId,
+        account: &str,
+        status: PaymentStatus,
+    ) -> Result<(), Error> {
+        let amount = state.rounds[&id].payout(account).map(|v| v.amount);
+        state.update(SystemUpdate::AddPayment(AddPayment {
+            round: id,
+            account: account.to_owned(),
+            payment: Payment {
+                tx_hash: "00".repeat(32),
+                amount: amount.unwrap_or(0),
+                status,
+                timestamp: time(),
+            },
+            timestamp: time(),
+        }))
+    }
+
+    fn correct(state: &mut SystemState, id: RoundId, parent: RoundId) -> Result<(), Error> {
+        state.update(SystemUpdate::AddCorrection(AddCorrection {
+            id,
+            parent,
+            name: "Correction".to_owned(),
+            created_by: "key".to_owned(),
+            timestamp: time(),
+        }))
+    }
+
+    fn cancel(state: &mut SystemState, id: RoundId) -> Result<(), Error> {
+        state.update(SystemUpdate::CancelRound(CancelRound {
+            round: id,
+            key: "key".to_owned(),
+            timestamp: time(),
+        }))
+    }
+
+    fn amounts(state: &SystemState, id: RoundId) -> Vec<(String, Amount)> {
+        state.rounds[&id]
+            .payouts()
+            .into_iter()
+            .map(|v| (v.account, v.amount))
+            .collect()
+    }
+
+    #[test]
+    fn corrections_pay_the_difference() {
+        let mut state = SystemState::new();
+        // B is underpaid by mistake
+        approved(&mut state, 1, &[("B", -30)]);
+        pay(&mut state, 1, "A").unwrap();
+        pay(&mut state, 1, "B").unwrap();
+        assert_eq!(state.rounds[&1].status, RoundStatus::Paid);
+
+        correct(&mut state, 2, 1).unwrap();
+        let correction = &state.rounds[&2];
+        assert_eq!(correction.status, RoundStatus::Draft);
+        assert_eq!(correction.corrects, Some(1));
+        assert_eq!(
+            correction.previous,
+            BTreeMap::from([("A".to_owned(), 50), ("B".to_owned(), 20)])
+        );
+        // Nothing to pay until the mistake is fixed
+        assert!(matches!(approve(&mut state, 2), Err(Error::NoPayouts)));
+        adjust(&mut state, 2, &[]);
+        assert_eq!(amounts(&state, 2), vec![("B".to_owned(), 30)]);
+        let totals = state.rounds[&2].totals();
+        assert_eq!(totals.previous, 70);
+        assert_eq!(totals.dust, 0);
+        assert_eq!(totals.overspend, 0);
+        approve(&mut state, 2).unwrap();
+        pay(&mut state, 2, "B").unwrap();
+        assert_eq!(state.rounds[&2].status, RoundStatus::Paid);
+    }
+
+    #[test]
+    fn only_paid_rounds_are_corrected() {
+        let mut state = SystemState::new();
+        assert!(matches!(
+            correct(&mut state, 2, 1),
+            Err(Error::UnknownRound)
+        ));
+        approved(&mut state, 1, &[]);
+        pay(&mut state, 1, "A").unwrap();
+        // Payments of B after the correction would be paid twice
+        assert!(matches!(
+            correct(&mut state, 2, 1),
+            Err(Error::RoundStatus(RoundStatus::Approved))
+        ));
+        pay(&mut state, 1, "B").unwrap();
+        correct(&mut state, 2, 1).unwrap();
+        assert!(matches!(correct(&mut state, 2, 1), Err(Error::RoundExists)));
+        assert!(matches!(
+            pay(&mut state, 1, "B"),
+            Err(Error::RoundStatus(RoundStatus::Paid))
+        ));
+    }
+
+    #[test]
+    fn second_correction_counts_the_first_one() {
+        let mut state = SystemState::new();
+        approved(&mut state, 1, &[("B", -30)]);
+        pay(&mut state, 1, "A").unwrap();
+        pay(&mut state, 1, "B").unwrap();
+        correct(&mut state, 2, 1).unwrap();
+        assert!(matches!(
+            correct(&mut state, 3, 1),
+            Err(Error::CorrectionExists(2))
+        ));
+        // Cancelled correction doesn't block the new one
+        cancel(&mut state, 2).unwrap();
+        correct(&mut state, 3, 1).unwrap();
+        adjust(&mut state, 3, &[]);
+        approve(&mut state, 3).unwrap();
+        pay(&mut state, 3, "B").unwrap();
+        assert!(matches!(
+            correct(&mut state, 4, 1),
+            Err(Error::CorrectionExists(3))
+        ));
+        // The correction of the correction knows about both payments
+        correct(&mut state, 4, 3).unwrap();
+        assert_eq!(
+            state.rounds[&4].previous,
+            BTreeMap::from([("A".to_owned(), 50), ("B".to_owned(), 50)])
+        );
+        assert!(amounts(&state, 4).is_empty());
+    }
+
+    #[test]
+    fn corrections_cant_overspend() {
+        let mut state = SystemState::new();
+        // A gets most of the total by mistake
+        approved(&mut state, 1, &[("A", 40), ("B", -40)]);
+        pay(&mut state, 1, "A").unwrap();
+        pay(&mut state, 1, "B").unwrap();
+        correct(&mut state, 2, 1).unwrap();
+        adjust(&mut state, 2, &[]);
+        assert_eq!(amounts(&state, 2), vec![("B".to_owned(), 40)]);
+        let totals = state.rounds[&2].totals();
+        assert_eq!(totals.previous, 100);
+        assert_eq!(totals.dust, 0);
+        assert_eq!(totals.overspend, 40);
+        assert!(matches!(
+            approve(&mut state, 2),
+            Err(Error::PayoutsExceedTotal(140, 100))
+        ));
+    }
+
+    fn status(state: &SystemState, id: RoundId) -> RoundStatus {
+        state.rounds[&id].status
+    }
+
+    #[test]
+    fn only_drafts_are_changed() {
+        let mut state = SystemState::new();
+        approved(&mut state, 1, &[]);
+        let updates = [
+            SystemUpdate::UpdateRound(UpdateRound {
+                id: 1,
+                name: "Other".to_owned(),
+                asset: "DIV".to_owned(),
+                total: 200,
+                timestamp: time(),
+            }),
+            SystemUpdate::SetSnapshot(SetSnapshot {
+                round: 1,
+                snapshot: Snapshot {
+                    taken_at: time(),
+                    balances: BTreeMap::new(),
+                },
+                timestamp: time(),
+            }),
+            SystemUpdate::AddExclusion(AddExclusion {
+                round: 1,
+                account: "A".to_owned(),
+                reason: "issuer".to_owned(),
+                timestamp: time(),
+            }),
+            SystemUpdate::RemoveExclusion(RemoveExclusion {
+                round: 1,
+                account: "A".to_owned(),
+                timestamp: time(),
+            }),
+            SystemUpdate::SetAdjustments(SetAdjustments {
+                round: 1,
+                adjustments: BTreeMap::new(),
+                timestamp: time(),
+            }),
+            SystemUpdate::ApproveRound(ApproveRound {
+                round: 1,
+                key: "signer".to_owned(),
+                timestamp: time(),
+            }),
+            SystemUpdate::RemoveRound(RemoveRound {
+                id: 1,
+                timestamp: time(),
+            }),
+        ];
+        for update in updates {
+            assert!(matches!(
+                state.update(update),
+                Err(Error::RoundStatus(RoundStatus::Approved))
+            ));
+        }
+        assert_eq!(state.rounds[&1].total, 100);
+        assert_eq!(amounts(&state, 1).len(), 2);
+    }
+
+    #[test]
+    fn approval_needs_payouts_within_total() {
+        let mut state = SystemState::new();
+        state
+            .update(SystemUpdate::AddRound(AddRound {
+                id: 1,
+                name: "Empty".to_owned(),
+                asset: "DIV".to_owned(),
+                total: 100,
+                created_by: "key".to_owned(),
+                timestamp: time(),
+            }))
+            .unwrap();
+        assert!(matches!(approve(&mut state, 1), Err(Error::NoPayouts)));
+        // Adjustments take everything
+        draft(&mut state, 2, &[("A", -50), ("B", -50)]);
+        assert!(matches!(approve(&mut state, 2), Err(Error::NoPayouts)));
+        draft(&mut state, 3, &[("A", 10)]);
+        assert!(matches!(
+            approve(&mut state, 3),
+            Err(Error::PayoutsExceedTotal(110, 100))
+        ));
+        assert_eq!(status(&state, 3), RoundStatus::Draft);
+        assert!(matches!(approve(&mut state, 4), Err(Error::UnknownRound)));
+    }
+
+    #[test]
+    fn paid_rounds_are_final() {
+        let mut state = SystemState::new();
+        approved(&mut state, 1, &[]);
+        pay_with(&mut state, 1, "A", PaymentStatus::Pending).unwrap();
+        pay_with(&mut state, 1, "B", PaymentStatus::Failed).unwrap();
+        assert_eq!(status(&state, 1), RoundStatus::Approved);
+        pay(&mut state, 1, "A").unwrap();
+        assert!(matches!(pay(&mut state, 1, "A"), Err(Error::AlreadyPaid)));
+        assert!(matches!(pay(&mut state, 1, "C"), Err(Error::UnknownPayout)));
+        assert_eq!(status(&state, 1), RoundStatus::Approved);
+        // Failed payment is made again
+        pay(&mut state, 1, "B").unwrap();
+        assert_eq!(status(&state, 1), RoundStatus::Paid);
+        assert!(matches!(
+            pay(&mut state, 1, "B"),
+            Err(Error::RoundStatus(RoundStatus::Paid))
+        ));
+        assert!(matches!(
+            cancel(&mut state, 1),
+            Err(Error::RoundStatus(RoundStatus::Paid))
+        ));
+    }
+
+    #[test]
+    fn zero_payouts_are_not_waited_for() {
+        let mut state = SystemState::new();
+        approved(&mut state, 1, &[("A", 50), ("B", -50)]);
+        assert_eq!(
+            amounts(&state, 1),
+            vec![("A".to_owned(), 100), ("B".to_owned(), 0)]
+        );
+        assert_eq!(state.rounds[&1].totals().payees, 1);
+        pay(&mut state, 1, "A").unwrap();
+        assert_eq!(status(&state, 1), RoundStatus::Paid);
+    }
+
+    #[test]
+    fn rounds_with_payments_are_not_cancelled() {
+        let mut state = SystemState::new();
+        draft(&mut state, 1, &[]);
+        cancel(&mut state, 1).unwrap();
+        assert_eq!(status(&state, 1), RoundStatus::Cancelled);
+        approved(&mut state, 2, &[]);
+        pay_with(&mut state, 2, "A", PaymentStatus::Failed).unwrap();
+        assert!(matches!(cancel(&mut state, 2), Err(Error::HasPayments)));
+        assert_eq!(status(&state, 2), RoundStatus::Approved);
+        approved(&mut state, 3, &[]);
+        cancel(&mut state, 3).unwrap();
+        assert_eq!(state.rounds[&3].cancelled_by.as_deref(), Some("key"));
+        assert!(matches!(
+            cancel(&mut state, 3),
+            Err(Error::RoundStatus(RoundStatus::Cancelled))
+        ));
+    }
+
+    fn run(state: &mut SystemState, round: RoundId, run_at: NaiveDateTime) -> Result<(), Error> {
+        state.update(SystemUpdate::RunSchedule(RunSchedule {
+            schedule: 1,
+            round,
+            run_at,
+            timestamp: run_at,
+        }))
+    }
+
+    #[test]
+    fn schedules_create_one_round_per_period() {
+        let mut state = SystemState::new();
+        let created_at = NaiveDateTime::from_timestamp(1_670_000_000, 0);
+        state
+            .update(SystemUpdate::AddSchedule(AddSchedule {
+                id: 1,
+                name: "Dividends".to_owned(),
+                asset: "DIV".to_owned(),
+                total: 100,
+                period: SchedulePeriod::Monthly,
+                created_by: "key".to_owned(),
+                timestamp: created_at,
+            }))
+            .unwrap();
+        let run_at = state.schedules[&1].next_run();
+        run(&mut state, 1, run_at).unwrap();
+        let round = &state.rounds[&1];
+        assert_eq!(round.status, RoundStatus::Draft);
+        assert_eq!(round.name, "Dividends 2022-12");
+        assert_eq!(round.created_by, "key");
+        assert_eq!(state.schedules[&1].last_round, Some(1));
+        // Retry of the same period after restart does nothing
+        assert!(matches!(run(&mut state, 2, run_at), Err(Error::ScheduleRan(t)) if t == run_at));
+        assert!(!state.rounds.contains_key(&2));
+        assert!(matches!(
+            run(&mut state, 1, run_at),
+            Err(Error::RoundExists)
+        ));
+        let next = state.schedules[&1].next_run();
+        run(&mut state, 2, next).unwrap();
+        assert_eq!(state.rounds[&2].name, "Dividends 2023-01");
+        state
+            .update(SystemUpdate::RemoveSchedule(RemoveSchedule {
+                id: 1,
+                timestamp: next,
+            }))
+            .unwrap();
+        assert!(matches!(
+            run(&mut state, 3, next),
+            Err(Error::UnknownSchedule)
+        ));
+        assert_eq!(state.rounds.len(), 2);
+    }
+}
@@ -0,0 +1,788 @@
+use super::admin::PublicKey;
+use chrono::NaiveDateTime;
+use rocket::form::FromFormField;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Sequential number of the distribution round
+pub type RoundId = u64;
+/// Stellar account id (G...) of the holder
+pub type Account = String;
+/// Amount of the asset in stroops, i.e. 1e-7 of the unit
+pub type Amount = u64;
+/// Manual change of the payout in stroops
+pub type Adjustment = i64;
+
+/// Stroops in one unit of the asset
+pub const STROOPS: Amount = 10_000_000;
+
+/// Format amount in stroops as decimal with 7 digits after the point
+pub fn format_amount(v: Amount) -> String {
+    format!("{}.{:07}", v / STROOPS, v % STROOPS)
+}
+
+/// Parse decimal amount of the asset with up to 7 digits after the point
+pub fn parse_amount(v: &str) -> Option<Amount> {
+    let (units, fraction) = v.split_once('.').unwrap_or((v, ""));
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if units.is_empty() || !digits(units) || !digits(fraction) || fraction.len() > 7 {
+        return None;
+    }
+    let fraction: Amount = format!("{:0<7}", fraction).parse().ok()?;
+    units
+        .parse::<Amount>()
+        .ok()?
+        .checked_mul(STROOPS)?
+        .checked_add(fraction)
+}
+
+/// Format adjustment in stroops as signed decimal
+pub fn format_adjustment(v: Adjustment) -> String {
+    let sign = if v < 0 { "-" } else { "" };
+    format!("{}{}", sign, format_amount(v.unsigned_abs()))
+}
+
+/// Parse signed decimal amount of the asset into adjustment in stroops
+pub fn parse_adjustment(v: &str) -> Option<Adjustment> {
+    let (negative, v) = match v.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, v.strip_prefix('+').unwrap_or(v)),
+    };
+    let amount = Adjustment::try_from(parse_amount(v)?).ok()?;
+    Some(if negative { -amount } else { amount })
+}
+
+/// Stage of the round. Rounds go from `Draft` to `Approved` and `Paid`,
+/// they can be cancelled before all payments are made.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, FromFormField, JsonSchema,
+)]
+pub enum RoundStatus {
+    /// Snapshot and exclusions can be changed
+    Draft,
+    /// Payouts are fixed and wait for the payments
+    Approved,
+    /// All payouts are paid
+    Paid,
+    /// The round will never be paid
+    Cancelled,
+}
+
+impl fmt::Display for RoundStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoundStatus::Draft => write!(f, "draft"),
+            RoundStatus::Approved => write!(f, "approved"),
+            RoundStatus::Paid => write!(f, "paid"),
+            RoundStatus::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Balances of the holders at the moment of the snapshot
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Snapshot {
+    /// When the balances were taken
+    pub taken_at: NaiveDateTime,
+    /// Balance of each holder
+    pub balances: BTreeMap<Account, Amount>,
+}
+
+/// Change of the holder balance between two snapshots
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BalanceChange {
+    pub account: Account,
+    /// Balance in the base snapshot
+    pub before: Amount,
+    /// Balance in the compared snapshot
+    pub after: Amount,
+    /// Signed difference of the balances
+    pub change: i128,
+}
+
+/// How much of the supply is held by the largest holders
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Concentration {
+    /// Holders with non zero balance
+    pub holders: usize,
+    /// Share of the largest holder in percents
+    pub top1: f64,
+    /// Share of 10 largest holders in percents
+    pub top10: f64,
+    /// Herfindahl-Hirschman index from 0 to 10000, higher is more
+    /// concentrated
+    pub hhi: f64,
+}
+
+/// Comparison of two snapshots, lists are sorted by the size of change
+/// and cut to the limit
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SnapshotDiff {
+    /// Holders that appeared since the base snapshot
+    pub new_holders: Vec<BalanceChange>,
+    pub new_count: usize,
+    /// Holders that sold everything since the base snapshot
+    pub exited_holders: Vec<BalanceChange>,
+    pub exited_count: usize,
+    /// Biggest increases of the balances of the existing holders
+    pub increases: Vec<BalanceChange>,
+    /// Biggest decreases of the balances of the existing holders
+    pub decreases: Vec<BalanceChange>,
+    pub before: Concentration,
+    pub after: Concentration,
+}
+
+impl Snapshot {
+    /// Concentration of the balances in the snapshot
+    pub fn concentration(&self) -> Concentration {
+        let mut balances: Vec<Amount> =
+            self.balances.values().cloned().filter(|v| *v > 0).collect();
+        balances.sort_unstable_by(|a, b| b.cmp(a));
+        let supply: u128 = balances.iter().map(|v| *v as u128).sum();
+        let share = |v: u128| {
+            if supply == 0 {
+                0.0
+            } else {
+                v as f64 * 100.0 / supply as f64
+            }
+        };
+        let top = |n: usize| share(balances.iter().take(n).map(|v| *v as u128).sum());
+        Concentration {
+            holders: balances.len(),
+            top1: top(1),
+            top10: top(10),
+            hhi: balances.iter().map(|v| share(*v as u128).powi(2)).sum(),
+        }
+    }
+
+    /// Compare the snapshot with the earlier one. Each list contains at
+    /// most `limit` holders with the biggest changes.
+    pub fn diff(&self, base: &Snapshot, limit: usize) -> SnapshotDiff {
+        let balance = |s: &Snapshot, account: &str| s.balances.get(account).cloned().unwrap_or(0);
+        let mut new_holders = vec![];
+        let mut exited_holders = vec![];
+        let mut increases = vec![];
+        let mut decreases = vec![];
+        let accounts: BTreeSet<&Account> =
+            self.balances.keys().chain(base.balances.keys()).collect();
+        for account in accounts {
+            let before = balance(base, account);
+            let after = balance(self, account);
+            let change = BalanceChange {
+                account: account.clone(),
+                before,
+                after,
+                change: after as i128 - before as i128,
+            };
+            if before == 0 && after > 0 {
+                new_holders.push(change);
+            } else if before > 0 && after == 0 {
+                exited_holders.push(change);
+            } else if after > before {
+                increases.push(change);
+            } else if after < before {
+                decreases.push(change);
+            }
+        }
+        let new_count = new_holders.len();
+        let exited_count = exited_holders.len();
+        let biggest = |mut v: Vec<BalanceChange>| {
+            v.sort_by(|a, b| b.change.abs().cmp(&a.change.abs()));
+            v.truncate(limit);
+            v
+        };
+        SnapshotDiff {
+            new_holders: biggest(new_holders),
+            new_count,
+            exited_holders: biggest(exited_holders),
+            exited_count,
+            increases: biggest(increases),
+            decreases: biggest(decreases),
+            before: base.concentration(),
+            after: self.concentration(),
+        }
+    }
+}
+
+/// Result of the payment transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum PaymentStatus {
+    /// Transaction is submitted but not confirmed yet
+    Pending,
+    /// Transaction is included in the ledger
+    Success,
+    /// Transaction failed, the payout should be made again
+    Failed,
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentStatus::Pending => write!(f, "pending"),
+            PaymentStatus::Success => write!(f, "success"),
+            PaymentStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Executed payment of the payout
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Payment {
+    /// Hash of the Stellar transaction
+    pub tx_hash: String,
+    /// Paid amount
+    pub amount: Amount,
+    /// Result of the transaction
+    pub status: PaymentStatus,
+    /// When the payment was recorded
+    pub timestamp: NaiveDateTime,
+}
+
+/// Payout of the holder that is calculated from the snapshot
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Payout {
+    /// Holder account
+    pub account: Account,
+    /// Balance of the holder in the snapshot
+    pub balance: Amount,
+    /// Share of the holder among eligible holders in percents
+    pub share: f64,
+    /// Amount to pay including the adjustment
+    pub amount: Amount,
+    /// Manual change of the pro-rata amount
+    #[serde(default)]
+    pub adjustment: Adjustment,
+    /// Already paid by the corrected round, the amount is reduced by it
+    #[serde(default)]
+    pub previous: Amount,
+}
+
+/// Summary of the round payouts and payments
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RoundTotals {
+    /// Holders in the snapshot
+    pub holders: usize,
+    /// Holders that are excluded from the round
+    pub excluded: usize,
+    /// Holders that get more than 0, the ones that need payments
+    pub payees: usize,
+    /// Sum of balances of the holders that get dividends
+    pub eligible_balance: u128,
+    /// Sum of all payouts
+    pub payout_total: Amount,
+    /// Sum that is already paid by the corrected round
+    #[serde(default)]
+    pub previous: Amount,
+    /// Part of the total that is lost due to rounding
+    pub dust: Amount,
+    /// Part of the payouts and earlier payments that exceeds the total,
+    /// the round can't be approved with it
+    #[serde(default)]
+    pub overspend: Amount,
+    /// Sum of successful payments
+    pub paid: Amount,
+    /// Amount of failed payments
+    pub failed: usize,
+}
+
+/// Distribution of dividends to the holders
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RoundInfo {
+    pub id: RoundId,
+    /// Human readable name of the round
+    pub name: String,
+    /// Code of the asset that is paid
+    pub asset: String,
+    /// Amount that is distributed among the holders
+    pub total: Amount,
+    pub status: RoundStatus,
+    /// Key of the user that created the round
+    pub created_by: PublicKey,
+    pub created_at: NaiveDateTime,
+    /// Key of the user that approved the payouts
+    pub approved_by: Option<PublicKey>,
+    /// Key of the user that cancelled the round
+    #[serde(default)]
+    pub cancelled_by: Option<PublicKey>,
+    pub snapshot: Option<Snapshot>,
+    /// Holders that don't get dividends with the reason why
+    pub exclusions: BTreeMap<Account, String>,
+    /// Manual changes of the payouts, e.g. for off-chain corrections
+    #[serde(default)]
+    pub adjustments: BTreeMap<Account, Adjustment>,
+    /// Payments that are made for the payouts
+    pub payments: BTreeMap<Account, Payment>,
+    /// Round which payouts are corrected by this one
+    #[serde(default)]
+    pub corrects: Option<RoundId>,
+    /// Amounts that the holders already got from the corrected round
+    /// and the rounds that it corrects
+    #[serde(default)]
+    pub previous: BTreeMap<Account, Amount>,
+}
+
+impl RoundInfo {
+    /// Sum of balances of the holders that get dividends
+    pub fn eligible_balance(&self) -> u128 {
+        self.eligible().map(|(_, v)| *v as u128).sum()
+    }
+
+    fn eligible(&self) -> impl Iterator<Item = (&Account, &Amount)> {
+        self.snapshot
+            .iter()
+            .flat_map(|v| v.balances.iter())
+            .filter(move |(k, v)| **v > 0 && !self.exclusions.contains_key(*k))
+    }
+
+    /// Split the total proportionally to the balances of eligible
+    /// holders. Amounts are rounded down, the dust is not paid.
+    /// Adjustments are added on top and can't make the amount negative.
+    ///
+    /// Correction rounds pay only the difference with the amounts that
+    /// are already paid, holders that got enough are not listed.
+    pub fn payouts(&self) -> Vec<Payout> {
+        let eligible = self.eligible_balance();
+        if eligible == 0 {
+            return vec![];
+        }
+        self.eligible()
+            .map(|(account, balance)| {
+                let amount = (self.total as u128 * *balance as u128 / eligible) as Amount;
+                let adjustment = self.adjustments.get(account).cloned().unwrap_or(0);
+                let amount = (amount as i128 + adjustment as i128).max(0) as Amount;
+                let previous = self.previous.get(account).cloned().unwrap_or(0);
+                Payout {
+                    account: account.clone(),
+                    balance: *balance,
+                    share: *balance as f64 * 100.0 / eligible as f64,
+                    amount: amount.saturating_sub(previous),
+                    adjustment,
+                    previous,
+                }
+            })
+            .filter(|v| self.corrects.is_none() || v.amount > 0)
+            .collect()
+    }
+
+    /// Find payout of the holder
+    pub fn payout(&self, account: &str) -> Option<Payout> {
+        self.payouts().into_iter().find(|v| v.account == account)
+    }
+
+    /// Totals of the payouts and payments for reports
+    pub fn totals(&self) -> RoundTotals {
+        let payouts = self.payouts();
+        let payout_total = payouts.iter().map(|v| v.amount).sum();
+        let previous: Amount = self.previous.values().sum();
+        let spent = payout_total + previous;
+        RoundTotals {
+            holders: self
+                .snapshot
+                .as_ref()
+                .map(|v| v.balances.len())
+                .unwrap_or(0),
+            excluded: self.exclusions.len(),
+            payees: payouts.iter().filter(|v| v.amount > 0).count(),
+            eligible_balance: self.eligible_balance(),
+            payout_total,
+            previous,
+            dust: self.total.saturating_sub(spent),
+            overspend: spent.saturating_sub(self.total),
+            paid: self
+                .payments
+                .values()
+                .filter(|v| v.status == PaymentStatus::Success)
+                .map(|v| v.amount)
+                .sum(),
+            failed: self
+                .payments
+                .values()
+                .filter(|v| v.status == PaymentStatus::Failed)
+                .count(),
+        }
+    }
+
+    /// Draft round that corrects payouts of this paid one. Snapshot,
+    /// exclusions and adjustments are copied, so the operator only fixes
+    /// the mistake. Successful payments of this round are added to what
+    /// the holders got from the rounds it corrects.
+    pub fn correction(&self, v: AddCorrection) -> RoundInfo {
+        let mut previous = self.previous.clone();
+        for (account, payment) in self.payments.iter() {
+            if payment.status == PaymentStatus::Success {
+                *previous.entry(account.clone()).or_insert(0) += payment.amount;
+            }
+        }
+        RoundInfo {
+            id: v.id,
+            name: v.name,
+            asset: self.asset.clone(),
+            total: self.total,
+            status: RoundStatus::Draft,
+            created_by: v.created_by,
+            created_at: v.timestamp,
+            approved_by: None,
+            cancelled_by: None,
+            snapshot: self.snapshot.clone(),
+            exclusions: self.exclusions.clone(),
+            adjustments: self.adjustments.clone(),
+            payments: BTreeMap::new(),
+            corrects: Some(self.id),
+            previous,
+        }
+    }
+
+    /// All payouts have successful payments. Payouts of 0 are not paid,
+    /// e.g. when an adjustment takes the whole share of the holder.
+    pub fn fully_paid(&self) -> bool {
+        self.payouts().iter().filter(|v| v.amount > 0).all(|v| {
+            self.payments
+                .get(&v.account)
+                .map(|p| p.status == PaymentStatus::Success)
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// Action to create new round in `Draft` status
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddRound {
+    pub id: RoundId,
+    pub name: String,
+    pub asset: String,
+    pub total: Amount,
+    /// Key of the user that created the round
+    pub created_by: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+impl From<AddRound> for RoundInfo {
+    fn from(v: AddRound) -> Self {
+        RoundInfo {
+            id: v.id,
+            name: v.name,
+            asset: v.asset,
+            total: v.total,
+            status: RoundStatus::Draft,
+            created_by: v.created_by,
+            created_at: v.timestamp,
+            approved_by: None,
+            cancelled_by: None,
+            snapshot: None,
+            exclusions: BTreeMap::new(),
+            adjustments: BTreeMap::new(),
+            payments: BTreeMap::new(),
+            corrects: None,
+            previous: BTreeMap::new(),
+        }
+    }
+}
+
+/// Action to create draft round that pays the difference to holders that
+/// were underpaid or missed by the paid round. The round can have only
+/// one correction that is not cancelled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddCorrection {
+    pub id: RoundId,
+    /// Round which payouts are corrected
+    pub parent: RoundId,
+    pub name: String,
+    /// Key of the user that created the round
+    pub created_by: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to change parameters of the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpdateRound {
+    pub id: RoundId,
+    pub name: String,
+    pub asset: String,
+    pub total: Amount,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to delete the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoveRound {
+    pub id: RoundId,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to replace snapshot of the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetSnapshot {
+    pub round: RoundId,
+    pub snapshot: Snapshot,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to exclude the holder from the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddExclusion {
+    pub round: RoundId,
+    pub account: Account,
+    /// Why the holder doesn't get dividends
+    pub reason: String,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to return the holder to the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoveExclusion {
+    pub round: RoundId,
+    pub account: Account,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to replace manual adjustments of the draft round payouts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetAdjustments {
+    pub round: RoundId,
+    /// Change of the payout of each holder
+    pub adjustments: BTreeMap<Account, Adjustment>,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to approve payouts of the draft round
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApproveRound {
+    pub round: RoundId,
+    /// Key of the user that approved the round
+    pub key: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to cancel the round that is not paid yet and has no payments
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CancelRound {
+    pub round: RoundId,
+    /// Key of the user that cancelled the round
+    pub key: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to record payment of the approved payout. The round becomes
+/// `Paid` when all payouts have successful payments. Successful payment
+/// can't be replaced.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddPayment {
+    pub round: RoundId,
+    pub account: Account,
+    pub payment: Payment,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(total: Amount, balances: &[(&str, Amount)]) -> RoundInfo {
+        let mut round: RoundInfo = AddRound {
+            id: 1,
+            name: "Test".to_owned(),
+            asset: "DIV".to_owned(),
+            total,
+            created_by: "key".to_owned(),
+            timestamp: NaiveDateTime::from_timestamp(0, 0),
+        }
+        .into();
+        round.snapshot = Some(Snapshot {
+            taken_at: NaiveDateTime::from_timestamp(0, 0),
+            balances: balances.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        });
+        round
+    }
+
+    fn amounts(round: &RoundInfo) -> Vec<(String, Amount)> {
+        round
+            .payouts()
+            .into_iter()
+            .map(|v| (v.account, v.amount))
+            .collect()
+    }
+
+    #[test]
+    fn payouts_are_rounded_down() {
+        let round = round(100, &[("A", 1), ("B", 1), ("C", 1)]);
+        assert_eq!(
+            amounts(&round),
+            vec![
+                ("A".to_owned(), 33),
+                ("B".to_owned(), 33),
+                ("C".to_owned(), 33)
+            ]
+        );
+        let totals = round.totals();
+        assert_eq!(totals.payout_total, 99);
+        assert_eq!(totals.dust, 1);
+    }
+
+    #[test]
+    fn payouts_are_proportional_without_overflow() {
+        let total = 1_000_000 * STROOPS;
+        let round = round(total, &[("A", u64::MAX / 4), ("B", u64::MAX / 4 * 3)]);
+        let payouts = amounts(&round);
+        assert_eq!(payouts[0].1, total / 4);
+        assert_eq!(payouts[1].1, total / 4 * 3);
+        assert!(round.totals().dust <= 1);
+    }
+
+    #[test]
+    fn excluded_and_empty_holders_get_nothing() {
+        let mut round = round(100, &[("A", 1), ("B", 1), ("C", 1), ("D", 0)]);
+        round
+            .exclusions
+            .insert("B".to_owned(), "exchange".to_owned());
+        assert_eq!(
+            amounts(&round),
+            vec![("A".to_owned(), 50), ("C".to_owned(), 50)]
+        );
+        assert_eq!(round.payout("B"), None);
+        assert_eq!(round.payout("D"), None);
+        let totals = round.totals();
+        assert_eq!(totals.holders, 4);
+        assert_eq!(totals.excluded, 1);
+        assert_eq!(totals.payees, 2);
+        assert_eq!(totals.dust, 0);
+    }
+
+    #[test]
+    fn everybody_excluded_has_no_payouts() {
+        let mut round = round(100, &[("A", 1)]);
+        round.exclusions.insert("A".to_owned(), "issuer".to_owned());
+        assert!(round.payouts().is_empty());
+        assert_eq!(round.totals().dust, 100);
+    }
+
+    #[test]
+    fn adjustments_are_added_and_clamped() {
+        let mut round = round(100, &[("A", 1), ("B", 1)]);
+        round.adjustments.insert("A".to_owned(), 10);
+        round.adjustments.insert("B".to_owned(), -80);
+        let payouts = round.payouts();
+        assert_eq!(payouts[0].amount, 60);
+        assert_eq!(payouts[0].adjustment, 10);
+        assert_eq!(payouts[1].amount, 0);
+        assert_eq!(payouts[1].adjustment, -80);
+        assert_eq!(round.totals().dust, 40);
+    }
+
+    #[test]
+    fn positive_adjustments_can_exceed_total() {
+        let mut round = round(100, &[("A", 1), ("B", 1)]);
+        round.adjustments.insert("A".to_owned(), 10);
+        let totals = round.totals();
+        assert_eq!(totals.payout_total, 110);
+        assert_eq!(totals.dust, 0);
+    }
+
+    #[test]
+    fn dust_of_tiny_total() {
+        let round = round(2, &[("A", 1), ("B", 1), ("C", 1)]);
+        assert_eq!(
+            amounts(&round),
+            vec![
+                ("A".to_owned(), 0),
+                ("B".to_owned(), 0),
+                ("C".to_owned(), 0)
+            ]
+        );
+        assert_eq!(round.totals().dust, 2);
+    }
+
+    #[test]
+    fn corrections_pay_the_difference() {
+        let mut round = round(100, &[("A", 1), ("B", 1)]);
+        round.corrects = Some(0);
+        round.previous.insert("A".to_owned(), 50);
+        round.previous.insert("B".to_owned(), 20);
+        assert_eq!(amounts(&round), vec![("B".to_owned(), 30)]);
+        let totals = round.totals();
+        assert_eq!(totals.previous, 70);
+        assert_eq!(totals.dust, 0);
+    }
+
+    fn snapshot(balances: &[(&str, Amount)]) -> Snapshot {
+        Snapshot {
+            taken_at: NaiveDateTime::from_timestamp(0, 0),
+            balances: balances.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+
+    fn changes(v: &[BalanceChange]) -> Vec<(&str, i128)> {
+        v.iter().map(|v| (v.account.as_str(), v.change)).collect()
+    }
+
+    #[test]
+    fn diff_classifies_holders() {
+        let base = snapshot(&[("A", 10), ("B", 10), ("C", 10), ("D", 5)]);
+        let new = snapshot(&[("A", 15), ("B", 4), ("C", 10), ("D", 0), ("E", 7)]);
+        let diff = new.diff(&base, 10);
+        assert_eq!(changes(&diff.new_holders), vec![("E", 7)]);
+        assert_eq!(diff.new_count, 1);
+        assert_eq!(changes(&diff.exited_holders), vec![("D", -5)]);
+        assert_eq!(diff.exited_count, 1);
+        assert_eq!(changes(&diff.increases), vec![("A", 5)]);
+        assert_eq!(changes(&diff.decreases), vec![("B", -6)]);
+        assert_eq!(diff.decreases[0].before, 10);
+        assert_eq!(diff.decreases[0].after, 4);
+        assert_eq!(diff.before.holders, 4);
+        assert_eq!(diff.after.holders, 4);
+    }
+
+    #[test]
+    fn diff_keeps_biggest_changes_and_counts() {
+        let base = snapshot(&[("A", 1), ("B", 1), ("C", 1), ("X", 1), ("Y", 5), ("Z", 3)]);
+        let new = snapshot(&[("A", 2), ("B", 5), ("C", 4), ("N", 1), ("O", 3), ("P", 2)]);
+        let diff = new.diff(&base, 2);
+        assert_eq!(changes(&diff.new_holders), vec![("O", 3), ("P", 2)]);
+        assert_eq!(diff.new_count, 3);
+        assert_eq!(changes(&diff.exited_holders), vec![("Y", -5), ("Z", -3)]);
+        assert_eq!(diff.exited_count, 3);
+        assert_eq!(changes(&diff.increases), vec![("B", 4), ("C", 3)]);
+        assert!(diff.decreases.is_empty());
+    }
+
+    #[test]
+    fn concentration_of_empty_and_single_holder() {
+        let empty = snapshot(&[]).concentration();
+        assert_eq!(empty.holders, 0);
+        assert_eq!(empty.top1, 0.0);
+        assert_eq!(empty.top10, 0.0);
+        assert_eq!(empty.hhi, 0.0);
+        let single = snapshot(&[("A", 42), ("B", 0)]).concentration();
+        assert_eq!(single.holders, 1);
+        assert_eq!(single.top1, 100.0);
+        assert_eq!(single.top10, 100.0);
+        assert_eq!(single.hhi, 10000.0);
+    }
+
+    #[test]
+    fn concentration_of_top_holders() {
+        let even = snapshot(&[("A", 1), ("B", 1)]).concentration();
+        assert_eq!(even.top1, 50.0);
+        assert_eq!(even.hhi, 5000.0);
+        let balances: Vec<(String, Amount)> = (0..20).map(|i| (format!("H{:02}", i), 1)).collect();
+        let balances: Vec<(&str, Amount)> =
+            balances.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        let many = snapshot(&balances).concentration();
+        assert_eq!(many.holders, 20);
+        assert_eq!(many.top1, 5.0);
+        assert_eq!(many.top10, 50.0);
+        assert!((many.hhi - 500.0).abs() < 1e-9);
+    }
+}
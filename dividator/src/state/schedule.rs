@@ -0,0 +1,242 @@
+use super::admin::PublicKey;
+use super::round::{Amount, RoundId};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use rocket::form::FromFormField;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Sequential number of the round schedule
+pub type ScheduleId = u64;
+
+/// How often the scheduled rounds are created. Periods are taken in UTC,
+/// weeks start on Monday.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, FromFormField, JsonSchema,
+)]
+pub enum SchedulePeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl SchedulePeriod {
+    /// Start of the period that contains the time
+    pub fn start(&self, t: NaiveDateTime) -> NaiveDateTime {
+        let date = t.date();
+        let start = match self {
+            SchedulePeriod::Daily => date,
+            SchedulePeriod::Weekly => {
+                date - Duration::days(date.weekday().num_days_from_monday() as i64)
+            }
+            SchedulePeriod::Monthly => NaiveDate::from_ymd(date.year(), date.month(), 1),
+        };
+        start.and_hms(0, 0, 0)
+    }
+
+    /// Start of the period that follows the one with the time
+    pub fn next(&self, t: NaiveDateTime) -> NaiveDateTime {
+        let start = self.start(t);
+        match self {
+            SchedulePeriod::Daily => start + Duration::days(1),
+            SchedulePeriod::Weekly => start + Duration::weeks(1),
+            SchedulePeriod::Monthly if start.month() == 12 => {
+                NaiveDate::from_ymd(start.year() + 1, 1, 1).and_hms(0, 0, 0)
+            }
+            SchedulePeriod::Monthly => {
+                NaiveDate::from_ymd(start.year(), start.month() + 1, 1).and_hms(0, 0, 0)
+            }
+        }
+    }
+
+    /// Label of the period that ends at the time, e.g. `2022-07` for
+    /// monthly round created at 2022-08-01 00:00
+    pub fn label(&self, end: NaiveDateTime) -> String {
+        let last = end - Duration::seconds(1);
+        match self {
+            SchedulePeriod::Daily => last.format("%Y-%m-%d").to_string(),
+            SchedulePeriod::Weekly => last.format("%G-W%V").to_string(),
+            SchedulePeriod::Monthly => last.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SchedulePeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulePeriod::Daily => write!(f, "daily"),
+            SchedulePeriod::Weekly => write!(f, "weekly"),
+            SchedulePeriod::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+/// Draft round that is created at the end of each period. The snapshot
+/// is not taken by the schedule yet, there is no Horizon source for it,
+/// so it is set by the API or CSV import before approval.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ScheduleInfo {
+    pub id: ScheduleId,
+    /// Name of the rounds, the period label is appended to it
+    pub name: String,
+    /// Code of the asset that is paid
+    pub asset: String,
+    /// Amount in stroops that each round distributes
+    pub total: Amount,
+    pub period: SchedulePeriod,
+    /// Key of the user that created the schedule, the rounds are
+    /// created on behalf of it
+    pub created_by: PublicKey,
+    pub created_at: NaiveDateTime,
+    /// End of the last period that got its round
+    pub last_run: Option<NaiveDateTime>,
+    /// Round that is created by the last run
+    pub last_round: Option<RoundId>,
+}
+
+impl ScheduleInfo {
+    /// End of the first period that has no round yet
+    pub fn next_run(&self) -> NaiveDateTime {
+        self.period.next(self.last_run.unwrap_or(self.created_at))
+    }
+
+    /// End of the period that needs a round at the time. Periods that were
+    /// missed while the service was down are skipped, only the latest one
+    /// gets a round.
+    pub fn due(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let run = self.period.start(now);
+        if run >= self.next_run() {
+            Some(run)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the round that is created for the period ending at the time
+    pub fn round_name(&self, run_at: NaiveDateTime) -> String {
+        format!("{} {}", self.name, self.period.label(run_at))
+    }
+}
+
+/// Action to create new round schedule
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddSchedule {
+    pub id: ScheduleId,
+    pub name: String,
+    pub asset: String,
+    pub total: Amount,
+    pub period: SchedulePeriod,
+    /// Key of the user that created the schedule
+    pub created_by: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+impl From<AddSchedule> for ScheduleInfo {
+    fn from(v: AddSchedule) -> Self {
+        ScheduleInfo {
+            id: v.id,
+            name: v.name,
+            asset: v.asset,
+            total: v.total,
+            period: v.period,
+            created_by: v.created_by,
+            created_at: v.timestamp,
+            last_run: None,
+            last_round: None,
+        }
+    }
+}
+
+/// Action to stop the schedule. Rounds it created are kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoveSchedule {
+    pub id: ScheduleId,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to create the draft round of the schedule for the period that
+/// ends at `run_at`. Rejected if the period already has its round, so the
+/// runner can retry it safely.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunSchedule {
+    pub schedule: ScheduleId,
+    /// Id of the created round
+    pub round: RoundId,
+    /// End of the period the round is created for
+    pub run_at: NaiveDateTime,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn schedule(period: SchedulePeriod, created_at: &str) -> ScheduleInfo {
+        ScheduleInfo {
+            id: 1,
+            name: "Dividends".to_owned(),
+            asset: "DIV".to_owned(),
+            total: 100,
+            period,
+            created_by: "key".to_owned(),
+            created_at: time(created_at),
+            last_run: None,
+            last_round: None,
+        }
+    }
+
+    #[test]
+    fn period_bounds() {
+        let t = time("2022-12-14 15:30");
+        let daily = SchedulePeriod::Daily;
+        assert_eq!(daily.start(t), time("2022-12-14 00:00"));
+        assert_eq!(daily.next(t), time("2022-12-15 00:00"));
+        let weekly = SchedulePeriod::Weekly;
+        assert_eq!(weekly.start(t), time("2022-12-12 00:00"));
+        assert_eq!(weekly.next(t), time("2022-12-19 00:00"));
+        let monthly = SchedulePeriod::Monthly;
+        assert_eq!(monthly.start(t), time("2022-12-01 00:00"));
+        assert_eq!(monthly.next(t), time("2023-01-01 00:00"));
+        assert_eq!(
+            monthly.next(time("2023-01-01 00:00")),
+            time("2023-02-01 00:00")
+        );
+    }
+
+    #[test]
+    fn labels_name_the_ended_period() {
+        let end = time("2023-01-02 00:00");
+        assert_eq!(SchedulePeriod::Daily.label(end), "2023-01-01");
+        assert_eq!(SchedulePeriod::Weekly.label(end), "2022-W52");
+        assert_eq!(SchedulePeriod::Monthly.label(end), "2023-01");
+        assert_eq!(
+            schedule(SchedulePeriod::Monthly, "2022-12-14 15:30")
+                .round_name(time("2023-01-01 00:00")),
+            "Dividends 2022-12"
+        );
+    }
+
+    #[test]
+    fn due_once_per_period() {
+        let mut v = schedule(SchedulePeriod::Monthly, "2022-12-14 15:30");
+        assert_eq!(v.due(time("2022-12-31 23:59")), None);
+        assert_eq!(
+            v.due(time("2023-01-01 00:00")),
+            Some(time("2023-01-01 00:00"))
+        );
+        v.last_run = Some(time("2023-01-01 00:00"));
+        assert_eq!(v.due(time("2023-01-20 10:00")), None);
+        // Missed periods are not created one by one
+        assert_eq!(
+            v.due(time("2023-04-03 10:00")),
+            Some(time("2023-04-01 00:00"))
+        );
+    }
+}
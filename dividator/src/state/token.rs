@@ -0,0 +1,69 @@
+use super::admin::PublicKey;
+use super::user::Permission;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Alias for public part of API token that is used to find it
+pub type TokenId = String;
+
+/// API token for machine clients. Only hash of the secret is kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    /// Public identifier of the token
+    pub id: TokenId,
+    /// Human readable name to distinguish tokens
+    pub name: String,
+    /// Hex encoded sha256 of the token secret
+    pub hash: String,
+    /// Permissions that the token grants
+    pub permissions: BTreeSet<Permission>,
+    /// Key of the user that minted the token
+    pub created_by: PublicKey,
+    /// Time of creation
+    pub created_at: NaiveDateTime,
+    /// The token is not accepted after that time
+    pub expires_at: NaiveDateTime,
+}
+
+/// Action to mint new API token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddToken {
+    /// Public identifier of the token
+    pub id: TokenId,
+    /// Human readable name to distinguish tokens
+    pub name: String,
+    /// Hex encoded sha256 of the token secret
+    pub hash: String,
+    /// Permissions that the token grants
+    pub permissions: BTreeSet<Permission>,
+    /// Key of the user that minted the token
+    pub created_by: PublicKey,
+    /// The token is not accepted after that time
+    pub expires_at: NaiveDateTime,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+impl From<AddToken> for TokenInfo {
+    fn from(v: AddToken) -> Self {
+        TokenInfo {
+            id: v.id,
+            name: v.name,
+            hash: v.hash,
+            permissions: v.permissions,
+            created_by: v.created_by,
+            created_at: v.timestamp,
+            expires_at: v.expires_at,
+        }
+    }
+}
+
+/// Action to revoke API token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RevokeToken {
+    /// Public identifier of the token
+    pub id: TokenId,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
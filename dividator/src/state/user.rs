@@ -0,0 +1,122 @@
+use super::admin::{PublicKey, Signature, K1};
+use chrono::NaiveDateTime;
+use rocket::form::FromFormField;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Permissions that linked wallet could have
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    PartialOrd,
+    Eq,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    FromFormField,
+    JsonSchema,
+)]
+pub enum Permission {
+    /// Read only access to the dashboard
+    Viewer,
+    /// Can create and calculate rounds
+    Operator,
+    /// Can submit signatures for payouts
+    Signer,
+    /// All included
+    Admin,
+}
+
+impl Permission {
+    /// All known permissions in order of increasing power
+    pub const ALL: [Permission; 4] = [
+        Permission::Viewer,
+        Permission::Operator,
+        Permission::Signer,
+        Permission::Admin,
+    ];
+
+    /// Return `true` if the permission grants everything that `other` grants.
+    /// Each role includes `Viewer` and `Admin` includes all of them.
+    pub fn implies(&self, other: &Permission) -> bool {
+        match self {
+            Permission::Admin => true,
+            _ if *other == Permission::Viewer => true,
+            _ => self == other,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Viewer => write!(f, "viewer"),
+            Permission::Operator => write!(f, "operator"),
+            Permission::Signer => write!(f, "signer"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Additional user info that we keep in memory
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    /// Public key that was used in linking. Either LNURL linking key
+    /// or Stellar account (G...) for users that log in with SEP-10.
+    pub key: PublicKey,
+    /// Permissions that are granted to the user
+    pub permissions: BTreeSet<Permission>,
+    /// Time of creation
+    pub created_at: NaiveDateTime,
+}
+
+/// Action to link new user by invite
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddUser {
+    /// Public key of the user
+    pub key: PublicKey,
+    /// Permissions that were carried by the invite
+    pub permissions: BTreeSet<Permission>,
+    /// 32 byte hex encoded nonce of the invite
+    pub k1: K1,
+    /// Der encoded signature of the k1 or base64 encoded signed
+    /// SEP-10 challenge for Stellar accounts
+    pub signature: Signature,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+impl From<AddUser> for UserInfo {
+    fn from(v: AddUser) -> Self {
+        UserInfo {
+            key: v.key,
+            permissions: v.permissions,
+            created_at: v.timestamp,
+        }
+    }
+}
+
+/// Action to change permissions of existing user
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetPermissions {
+    /// Public key of the user
+    pub key: PublicKey,
+    /// New set of permissions, replaces the old one
+    pub permissions: BTreeSet<Permission>,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
+
+/// Action to unlink user from the system
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoveUser {
+    /// Public key of the user
+    pub key: PublicKey,
+    /// Time of the event
+    pub timestamp: NaiveDateTime,
+}
@@ -0,0 +1,130 @@
+use super::auth::guard::Admin;
+use super::auth::{
+    generate_auth_lnurl, generate_k1, generate_keyauth_url, AuthedPage, LnAuthAction,
+};
+use super::types::*;
+use log::*;
+use rocket::form::{Form, FromForm};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::{get, post, uri, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct RotateContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    k1: String,
+    lnurl: String,
+    keyauth: String,
+    /// Key of the new wallet when it is waiting for confirmation
+    new_key: Option<String>,
+}
+
+/// Only the wallet that is linked as admin can rotate it, users
+/// with `Admin` permission are not allowed to.
+async fn is_admin_key(db: &DataBase, key: &str) -> bool {
+    let db = db.lock().await;
+    let state = db.get().await;
+    state.admin_key().as_deref() == Some(key)
+}
+
+#[derive(FromForm)]
+pub struct RotateForm {
+    csrf: String,
+}
+
+/// Start the rotation and show its code on the next page
+#[openapi(skip)]
+#[post("/admin/rotate", data = "<form>")]
+pub async fn start_rotate(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    user: AuthedPage<Admin>,
+    form: Form<RotateForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    if !is_admin_key(db, &user.key).await {
+        warn!("User {} tried to rotate admin key", user.key);
+        return Err(Status::Forbidden);
+    }
+    let k1 = generate_k1();
+    cache_mutex.lock().await.add_rotation(&k1, &user.key);
+    Ok(Redirect::to(uri!(rotate(k1))))
+}
+
+#[openapi(skip)]
+#[get("/admin/rotate?<k1>")]
+pub async fn rotate(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    domain: &State<String>,
+    user: AuthedPage<Admin>,
+    k1: String,
+) -> Result<Template, Status> {
+    if !is_admin_key(db, &user.key).await {
+        warn!("User {} tried to rotate admin key", user.key);
+        return Err(Status::Forbidden);
+    }
+    let rotation = cache_mutex.lock().await.has_rotation(&k1).cloned();
+    match rotation {
+        Some(v) if v.old_key == user.key && v.new_key.is_none() => (),
+        _ => return Err(Status::NotFound),
+    }
+    let lnurl = generate_auth_lnurl(domain, &k1, LnAuthAction::Register)?;
+    let keyauth = generate_keyauth_url(domain, &k1, LnAuthAction::Register);
+    let context = RotateContext {
+        base: BaseContext::new("Rotate admin key", &user.session, user.csrf()),
+        k1,
+        lnurl,
+        keyauth,
+        new_key: None,
+    };
+    Ok(Template::render("rotate", context))
+}
+
+#[openapi(skip)]
+#[get("/admin/rotate/confirm?<k1>")]
+pub async fn rotate_confirm(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    domain: &State<String>,
+    user: AuthedPage<Admin>,
+    k1: String,
+) -> Result<Template, Status> {
+    if !is_admin_key(db, &user.key).await {
+        warn!("User {} tried to rotate admin key", user.key);
+        return Err(Status::Forbidden);
+    }
+    let rotation = cache_mutex.lock().await.has_rotation(&k1).cloned();
+    match rotation.and_then(|v| v.new_key) {
+        Some((new_key, _)) => {
+            let lnurl = generate_auth_lnurl(domain, &k1, LnAuthAction::Auth)?;
+            let keyauth = generate_keyauth_url(domain, &k1, LnAuthAction::Auth);
+            let context = RotateContext {
+                base: BaseContext::new("Confirm admin key rotation", &user.session, user.csrf()),
+                k1,
+                lnurl,
+                keyauth,
+                new_key: Some(new_key),
+            };
+            Ok(Template::render("rotate", context))
+        }
+        None => Err(Status::NotFound),
+    }
+}
+
+/// Next step of the rotation, opened by the browser when it gets
+/// `signed` event from `/events/k1`.
+#[openapi(skip)]
+#[get("/admin/rotate/next?<k1>")]
+pub async fn rotate_next(cache_mutex: &State<AuthCache>, k1: String) -> Redirect {
+    let mut cache = cache_mutex.lock().await;
+    match cache.has_rotation(&k1) {
+        Some(v) if v.new_key.is_some() => Redirect::to(uri!(rotate_confirm(k1))),
+        // Rotation is finished, old sessions are dropped
+        _ => Redirect::to(uri!(super::auth::routes::signin)),
+    }
+}
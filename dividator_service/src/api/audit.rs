@@ -0,0 +1,282 @@
+use super::auth::guard::Admin;
+use super::auth::{AuthedPage, AuthedUser};
+use super::types::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use dividator::db::Pool;
+use log::*;
+use rocket::form::{FromForm, FromFormField};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// How many events are shown at once
+const AUDIT_PAGE_SIZE: i64 = 200;
+/// Longest period of the days filter, larger values overflow the date
+const MAX_AUDIT_DAYS: i64 = 3650;
+
+/// Kind of audited auth or admin action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    #[field(value = "login_succeeded")]
+    LoginSucceeded,
+    #[field(value = "login_failed")]
+    LoginFailed,
+    #[field(value = "admin_registered")]
+    AdminRegistered,
+    #[field(value = "admin_rotated")]
+    AdminRotated,
+    #[field(value = "invite_created")]
+    InviteCreated,
+    #[field(value = "user_linked")]
+    UserLinked,
+    #[field(value = "role_changed")]
+    RoleChanged,
+    #[field(value = "user_removed")]
+    UserRemoved,
+    #[field(value = "session_revoked")]
+    SessionRevoked,
+    #[field(value = "token_minted")]
+    TokenMinted,
+    #[field(value = "token_revoked")]
+    TokenRevoked,
+    #[field(value = "round_approved")]
+    RoundApproved,
+    #[field(value = "round_cancelled")]
+    RoundCancelled,
+    #[field(value = "round_imported")]
+    RoundImported,
+}
+
+impl AuditKind {
+    pub const ALL: [AuditKind; 14] = [
+        AuditKind::LoginSucceeded,
+        AuditKind::LoginFailed,
+        AuditKind::AdminRegistered,
+        AuditKind::AdminRotated,
+        AuditKind::InviteCreated,
+        AuditKind::UserLinked,
+        AuditKind::RoleChanged,
+        AuditKind::UserRemoved,
+        AuditKind::SessionRevoked,
+        AuditKind::TokenMinted,
+        AuditKind::TokenRevoked,
+        AuditKind::RoundApproved,
+        AuditKind::RoundCancelled,
+        AuditKind::RoundImported,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::LoginSucceeded => "login_succeeded",
+            AuditKind::LoginFailed => "login_failed",
+            AuditKind::AdminRegistered => "admin_registered",
+            AuditKind::AdminRotated => "admin_rotated",
+            AuditKind::InviteCreated => "invite_created",
+            AuditKind::UserLinked => "user_linked",
+            AuditKind::RoleChanged => "role_changed",
+            AuditKind::UserRemoved => "user_removed",
+            AuditKind::SessionRevoked => "session_revoked",
+            AuditKind::TokenMinted => "token_minted",
+            AuditKind::TokenRevoked => "token_revoked",
+            AuditKind::RoundApproved => "round_approved",
+            AuditKind::RoundCancelled => "round_cancelled",
+            AuditKind::RoundImported => "round_imported",
+        }
+    }
+}
+
+impl fmt::Display for AuditKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AuditKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AuditKind::ALL
+            .iter()
+            .find(|v| v.as_str() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown audit event kind {}", s))
+    }
+}
+
+/// Recorded audit event
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub kind: AuditKind,
+    /// Linking key of the user that made the action
+    pub key: Option<String>,
+    /// IP address the action came from
+    pub ip: Option<String>,
+    /// Target of the action or failure reason
+    pub details: Option<String>,
+}
+
+/// Row of the `audit` table
+type AuditRow = (
+    i64,
+    NaiveDateTime,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+/// Filter of the audit page
+#[derive(Debug, Clone, Default, FromForm)]
+pub struct AuditFilter {
+    pub kind: Option<AuditKind>,
+    pub key: Option<String>,
+    pub ip: Option<String>,
+    /// Only events of the last days, clamped to 0..=3650
+    pub days: Option<i64>,
+}
+
+/// Audit trail of auth and admin actions that is kept in the `audit`
+/// table, so it survives log rotation.
+#[derive(Clone)]
+pub struct AuditLog {
+    pool: Pool,
+}
+
+impl AuditLog {
+    pub fn new(pool: Pool) -> Self {
+        AuditLog { pool }
+    }
+
+    /// Record the event. Failures are only logged as the audit must
+    /// not break the action itself.
+    pub async fn record(
+        &self,
+        kind: AuditKind,
+        key: Option<&str>,
+        ip: Option<&str>,
+        details: Option<&str>,
+    ) {
+        let res = sqlx::query("insert into audit (kind, key, ip, details) values ($1, $2, $3, $4)")
+            .bind(kind.as_str())
+            .bind(key)
+            .bind(ip)
+            .bind(details)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = res {
+            error!("Failed to record audit event {}: {}", kind, e);
+        }
+    }
+
+    /// Latest events that match the filter
+    pub async fn list(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        let since = filter
+            .days
+            .map(|v| Utc::now().naive_utc() - Duration::days(v.clamp(0, MAX_AUDIT_DAYS)));
+        let rows: Vec<AuditRow> = sqlx::query_as(
+            "select id, created_at, kind, key, ip, details from audit
+            where ($1::text is null or kind = $1)
+                and ($2::text is null or key = $2)
+                and ($3::text is null or ip = $3)
+                and ($4::timestamp is null or created_at >= $4)
+            order by created_at desc, id desc
+            limit $5",
+        )
+        .bind(filter.kind.map(|v| v.as_str()))
+        .bind(filter.key.as_deref().filter(|v| !v.is_empty()))
+        .bind(filter.ip.as_deref().filter(|v| !v.is_empty()))
+        .bind(since)
+        .bind(AUDIT_PAGE_SIZE)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(id, created_at, kind, key, ip, details)| {
+                let kind = match AuditKind::from_str(&kind) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("{}", e);
+                        return None;
+                    }
+                };
+                Some(AuditEvent {
+                    id,
+                    created_at,
+                    kind,
+                    key,
+                    ip,
+                    details,
+                })
+            })
+            .collect())
+    }
+}
+
+#[derive(Serialize)]
+struct AuditContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    events: Vec<AuditEvent>,
+    kinds: Vec<&'static str>,
+    kind: Option<&'static str>,
+    key: Option<String>,
+    ip: Option<String>,
+    days: Option<i64>,
+}
+
+#[openapi(skip)]
+#[get("/audit?<filter..>")]
+pub async fn audit(
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    filter: AuditFilter,
+) -> Result<Template, Status> {
+    let events = audit_log.list(&filter).await.map_err(|e| {
+        error!("Failed to read audit events: {}", e);
+        Status::InternalServerError
+    })?;
+    let context = AuditContext {
+        base: BaseContext::new("Audit", &user.session, user.csrf()),
+        events,
+        kinds: AuditKind::ALL.iter().map(|v| v.as_str()).collect(),
+        kind: filter.kind.map(|v| v.as_str()),
+        key: filter.key,
+        ip: filter.ip,
+        days: filter.days,
+    };
+    Ok(Template::render("audit", context))
+}
+
+/// Latest audit events of auth and admin actions, newest first
+#[openapi(tag = "audit")]
+#[get("/api/audit?<kind>&<key>&<ip>&<days>")]
+pub async fn audit_json(
+    audit_log: &State<AuditLog>,
+    _user: AuthedUser<Admin>,
+    kind: Option<AuditKind>,
+    key: Option<String>,
+    ip: Option<String>,
+    days: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, Status> {
+    let filter = AuditFilter {
+        kind,
+        key,
+        ip,
+        days,
+    };
+    let events = audit_log.list(&filter).await.map_err(|e| {
+        error!("Failed to read audit events: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(Json(events))
+}
@@ -2,7 +2,11 @@ use super::types::*;
 use chrono::prelude::*;
 use chrono::Duration;
 use dividator::state::{PublicKey, K1};
-use std::collections::{HashMap, HashSet};
+use log::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Default limit of k1 keys that wait for the signature
+pub const MAX_PENDING_KEYS: usize = 10_000;
 
 /// Cache for used k1 nonces that are signed by wallet.
 ///
@@ -17,15 +21,68 @@ use std::collections::{HashMap, HashSet};
 pub struct Cache {
     /// Amount of time we expect to wallet to login
     pub login_timeout: Duration,
-    /// Amount of time session persists without activity
-    pub session_timeout: Duration,
+    /// Amount of time an invite link stays valid
+    pub invite_timeout: Duration,
     /// Cached K1 keys that we allow to login
     pub keys: HashMap<K1, NaiveDateTime>,
-    /// Memorized sessions of logged users
-    pub sessions: HashMap<K1, SessionInfo>,
+    /// Timeouts of the keys in the order they were added
+    keys_order: VecDeque<(NaiveDateTime, K1)>,
+    /// Maximum amount of pending k1 keys and challenges, the ones
+    /// that expire first are evicted when the limit is reached.
+    pub max_keys: usize,
+    /// How many pending k1 keys and challenges were evicted
+    pub evicted_keys: u64,
+    /// One-time invites that are not accepted yet
+    pub invites: HashMap<K1, InviteInfo>,
+    /// Admin key rotations that are in progress
+    pub rotations: HashMap<K1, RotationInfo>,
+    /// SEP-10 challenges that are sent to Stellar wallets, keyed by
+    /// k1 of the page that waits for login
+    pub challenges: HashMap<K1, ChallengeInfo>,
+    /// Timeouts of the challenges in the order they were added
+    challenges_order: VecDeque<(NaiveDateTime, K1)>,
+    /// Freshly minted API tokens that are shown once after redirect,
+    /// keyed by k1 of the session that minted them
+    pub minted: HashMap<K1, (String, NaiveDateTime)>,
+    /// Sessions that are created for signed k1s of the pages, the page
+    /// k1 is public, so the session gets its own k1 that the browser
+    /// picks once.
+    pub signed: HashMap<K1, (K1, NaiveDateTime)>,
+}
+
+/// SEP-10 challenge that waits for the signature of the account
+#[derive(Clone, Debug)]
+pub struct ChallengeInfo {
+    /// Stellar account that should sign the challenge
+    pub account: String,
+    /// XDR of the challenge transaction
+    pub tx: Vec<u8>,
+    /// When the challenge will expire
+    pub timeout: NaiveDateTime,
+}
+
+/// Invite for a new wallet that is not used yet
+#[derive(Clone, Debug)]
+pub struct InviteInfo {
+    /// Permissions that the linked wallet will get
+    pub permissions: Vec<Permission>,
+    /// When the invite will expire
+    pub timeout: NaiveDateTime,
+}
+
+/// Admin key rotation that waits for signatures of both wallets
+#[derive(Clone, Debug)]
+pub struct RotationInfo {
+    /// Key of the current admin that started the rotation
+    pub old_key: PublicKey,
+    /// Key and signature of the new wallet when it scanned the code
+    pub new_key: Option<(PublicKey, String)>,
+    /// When the rotation will expire
+    pub timeout: NaiveDateTime,
 }
 
 /// Session runtime information
+#[derive(Clone, Debug)]
 pub struct SessionInfo {
     /// Wallet linking key
     pub key: PublicKey,
@@ -33,87 +90,329 @@ pub struct SessionInfo {
     pub timeout: NaiveDateTime,
     /// Permissions of the session
     pub permissions: HashSet<Permission>,
+    /// When the user logged in
+    pub created_at: NaiveDateTime,
+    /// Last time the session was used
+    pub last_activity: NaiveDateTime,
+    /// IP address of the browser that uses the session
+    pub ip: Option<String>,
+    /// User agent of the browser that uses the session
+    pub user_agent: Option<String>,
 }
 
 impl SessionInfo {
     /// Return `true` if the session has all the required permissions
     pub fn check_permissions(&self, perms: &[Permission]) -> bool {
         for p in perms {
-            if !self.permissions.contains(p) {
+            if !self.permissions.iter().any(|sp| sp.implies(p)) {
                 return false;
             }
         }
         return true;
     }
+
+    /// Return the most powerful permission of the session to show in UI
+    pub fn role(&self) -> Option<Permission> {
+        self.permissions.iter().max().cloned()
+    }
 }
 
 impl Cache {
-    pub fn new(login_timeout: Duration, session_timeout: Duration) -> Self {
+    pub fn new(login_timeout: Duration, invite_timeout: Duration) -> Self {
         Cache {
             login_timeout,
-            session_timeout,
+            invite_timeout,
             keys: HashMap::new(),
-            sessions: HashMap::new(),
+            keys_order: VecDeque::new(),
+            max_keys: MAX_PENDING_KEYS,
+            evicted_keys: 0,
+            invites: HashMap::new(),
+            rotations: HashMap::new(),
+            challenges: HashMap::new(),
+            challenges_order: VecDeque::new(),
+            minted: HashMap::new(),
+            signed: HashMap::new(),
         }
     }
 
     /// Add given k1 secret to internal storage with timeout.
-    /// Also removes outdated keys from the map.
+    /// Also removes outdated keys from the map and evicts the oldest
+    /// ones if there are too many.
     pub fn add(&mut self, k1: &str) {
         self.cleanup();
-        self.keys
-            .insert(k1.to_owned(), Utc::now().naive_utc() + self.login_timeout);
+        self.evicted_keys += evict(&mut self.keys, &mut self.keys_order, self.max_keys, |t| *t);
+        let timeout = Utc::now().naive_utc() + self.login_timeout;
+        self.keys.insert(k1.to_owned(), timeout);
+        self.keys_order.push_back((timeout, k1.to_owned()));
+    }
+
+    /// Check if the key is located in the cache without removing it
+    pub fn has_key(&mut self, k1: &str) -> bool {
+        self.cleanup();
+        self.keys.contains_key(k1)
     }
 
     /// Check if the key is located in the cache
     /// if so return `true` and remove it from the cache.
     /// If there is no such key, returns `false`.
     pub fn pick(&mut self, k1: &str) -> bool {
-        if self.keys.contains_key(k1) {
-            self.keys.remove(k1);
-            true
-        } else {
-            false
+        match self.keys.remove(k1) {
+            Some(timeout) => timeout >= Utc::now().naive_utc(),
+            None => false,
         }
     }
 
-    /// Check if we know that k1 key as active session
-    pub fn has_session(&mut self, k1: &str) -> Option<&SessionInfo> {
+    /// Add one-time invite that grants given permissions to the wallet
+    /// that signs the k1.
+    pub fn add_invite(&mut self, k1: &str, permissions: &[Permission]) {
         self.cleanup();
-        self.sessions.get(k1)
+        self.invites.insert(
+            k1.to_owned(),
+            InviteInfo {
+                permissions: permissions.to_vec(),
+                timeout: Utc::now().naive_utc() + self.invite_timeout,
+            },
+        );
     }
 
-    /// Create session or update existing one
-    pub fn upsert_session(&mut self, k1: &str, pub_key: &str, permissions: &[Permission]) {
+    /// Check if we know that k1 key as active invite
+    pub fn has_invite(&mut self, k1: &str) -> Option<&InviteInfo> {
         self.cleanup();
-        let now = Utc::now().naive_utc();
-        match self.sessions.get_mut(k1) {
-            Some(r) => r.timeout = now + self.session_timeout,
-            None => {
-                self.sessions.insert(
-                    k1.to_owned(),
-                    SessionInfo {
-                        key: pub_key.to_owned(),
-                        timeout: now + self.session_timeout,
-                        permissions: permissions.iter().cloned().collect(),
-                    },
-                );
-            }
-        }
+        self.invites.get(k1)
+    }
+
+    /// Forget the invite after it was accepted
+    pub fn remove_invite(&mut self, k1: &str) {
+        self.invites.remove(k1);
+    }
+
+    /// Start admin key rotation that is confirmed by signatures of k1
+    pub fn add_rotation(&mut self, k1: &str, old_key: &str) {
+        self.cleanup();
+        self.rotations.insert(
+            k1.to_owned(),
+            RotationInfo {
+                old_key: old_key.to_owned(),
+                new_key: None,
+                timeout: Utc::now().naive_utc() + self.login_timeout,
+            },
+        );
+    }
+
+    /// Check if we know that k1 key as rotation in progress
+    pub fn has_rotation(&mut self, k1: &str) -> Option<&mut RotationInfo> {
+        self.cleanup();
+        self.rotations.get_mut(k1)
+    }
+
+    /// Forget the rotation after it was finished
+    pub fn remove_rotation(&mut self, k1: &str) {
+        self.rotations.remove(k1);
+    }
+
+    /// Remember SEP-10 challenge for the account. Replaces previous
+    /// challenge for the same k1.
+    pub fn add_challenge(&mut self, k1: &str, account: &str, tx: Vec<u8>) {
+        self.cleanup();
+        self.evicted_keys += evict(
+            &mut self.challenges,
+            &mut self.challenges_order,
+            self.max_keys,
+            |v| v.timeout,
+        );
+        let timeout = Utc::now().naive_utc() + self.login_timeout;
+        self.challenges.insert(
+            k1.to_owned(),
+            ChallengeInfo {
+                account: account.to_owned(),
+                tx,
+                timeout,
+            },
+        );
+        self.challenges_order.push_back((timeout, k1.to_owned()));
+    }
+
+    /// Take SEP-10 challenge out of the cache, it can be used only once
+    pub fn pick_challenge(&mut self, k1: &str) -> Option<ChallengeInfo> {
+        self.cleanup();
+        self.challenges.remove(k1)
+    }
+
+    /// Remember minted API token until the session shows it
+    pub fn add_minted(&mut self, k1: &str, token: String) {
+        self.cleanup();
+        let timeout = Utc::now().naive_utc() + self.login_timeout;
+        self.minted.insert(k1.to_owned(), (token, timeout));
+    }
+
+    /// Take minted API token of the session, it is shown only once
+    pub fn pick_minted(&mut self, k1: &str) -> Option<String> {
+        self.cleanup();
+        self.minted.remove(k1).map(|(v, _)| v)
+    }
+
+    /// Remember that the page k1 is signed and the session is created
+    /// with another k1
+    pub fn add_signed(&mut self, k1: &str, session_k1: &str) {
+        self.cleanup();
+        let timeout = Utc::now().naive_utc() + self.login_timeout;
+        self.signed
+            .insert(k1.to_owned(), (session_k1.to_owned(), timeout));
+    }
+
+    /// Check if the page k1 is signed and its session waits for the browser
+    pub fn has_signed(&mut self, k1: &str) -> bool {
+        self.cleanup();
+        self.signed.contains_key(k1)
+    }
+
+    /// Take k1 of the session that is created for the signed page k1,
+    /// it can be taken only once
+    pub fn pick_signed(&mut self, k1: &str) -> Option<K1> {
+        self.cleanup();
+        self.signed.remove(k1).map(|(v, _)| v)
     }
 
     // pub fn touch_session(&mut self, k1: &str)
-    /// Cleanup outdated keys from the cache.
+    /// Cleanup outdated keys, invites, rotations, challenges, minted
+    /// tokens and signed page k1s from the cache.
+    /// Sessions are swept separately by `Sessions::sweep`.
     pub fn cleanup(&mut self) {
         let now = Utc::now().naive_utc();
-        self.keys.retain(|_, t| *t >= now);
-        self.sessions.retain(|_, v| v.timeout >= now);
+        expire(&mut self.keys, &mut self.keys_order, now, |t| *t);
+        self.invites.retain(|_, v| v.timeout >= now);
+        self.rotations.retain(|_, v| v.timeout >= now);
+        expire(&mut self.challenges, &mut self.challenges_order, now, |v| {
+            v.timeout
+        });
+        self.minted.retain(|_, (_, t)| *t >= now);
+        self.signed.retain(|_, (_, t)| *t >= now);
+    }
+}
+
+/// Take the first entry of the queue and remove it from the map, unless
+/// the k1 was already removed or added once more. Returns `false` when
+/// the queue is empty.
+fn pop_oldest<V, F>(
+    map: &mut HashMap<K1, V>,
+    order: &mut VecDeque<(NaiveDateTime, K1)>,
+    timeout: F,
+    removed: &mut u64,
+) -> bool
+where
+    F: Fn(&V) -> NaiveDateTime,
+{
+    match order.pop_front() {
+        Some((t, k1)) => {
+            if map.get(&k1).map(|v| timeout(v) == t).unwrap_or(false) {
+                map.remove(&k1);
+                *removed += 1;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drop expired entries. All of them get the same login timeout, so
+/// the queue in the order of insertion is also ordered by timeouts.
+fn expire<V, F>(
+    map: &mut HashMap<K1, V>,
+    order: &mut VecDeque<(NaiveDateTime, K1)>,
+    now: NaiveDateTime,
+    timeout: F,
+) where
+    F: Fn(&V) -> NaiveDateTime,
+{
+    let mut expired = 0;
+    while order.front().map(|(t, _)| *t < now).unwrap_or(false) {
+        pop_oldest(map, order, &timeout, &mut expired);
+    }
+}
+
+/// Drop entries that expire first until there is a room for a new one.
+/// Returns amount of evicted entries.
+fn evict<V, F>(
+    map: &mut HashMap<K1, V>,
+    order: &mut VecDeque<(NaiveDateTime, K1)>,
+    max: usize,
+    timeout: F,
+) -> u64
+where
+    F: Fn(&V) -> NaiveDateTime,
+{
+    let mut evicted = 0;
+    while map.len() >= max && pop_oldest(map, order, &timeout, &mut evicted) {}
+    if evicted > 0 {
+        warn!("Evicted {} pending k1 keys, the cache is full", evicted);
     }
+    evicted
 }
 
 impl Default for Cache {
-    /// 5 minutes default timeout for login and 30 minutes for session
+    /// 5 minutes default timeout for login and 24 hours for invites
     fn default() -> Self {
-        Cache::new(Duration::minutes(5), Duration::minutes(30))
+        Cache::new(Duration::minutes(5), Duration::hours(24))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_keys: usize) -> Cache {
+        let mut cache = Cache::default();
+        cache.max_keys = max_keys;
+        cache
+    }
+
+    #[test]
+    fn evicts_keys_that_expire_first() {
+        let mut cache = cache(2);
+        for k1 in ["a", "b", "c"] {
+            cache.add(k1);
+        }
+        assert!(!cache.has_key("a"));
+        assert!(cache.has_key("b"));
+        assert!(cache.has_key("c"));
+        assert_eq!(cache.evicted_keys, 1);
+        assert_eq!(cache.keys_order.len(), 2);
+    }
+
+    #[test]
+    fn checks_dont_prolong_keys() {
+        let mut cache = cache(2);
+        cache.add("a");
+        let timeout = cache.keys["a"];
+        for _ in 0..10 {
+            assert!(cache.has_key("a"));
+        }
+        assert_eq!(cache.keys["a"], timeout);
+        assert_eq!(cache.keys_order.len(), 1);
+        assert!(cache.pick("a"));
+        assert!(!cache.pick("a"));
+    }
+
+    #[test]
+    fn expired_keys_are_dropped() {
+        let mut cache = cache(2);
+        cache.login_timeout = Duration::seconds(-1);
+        cache.add("a");
+        assert!(!cache.has_key("a"));
+        assert!(cache.keys_order.is_empty());
+        assert_eq!(cache.evicted_keys, 0);
+    }
+
+    #[test]
+    fn evicts_challenges_that_expire_first() {
+        let mut cache = cache(1);
+        cache.add_challenge("a", "GA", vec![]);
+        cache.add_challenge("b", "GB", vec![]);
+        assert!(cache.pick_challenge("a").is_none());
+        assert_eq!(
+            cache.pick_challenge("b").map(|v| v.account).as_deref(),
+            Some("GB")
+        );
+        assert_eq!(cache.evicted_keys, 1);
     }
 }
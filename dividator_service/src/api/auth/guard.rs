@@ -0,0 +1,382 @@
+use super::cache::SessionInfo;
+use super::types::{ClientInfo, Permission};
+use super::{csrf_token, routes, session_id, token_session, AuthResponse, AUTH_COOKIE};
+use crate::api::types::*;
+use dividator::state::K1;
+use log::*;
+use rocket::http::{Cookie, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{catch, uri, Responder};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use thiserror::Error;
+
+/// Role that is required by `AuthedUser` and `AuthedPage` guards
+pub trait Role: Send + Sync + 'static {
+    const PERMISSION: Permission;
+}
+
+/// Requires `Permission::Viewer`
+pub struct Viewer;
+/// Requires `Permission::Operator`
+pub struct Operator;
+/// Requires `Permission::Signer`
+pub struct Signer;
+/// Requires `Permission::Admin`
+pub struct Admin;
+
+impl Role for Viewer {
+    const PERMISSION: Permission = Permission::Viewer;
+}
+
+impl Role for Operator {
+    const PERMISSION: Permission = Permission::Operator;
+}
+
+impl Role for Signer {
+    const PERMISSION: Permission = Permission::Signer;
+}
+
+impl Role for Admin {
+    const PERMISSION: Permission = Permission::Admin;
+}
+
+/// Reason why the request is not authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum AuthError {
+    #[error("Auth state is not configured")]
+    Internal,
+    #[error("System is not initialized yet")]
+    NotInitialized,
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Session is expired")]
+    Expired,
+    #[error("User is not linked anymore")]
+    Unlinked,
+    #[error("Invalid or expired API token")]
+    InvalidToken,
+    #[error("User has no permissions at all")]
+    NoPermissions,
+    #[error("Not enough permissions")]
+    Forbidden,
+    #[error("Missing or invalid X-CSRF-Token header")]
+    Csrf,
+}
+
+impl AuthError {
+    /// Status that is returned to API clients
+    pub fn status(&self) -> Status {
+        match self {
+            AuthError::Internal => Status::InternalServerError,
+            AuthError::NoPermissions | AuthError::Forbidden | AuthError::Csrf => Status::Forbidden,
+            _ => Status::Unauthorized,
+        }
+    }
+
+    /// Session cookie should be dropped as it can't be used anymore
+    pub fn drop_session(&self) -> bool {
+        matches!(
+            self,
+            AuthError::Expired | AuthError::Unlinked | AuthError::NoPermissions
+        )
+    }
+
+    /// Where to send the browser for HTML pages
+    pub fn redirect(&self) -> Redirect {
+        match self {
+            AuthError::NotInitialized => Redirect::to(uri!(routes::init)),
+            AuthError::Forbidden => Redirect::to(uri!("/")),
+            _ => Redirect::to(uri!(routes::signin)),
+        }
+    }
+}
+
+/// Failed authentication that is remembered for the catchers
+#[derive(Debug, Clone, Copy)]
+struct AuthFailure {
+    error: AuthError,
+    /// The request is made by HTML page and should be redirected
+    page: bool,
+}
+
+fn managed<'r, T: Send + Sync + 'static>(req: &'r Request<'_>) -> Result<&'r T, AuthError> {
+    req.rocket().state::<T>().ok_or(AuthError::Internal)
+}
+
+fn check_role(session: SessionInfo, permission: Permission) -> Result<SessionInfo, AuthError> {
+    if session.check_permissions(&[permission]) {
+        Ok(session)
+    } else if session.check_permissions(&[Permission::Viewer]) {
+        warn!(
+            "User doesn' have required permission: {}, user perms: {:?}",
+            permission, session.permissions
+        );
+        Err(AuthError::Forbidden)
+    } else {
+        warn!("User {} has no permissions at all", session.key);
+        Err(AuthError::NoPermissions)
+    }
+}
+
+/// Find session of the request by API token or session cookie.
+///
+/// Permissions of the session are refreshed from the state on each
+/// request, so changes of user roles take effect without relogin.
+async fn authenticate(
+    req: &Request<'_>,
+    permission: Permission,
+) -> Result<(SessionInfo, Option<K1>), AuthError> {
+    let db_mutex = managed::<DataBase>(req)?;
+    let sessions = managed::<AuthSessions>(req)?;
+    let client = req
+        .guard::<ClientInfo>()
+        .await
+        .succeeded()
+        .unwrap_or_default();
+
+    if let Some(token) = &client.token {
+        let session = {
+            let db = db_mutex.lock().await;
+            let state = db.get().await;
+            token_session(&state, token)
+        };
+        let session = session.ok_or_else(|| {
+            warn!("Invalid or expired API token");
+            AuthError::InvalidToken
+        })?;
+        return check_role(session, permission).map(|v| (v, None));
+    }
+
+    let has_admin = {
+        let db = db_mutex.lock().await;
+        let state = db.get().await;
+        state.has_admin()
+    };
+    if !has_admin {
+        info!("System is not fully initialized");
+        return Err(AuthError::NotInitialized);
+    }
+    let cookies = req.cookies();
+    let cookie = cookies.get_private(AUTH_COOKIE).ok_or_else(|| {
+        info!("User is not logged in");
+        AuthError::NotLoggedIn
+    })?;
+    let k1 = cookie.value().to_owned();
+    let session = match sessions.get(&k1).await {
+        Some(v) => v,
+        None => {
+            warn!("User session {} expired", session_id(&k1));
+            cookies.remove_private(cookie);
+            return Err(AuthError::Expired);
+        }
+    };
+    let actual = {
+        let db = db_mutex.lock().await;
+        let state = db.get().await;
+        state.permissions(&session.key)
+    };
+    let actual: Vec<Permission> = match actual {
+        Some(v) => v.into_iter().collect(),
+        None => {
+            warn!("User {} is not linked anymore", session.key);
+            sessions.remove(&k1).await;
+            cookies.remove_private(cookie);
+            return Err(AuthError::Unlinked);
+        }
+    };
+    sessions.touch(&k1, &session, &actual, &client).await;
+    let session = SessionInfo {
+        permissions: actual.into_iter().collect(),
+        ..session
+    };
+    check_role(session, permission).map(|v| (v, Some(k1)))
+}
+
+/// Request guard for API routes that requires the role `R`. Accepts
+/// session cookie or API token and fails with 401 or 403 status.
+pub struct AuthedUser<R> {
+    pub session: SessionInfo,
+    /// Session id from the cookie, `None` for API tokens
+    pub k1: Option<K1>,
+    role: PhantomData<R>,
+}
+
+impl<R> AuthedUser<R> {
+    /// CSRF token for the forms of the session, `None` for API tokens
+    pub fn csrf(&self) -> Option<String> {
+        self.k1.as_deref().map(csrf_token)
+    }
+
+    /// Check CSRF token of the submitted form. Requests with API tokens
+    /// are not checked as browsers never attach them by themselves.
+    pub fn check_csrf(&self, token: &str) -> Result<(), Status> {
+        match &self.k1 {
+            Some(k1) if csrf_token(k1) != token => {
+                warn!(
+                    "User {} submitted form with invalid CSRF token",
+                    self.session.key
+                );
+                Err(Status::Forbidden)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R> Deref for AuthedUser<R> {
+    type Target = SessionInfo;
+
+    fn deref(&self) -> &SessionInfo {
+        &self.session
+    }
+}
+
+async fn authed_user<R: Role>(req: &Request<'_>, page: bool) -> Outcome<AuthedUser<R>, AuthError> {
+    match authenticate(req, R::PERMISSION).await {
+        Ok((session, k1)) => Outcome::Success(AuthedUser {
+            session,
+            k1,
+            role: PhantomData,
+        }),
+        Err(error) => {
+            req.local_cache(|| Some(AuthFailure { error, page }));
+            Outcome::Failure((error.status(), error))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, R: Role> FromRequest<'r> for AuthedUser<R> {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authed_user(req, false).await
+    }
+}
+
+impl<'r, R: Role> OpenApiFromRequest<'r> for AuthedUser<R> {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// Header with CSRF token of the session for API requests that change data
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Request guard for API routes that change data and require the role `R`.
+/// Requests with the session cookie must send the CSRF token of the session
+/// in `X-CSRF-Token` header, API tokens are not checked as browsers never
+/// attach them by themselves.
+pub struct AuthedChange<R>(pub AuthedUser<R>);
+
+impl<R> Deref for AuthedChange<R> {
+    type Target = AuthedUser<R>;
+
+    fn deref(&self) -> &AuthedUser<R> {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, R: Role> FromRequest<'r> for AuthedChange<R> {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match authed_user::<R>(req, false).await {
+            Outcome::Success(v) => v,
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(v) => return Outcome::Forward(v),
+        };
+        let token = req.headers().get_one(CSRF_HEADER);
+        match (&user.k1, token) {
+            (Some(k1), Some(token)) if csrf_token(k1) == token => {
+                Outcome::Success(AuthedChange(user))
+            }
+            (Some(_), _) => {
+                warn!(
+                    "User {} sent API request without valid CSRF token",
+                    user.key
+                );
+                let error = AuthError::Csrf;
+                req.local_cache(|| Some(AuthFailure { error, page: false }));
+                Outcome::Failure((error.status(), error))
+            }
+            (None, _) => Outcome::Success(AuthedChange(user)),
+        }
+    }
+}
+
+impl<'r, R: Role> OpenApiFromRequest<'r> for AuthedChange<R> {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// Request guard for HTML pages that requires the role `R`. Failures
+/// are turned into redirects to signin or main page by the catchers.
+pub struct AuthedPage<R>(pub AuthedUser<R>);
+
+impl<R> Deref for AuthedPage<R> {
+    type Target = AuthedUser<R>;
+
+    fn deref(&self) -> &AuthedUser<R> {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, R: Role> FromRequest<'r> for AuthedPage<R> {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authed_user(req, true).await.map(AuthedPage)
+    }
+}
+
+/// Response of the catchers for failed authentication
+#[derive(Responder)]
+pub enum AuthFailureResponse {
+    Redirect(Redirect),
+    Error(Json<AuthResponse>),
+}
+
+/// Cookies that are changed by the failed guard are discarded by Rocket,
+/// so the dead session cookie is removed here.
+fn auth_failure(status: Status, req: &Request) -> AuthFailureResponse {
+    let failure = *req.local_cache(|| None::<AuthFailure>);
+    if failure.map(|v| v.error.drop_session()).unwrap_or(false) {
+        req.cookies().remove_private(Cookie::named(AUTH_COOKIE));
+    }
+    match failure {
+        Some(v) if v.page => AuthFailureResponse::Redirect(v.error.redirect()),
+        Some(v) => AuthFailureResponse::Error(Json(AuthResponse::Error {
+            reason: v.error.to_string(),
+        })),
+        None => AuthFailureResponse::Error(Json(AuthResponse::Error {
+            reason: status.reason().unwrap_or_default().to_owned(),
+        })),
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> AuthFailureResponse {
+    auth_failure(Status::Unauthorized, req)
+}
+
+#[catch(403)]
+pub fn forbidden(req: &Request) -> AuthFailureResponse {
+    auth_failure(Status::Forbidden, req)
+}
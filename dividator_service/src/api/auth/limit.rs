@@ -0,0 +1,406 @@
+use super::cache::MAX_PENDING_KEYS;
+use super::AuthResponse;
+use crate::api::types::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use log::*;
+use rocket::catch;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+/// Limits for unauthenticated auth endpoints
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    /// How many requests a single IP can make per minute
+    pub requests_per_minute: u32,
+    /// How many failed login attempts lead to the lockout
+    pub max_failures: u32,
+    /// How long the IP is locked out after too many failures
+    pub lockout: Duration,
+    /// Client IPs come from the trusted `--ip-header`. Without it all
+    /// clients behind a proxy share its IP, so nobody is locked out.
+    pub trusted_ip: bool,
+    /// How many k1s can wait for the signature at once, the ones that
+    /// expire first are evicted.
+    pub max_pending_k1: usize,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            requests_per_minute: 60,
+            max_failures: 5,
+            lockout: Duration::minutes(15),
+            trusted_ip: true,
+            max_pending_k1: MAX_PENDING_KEYS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum LimitError {
+    #[error("Too many requests, try again in a minute")]
+    TooManyRequests,
+    #[error("Too many failed attempts, try again later")]
+    LockedOut,
+    #[error("Rate limiter is not configured")]
+    Internal,
+}
+
+/// Counters of rejected auth attempts
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct AuthMetrics {
+    /// Requests rejected by per IP rate limit
+    pub rate_limited: u64,
+    /// Requests rejected because the IP is locked out
+    pub locked_out: u64,
+    /// Failed signature checks and logins
+    pub failed_attempts: u64,
+    /// Times an IP was locked out
+    pub lockouts: u64,
+    /// Pending k1s that were evicted to keep the cache bounded
+    pub evicted_k1: u64,
+}
+
+/// Requests of the IP in the current minute
+struct Window {
+    start: NaiveDateTime,
+    count: u32,
+}
+
+/// Failed attempts of the IP
+struct Failures {
+    count: u32,
+    last: NaiveDateTime,
+    locked_until: Option<NaiveDateTime>,
+}
+
+/// Per IP rate limiting and lockout on repeated failures
+pub struct RateLimiter {
+    pub config: LimitConfig,
+    pub metrics: AuthMetrics,
+    windows: HashMap<String, Window>,
+    failures: HashMap<String, Failures>,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitConfig) -> Self {
+        RateLimiter {
+            config,
+            metrics: AuthMetrics::default(),
+            windows: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Count the request of the IP and check that it is allowed
+    pub fn check(&mut self, ip: &str) -> Result<(), LimitError> {
+        self.cleanup();
+        let now = Utc::now().naive_utc();
+        if let Some(until) = self.failures.get(ip).and_then(|v| v.locked_until) {
+            if until > now {
+                self.metrics.locked_out += 1;
+                return Err(LimitError::LockedOut);
+            }
+        }
+        let window = self.windows.entry(ip.to_owned()).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        window.count += 1;
+        if window.count > self.config.requests_per_minute {
+            self.metrics.rate_limited += 1;
+            Err(LimitError::TooManyRequests)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remember failed attempt of the IP, locks it out after too many
+    pub fn fail(&mut self, ip: &str) {
+        let now = Utc::now().naive_utc();
+        self.metrics.failed_attempts += 1;
+        if !self.config.trusted_ip {
+            return;
+        }
+        let failures = self.failures.entry(ip.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= self.config.max_failures {
+            warn!(
+                "Locking out {} after {} failed attempts",
+                ip, failures.count
+            );
+            failures.count = 0;
+            failures.locked_until = Some(now + self.config.lockout);
+            self.metrics.lockouts += 1;
+        }
+    }
+
+    /// Forget failed attempts of the IP after successful login
+    pub fn success(&mut self, ip: &str) {
+        self.failures.remove(ip);
+    }
+
+    /// Drop finished windows, old failures and expired lockouts
+    pub fn cleanup(&mut self) {
+        let now = Utc::now().naive_utc();
+        let lockout = self.config.lockout;
+        self.windows
+            .retain(|_, v| v.start + Duration::minutes(1) > now);
+        self.failures.retain(|_, v| {
+            v.locked_until.map(|t| t > now).unwrap_or(false) || v.last + lockout > now
+        });
+    }
+}
+
+/// Request guard that counts the request against the per IP limit and
+/// fails with 429 status when the limit is exceeded or the IP is locked out.
+pub struct RateLimit {
+    pub ip: String,
+    limiter: AuthLimiter,
+}
+
+impl RateLimit {
+    /// Report failed login attempt from the IP
+    pub async fn fail(&self) {
+        self.limiter.lock().await.fail(&self.ip);
+    }
+
+    /// Report successful login from the IP
+    pub async fn success(&self) {
+        self.limiter.lock().await.success(&self.ip);
+    }
+
+    /// Report the result of the auth attempt
+    pub async fn report(&self, res: &AuthResponse) {
+        match res {
+            AuthResponse::Ok => self.success().await,
+            AuthResponse::Error { .. } => self.fail().await,
+        }
+    }
+}
+
+/// Managed state that holds the rate limiter of a request guard
+trait Limiter: Send + Sync + 'static {
+    /// Whether requests are limited when client IPs are not trusted and
+    /// all clients behind a proxy share one limit
+    const LIMIT_SHARED_IP: bool;
+
+    fn limiter(&self) -> &Mutex<RateLimiter>;
+}
+
+impl Limiter for AuthLimiter {
+    const LIMIT_SHARED_IP: bool = true;
+
+    fn limiter(&self) -> &Mutex<RateLimiter> {
+        self
+    }
+}
+
+/// Holder lookups only read public data, one scraper shouldn't make
+/// them fail for every holder behind the proxy
+impl Limiter for HolderLimiter {
+    const LIMIT_SHARED_IP: bool = false;
+
+    fn limiter(&self) -> &Mutex<RateLimiter> {
+        &self.0
+    }
+}
+
+/// Count the request of the client IP with the managed limiter `L`
+async fn check_request<'r, L: Limiter>(
+    req: &'r Request<'_>,
+) -> Outcome<(&'r L, String), LimitError> {
+    let state = match req.rocket().state::<L>() {
+        Some(v) => v,
+        None => return Outcome::Failure((Status::InternalServerError, LimitError::Internal)),
+    };
+    let ip = req
+        .client_ip()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "unknown".to_owned());
+    let res = {
+        let mut limiter = state.limiter().lock().await;
+        if limiter.config.trusted_ip || L::LIMIT_SHARED_IP {
+            limiter.check(&ip)
+        } else {
+            Ok(())
+        }
+    };
+    match res {
+        Ok(_) => Outcome::Success((state, ip)),
+        Err(e) => {
+            warn!(
+                "Rejected request to {} from {}: {}",
+                req.uri().path(),
+                ip,
+                e
+            );
+            req.local_cache(|| Some(e));
+            Outcome::Failure((Status::TooManyRequests, e))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = LimitError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        check_request::<AuthLimiter>(req)
+            .await
+            .map(|(limiter, ip)| RateLimit {
+                ip,
+                limiter: limiter.clone(),
+            })
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for RateLimit {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// Rate limiter of the public holder lookup. It is separate from the auth
+/// one, so lookups of holders can't lock users out of sign in.
+pub struct HolderLimiter(pub Mutex<RateLimiter>);
+
+impl HolderLimiter {
+    pub fn new(config: LimitConfig) -> Self {
+        HolderLimiter(Mutex::new(RateLimiter::new(config)))
+    }
+}
+
+/// Request guard that counts the request against the per IP limit of the
+/// holder lookup and fails with 429 status when the limit is exceeded.
+pub struct HolderLimit {
+    pub ip: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HolderLimit {
+    type Error = LimitError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        check_request::<HolderLimiter>(req)
+            .await
+            .map(|(_, ip)| HolderLimit { ip })
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for HolderLimit {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// Wallets expect LNURL error response even when they are rate limited
+#[catch(429)]
+pub fn too_many_requests(req: &Request) -> Json<AuthResponse> {
+    let reason = req
+        .local_cache(|| None::<LimitError>)
+        .unwrap_or(LimitError::TooManyRequests);
+    Json(AuthResponse::Error {
+        reason: reason.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: u32, max_failures: u32) -> RateLimiter {
+        RateLimiter::new(LimitConfig {
+            requests_per_minute,
+            max_failures,
+            ..LimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn check_limits_requests_per_ip() {
+        let mut limiter = limiter(2, 5);
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Err(LimitError::TooManyRequests));
+        assert_eq!(limiter.check("b"), Ok(()));
+        assert_eq!(limiter.metrics.rate_limited, 1);
+    }
+
+    #[test]
+    fn fail_locks_out_after_max_failures() {
+        let mut limiter = limiter(60, 2);
+        limiter.fail("a");
+        assert_eq!(limiter.check("a"), Ok(()));
+        limiter.fail("a");
+        assert_eq!(limiter.check("a"), Err(LimitError::LockedOut));
+        assert_eq!(limiter.check("b"), Ok(()));
+        assert_eq!(limiter.metrics.failed_attempts, 2);
+        assert_eq!(limiter.metrics.lockouts, 1);
+        assert_eq!(limiter.metrics.locked_out, 1);
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let mut limiter = limiter(60, 2);
+        limiter.fail("a");
+        limiter.success("a");
+        limiter.fail("a");
+        assert_eq!(limiter.check("a"), Ok(()));
+    }
+
+    #[test]
+    fn no_lockout_without_trusted_ip() {
+        let mut limiter = RateLimiter::new(LimitConfig {
+            max_failures: 1,
+            trusted_ip: false,
+            ..LimitConfig::default()
+        });
+        limiter.fail("proxy");
+        limiter.fail("proxy");
+        assert_eq!(limiter.check("proxy"), Ok(()));
+        assert_eq!(limiter.metrics.failed_attempts, 2);
+        assert_eq!(limiter.metrics.lockouts, 0);
+    }
+
+    #[test]
+    fn cleanup_drops_finished_windows_and_lockouts() {
+        let mut limiter = RateLimiter::new(LimitConfig {
+            requests_per_minute: 1,
+            max_failures: 1,
+            lockout: Duration::seconds(-1),
+            ..LimitConfig::default()
+        });
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("a"), Err(LimitError::TooManyRequests));
+        limiter.windows.get_mut("a").unwrap().start -= Duration::minutes(1);
+        limiter.fail("b");
+        limiter.cleanup();
+        assert!(limiter.windows.is_empty());
+        assert!(limiter.failures.is_empty());
+        assert_eq!(limiter.check("a"), Ok(()));
+        assert_eq!(limiter.check("b"), Ok(()));
+    }
+}
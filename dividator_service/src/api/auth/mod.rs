@@ -1,28 +1,32 @@
 pub mod cache;
+pub mod guard;
+pub mod limit;
 pub mod routes;
+pub mod session;
+pub mod stellar;
 pub mod types;
 
-use super::types::*;
-use bech32::{ToBase32, Variant};
-use cache::Cache;
-use futures::future::Future;
-use image::Rgb;
+#[cfg(test)]
+mod tests;
+
+use super::qr::{QrImage, QrOptions};
+use bech32::{FromBase32, ToBase32, Variant};
+use cache::{Cache, SessionInfo};
+use chrono::Utc;
+use dividator::state::{SystemState, TokenId};
+pub use guard::{AuthedChange, AuthedPage, AuthedUser};
 use log::*;
-use qrcode::QrCode;
 use rand::distributions::Uniform;
 use rand::Rng;
-use rocket::fs::NamedFile;
-use rocket::http::CookieJar;
-use rocket::http::Status;
-use rocket::response::Redirect;
-use rocket::uri;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket_okapi::JsonSchema;
+use secp256k1::hashes::{sha256, Hash};
 use secp256k1::{Message, Secp256k1};
 use serde::Serialize;
+use std::collections::HashSet;
 use std::str::FromStr;
-use tempfile::tempdir;
 use thiserror::Error;
-pub use types::{LnAuthAction, Permission};
+pub use types::{ClientInfo, LnAuthAction, Permission};
 
 /// Generate 32 bytes and encode them as hex string for LNURL
 pub fn generate_k1() -> String {
@@ -33,60 +37,78 @@ pub fn generate_k1() -> String {
     hex::encode(&k1)
 }
 
-/// Generate a lnurl for handler with random k1 and login tag
+/// URL of LNURL-auth handler with the k1 and login tag. LUD-04 allows
+/// only `tag`, `k1` and `action` query parameters there.
+///
+/// Wallets derive linking keys from the host of the URL (LUD-05), so the
+/// URL is always built from the configured domain. Otherwise the same
+/// wallet would get different keys depending on how the page is opened.
+pub fn auth_url(domain: &str, k1: &str, action: LnAuthAction) -> String {
+    format!(
+        "{}?tag=login&k1={}&action={}",
+        domain.trim_end_matches('/'),
+        k1,
+        action
+    )
+}
+
+/// Generate a lnurl for handler with random k1 and login tag. The result
+/// is lowercase bech32, uppercase it for denser QR codes if needed.
 pub fn generate_auth_lnurl(domain: &str, k1: &str, action: LnAuthAction) -> Result<String, Status> {
-    let handler = format!("{}?tag=login&k1={}&action={}", domain, k1, action);
+    let handler = auth_url(domain, k1, action);
     bech32::encode("lnurl", handler.as_bytes().to_base32(), Variant::Bech32).map_err(|e| {
         error!("Failed to encode bech32 lnurl: {}", e);
         Status::InternalServerError
     })
 }
 
-/// Generate QR code for LNURL auth to temp folder and return it as rocket responser.
+/// Generate `keyauth://` link (LUD-17) that wallets can open without
+/// bech32 decoding. Resolves to the same handler as the lnurl.
+pub fn generate_keyauth_url(domain: &str, k1: &str, action: LnAuthAction) -> String {
+    let url = auth_url(domain, k1, action);
+    let rest = url.split_once("://").map(|(_, v)| v).unwrap_or(&url);
+    format!("keyauth://{}", rest)
+}
+
+/// Decode lnurl in either lowercase or uppercase into the URL
+pub fn decode_lnurl(lnurl: &str) -> Result<String, LnUrlError> {
+    let lnurl = lnurl.trim();
+    let lnurl = lnurl
+        .strip_prefix("lightning:")
+        .or_else(|| lnurl.strip_prefix("LIGHTNING:"))
+        .unwrap_or(lnurl);
+    let (hrp, data, _) = bech32::decode(lnurl).map_err(LnUrlError::Bech32)?;
+    if hrp != "lnurl" {
+        return Err(LnUrlError::Prefix(hrp));
+    }
+    let bytes = Vec::<u8>::from_base32(&data).map_err(LnUrlError::Bech32)?;
+    String::from_utf8(bytes).map_err(|_| LnUrlError::Utf8)
+}
+
+#[derive(Debug, Error)]
+pub enum LnUrlError {
+    #[error("Invalid bech32 encoding: {0}")]
+    Bech32(bech32::Error),
+    #[error("Unexpected bech32 prefix {0}, expected lnurl")]
+    Prefix(String),
+    #[error("Encoded URL is not UTF-8")]
+    Utf8,
+}
+
+/// Render QR code with LNURL auth in memory.
 ///
 /// # Arguments
 ///
 /// * `domain` - which domain to use in LNURL. Including https. Example: `https://domain.sample`
-/// * `color` - RGB for dark part of the QR code
-/// * `dims` - size of resulted image, width and height.
-pub async fn generate_qrcode(
-    cache: &mut Cache,
+/// * `qr` - format and style of the image that is requested by the browser
+pub fn generate_qrcode(
     domain: &str,
-    color: [u8; 3],
-    dims: (u32, u32),
+    qr: &QrOptions,
     action: LnAuthAction,
     k1: &str,
-) -> Result<NamedFile, Status> {
-    // Encode some data into bits.
+) -> Result<QrImage, Status> {
     let bech_handler = generate_auth_lnurl(domain, k1, action)?;
-
-    let code = QrCode::new(bech_handler).map_err(|e| {
-        error!("Failed to create qrcode: {}", e);
-        Status::InternalServerError
-    })?;
-
-    // Render the bits into an image.
-    let image = code
-        .render::<Rgb<u8>>()
-        .dark_color(Rgb(color))
-        .min_dimensions(dims.0, dims.1)
-        .build();
-
-    // Save to temporary folder
-    let dir = tempdir().map_err(|e| {
-        error!("Failed to create temp dir for qrcode: {}", e);
-        Status::InternalServerError
-    })?;
-    let qrcode_path = dir.path().join("qrcode.png");
-    image.save(&qrcode_path).unwrap();
-    let opened = NamedFile::open(qrcode_path).await.map_err(|e| {
-        error!("Failed to open generated qrcode: {}", e);
-        Status::InternalServerError
-    })?;
-
-    // Store the k1 key in the cache
-    cache.add(&k1);
-    Ok(opened)
+    qr.render(&bech_handler)
 }
 
 /// Response for auth handler
@@ -124,72 +146,129 @@ pub enum SigError {
     InvalidK1(secp256k1::Error),
     #[error("Failed to decode hex public key: {0}")]
     KeyDecode(secp256k1::Error),
+    #[error("Linking key must be compressed 33 bytes public key")]
+    UncompressedKey,
     #[error("Failed to decode DER signature: {0}")]
     SignatureDecode(secp256k1::Error),
     #[error("Signature check failed: {0}")]
     SignatureVerify(secp256k1::Error),
 }
 
-/// Check that signature of k1 and user pubkey is valid and return `true` in that case.
-/// Returns `false` also if the key or signature is in invalid encoding.
+/// Check that DER signature of k1 by the compressed linking key is valid
+/// as LUD-04 requires. Signatures with high S are accepted as some
+/// wallets don't normalize them.
 pub fn check_signature(k1: &str, sig: &str, key: &str) -> Result<(), SigError> {
     let k1_bytes = hex::decode(k1.as_bytes()).map_err(SigError::HexK1)?;
     let message = Message::from_slice(&k1_bytes).map_err(SigError::InvalidK1)?;
     let secp = Secp256k1::new();
+    if key.len() != 66 {
+        return Err(SigError::UncompressedKey);
+    }
     let public_key = secp256k1::PublicKey::from_str(key).map_err(SigError::KeyDecode)?;
-    let signature =
+    let mut signature =
         secp256k1::ecdsa::Signature::from_str(sig).map_err(SigError::SignatureDecode)?;
+    signature.normalize_s();
     secp.verify_ecdsa(&message, &signature, &public_key)
         .map_err(SigError::SignatureVerify)?;
     Ok(())
 }
 
+/// Public identifier of the session that is safe to show in UI
+/// and API as it doesn't reveal the k1 itself.
+pub fn session_id(k1: &str) -> String {
+    sha256::Hash::hash(k1.as_bytes()).to_string()
+}
+
+/// CSRF token of the session that is put into every form. It is derived
+/// from the k1 in the private cookie, so other sites can't forge it, and
+/// differs from `session_id` that is shown to admins.
+pub fn csrf_token(k1: &str) -> String {
+    sha256::Hash::hash(format!("csrf:{}", k1).as_bytes()).to_string()
+}
+
+/// Generate new API token. Returns public id of the token and the
+/// full token that is shown to the user once.
+pub fn generate_token() -> (TokenId, String) {
+    let id: Vec<u8> = rand::thread_rng()
+        .sample_iter(Uniform::new_inclusive(0, u8::MAX))
+        .take(8)
+        .collect();
+    let id = hex::encode(&id);
+    let token = format!("{}.{}", id, generate_k1());
+    (id, token)
+}
+
+/// Hash of the full token that is stored in the state
+pub fn token_hash(token: &str) -> String {
+    sha256::Hash::hash(token.as_bytes()).to_string()
+}
+
+/// Check API token against the state and return session-like info
+/// on behalf of the user that minted the token. The token keeps only
+/// the permissions that the user still has, and stops working when the
+/// user is removed or has none of them left.
+pub fn token_session(state: &SystemState, token: &str) -> Option<SessionInfo> {
+    let (id, _) = token.split_once('.')?;
+    let info = state.tokens.get(id)?;
+    let now = Utc::now().naive_utc();
+    if info.hash != token_hash(token) || info.expires_at < now {
+        return None;
+    }
+    let current = state.permissions(&info.created_by)?;
+    let permissions: HashSet<Permission> = info
+        .permissions
+        .iter()
+        .filter(|v| current.iter().any(|p| p.implies(v)))
+        .cloned()
+        .collect();
+    if permissions.is_empty() {
+        return None;
+    }
+    Some(SessionInfo {
+        key: info.created_by.clone(),
+        timeout: info.expires_at,
+        permissions,
+        created_at: info.created_at,
+        last_activity: now,
+        ip: None,
+        user_agent: None,
+    })
+}
+
 /// Cookie name that contains session id
 pub const AUTH_COOKIE: &str = "session";
 
-/// Helper that allows to wrap any endpoint and gurantee
-/// that user passed the authentification with given permissions.
-pub async fn guard_auth<Fut, T>(
-    db_mutex: &DataBase,
-    cookies: &CookieJar<'_>,
-    cache_mutex: AuthCache,
-    permissions: &[Permission],
-    body: Fut,
-) -> Result<T, Redirect>
-where
-    Fut: Future<Output = T>,
-{
-    let has_admin = {
-        let db = db_mutex.lock().await;
-        let state = db.get().await;
-        state.has_admin()
-    };
-    if has_admin {
-        if let Some(k1) = cookies.get_private(AUTH_COOKIE) {
-            let mut cache = cache_mutex.lock().await;
-            if let Some(session) = cache.has_session(k1.value()) {
-                if session.check_permissions(permissions) {
-                    let pubkey = session.key.clone();
-                    cache.upsert_session(k1.value(), &pubkey, permissions);
-                    Ok(body.await)
-                } else {
-                    warn!(
-                        "User doesn' have required permissions: {:?}, user perms: {:?}",
-                        permissions, session.permissions
-                    );
-                    Err(Redirect::to(uri!("/")))
-                }
-            } else {
-                warn!("User session expired, k1: {}", k1);
-                cookies.remove_private(k1);
-                Err(Redirect::to(uri!(routes::signin)))
-            }
-        } else {
-            info!("User is not logged in, redirect to signin");
-            Err(Redirect::to(uri!(routes::signin)))
-        }
-    } else {
-        info!("System is not fully initialized, redirect to init");
-        Err(Redirect::to(uri!(routes::init)))
-    }
+/// Session cookie that is not sent with cross site requests. It is marked
+/// secure when the service is served over https, which is usually
+/// terminated by the reverse proxy, so Rocket doesn't know it by itself.
+pub fn auth_cookie(domain: &str, k1: String) -> Cookie<'static> {
+    Cookie::build(AUTH_COOKIE, k1)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(domain.starts_with("https://"))
+        .finish()
+}
+
+/// Cookie name that contains k1 of the page that waits for the login
+pub const PENDING_COOKIE: &str = "pending_k1";
+
+/// Binds k1 of the sign in, invite or init page to the browser that
+/// opened it. The k1 is public as it is in the QR code, so only the
+/// browser with this cookie can finish the login.
+pub fn pending_cookie(domain: &str, k1: String) -> Cookie<'static> {
+    Cookie::build(PENDING_COOKIE, k1)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(domain.starts_with("https://"))
+        .finish()
+}
+
+/// Check that the browser opened the page with the k1
+pub fn is_pending(cookies: &CookieJar<'_>, k1: &str) -> bool {
+    cookies
+        .get_private(PENDING_COOKIE)
+        .map(|v| v.value() == k1)
+        .unwrap_or(false)
 }
@@ -1,86 +1,196 @@
+use super::guard::Viewer;
+use super::limit::RateLimit;
+use super::stellar::{url_encode, StellarAuth};
 use super::types::Permission;
 use super::{
-    auth_handler, generate_auth_lnurl, generate_k1, generate_qrcode, AuthResponse, LnAuthAction,
-    AUTH_COOKIE,
+    auth_cookie, auth_handler, check_signature, generate_auth_lnurl, generate_k1,
+    generate_keyauth_url, generate_qrcode, is_pending, pending_cookie, AuthResponse, AuthedPage,
+    LnAuthAction, AUTH_COOKIE, PENDING_COOKIE,
 };
+use crate::api::audit::{AuditKind, AuditLog};
+use crate::api::qr::{QrImage, QrOptions};
 use crate::api::types::*;
 use chrono::prelude::*;
-use dividator::state::admin::AddAdmin;
+use dividator::state::admin::{AddAdmin, RotateAdmin};
+use dividator::state::user::AddUser;
 use dividator::state::SystemUpdate;
 use dividator::state::K1;
 use log::*;
-use rocket::fs::NamedFile;
-use rocket::get;
+use rocket::form::{Form, FromForm};
+use rocket::http::CookieJar;
 use rocket::http::Status;
-use rocket::http::{Cookie, CookieJar};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::uri;
 use rocket::State;
+use rocket::{get, post};
 use rocket_dyn_templates::Template;
 use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::Duration;
 use tokio::sync::broadcast;
-use tokio::time::timeout;
 
 /// <LNURL_hostname_and_path>?<LNURL_existing_query_parameters>&sig=<hex(sign(hexToBytes(k1), linkingPrivKey))>&key=<hex(linkingKey)>
+///
+/// Every request with `tag=login` gets LUD-04 JSON response, even when
+/// it is opened without the signature. Missing `action` means login.
 #[openapi(tag = "auth")]
 #[get("/?tag=login&<k1>&<action>&<sig>&<key>")]
 pub async fn index_handler(
     cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
     db_mutex: &State<DataBase>,
     k1_sender: &State<broadcast::Sender<K1>>,
-    action: LnAuthAction,
-    k1: String,
-    sig: String,
-    key: String,
+    audit_log: &State<AuditLog>,
+    limit: RateLimit,
+    action: Option<LnAuthAction>,
+    k1: Option<String>,
+    sig: Option<String>,
+    key: Option<String>,
 ) -> Json<AuthResponse> {
-    match action {
-        LnAuthAction::Login => handler_signin(cache_mutex, db_mutex, k1_sender, k1, sig, key).await,
+    let (k1, sig, key) = match (k1, sig, key) {
+        (Some(k1), Some(sig), Some(key)) => (k1.to_lowercase(), sig, key.to_lowercase()),
+        (None, _, _) => {
+            return Json(AuthResponse::Error {
+                reason: "Missing k1 parameter".to_owned(),
+            })
+        }
+        _ => {
+            return Json(AuthResponse::Error {
+                reason: "Missing sig or key, open the link with LNURL-auth wallet".to_owned(),
+            })
+        }
+    };
+    let action = action.unwrap_or(LnAuthAction::Login);
+    let (rotation, new_key) = match cache_mutex.lock().await.has_rotation(&k1) {
+        Some(v) => (true, v.new_key.as_ref().map(|(k, _)| k.clone())),
+        None => (false, None),
+    };
+    let res = match action {
+        LnAuthAction::Login => {
+            handler_signin(
+                cache_mutex,
+                sessions,
+                db_mutex,
+                k1_sender,
+                k1,
+                sig,
+                key.clone(),
+            )
+            .await
+        }
+        LnAuthAction::Register if rotation => {
+            handler_rotate_new(cache_mutex, db_mutex, k1_sender, k1, sig, key.clone()).await
+        }
+        LnAuthAction::Auth if rotation => {
+            handler_rotate_confirm(
+                cache_mutex,
+                sessions,
+                db_mutex,
+                k1_sender,
+                k1,
+                sig,
+                key.clone(),
+            )
+            .await
+        }
         LnAuthAction::Register => {
-            handler_register(cache_mutex, db_mutex, k1_sender, k1, sig, key).await
+            handler_register(
+                cache_mutex,
+                sessions,
+                db_mutex,
+                k1_sender,
+                k1,
+                sig,
+                key.clone(),
+            )
+            .await
+        }
+        LnAuthAction::Link => {
+            handler_link(
+                cache_mutex,
+                sessions,
+                db_mutex,
+                k1_sender,
+                k1,
+                sig,
+                key.clone(),
+            )
+            .await
         }
         _ => Json(AuthResponse::Error {
             reason: "Unexpected action tag".to_owned(),
         }),
+    };
+    limit.report(&res).await;
+    let event = match (&res.0, action) {
+        (AuthResponse::Error { reason }, _) => Some((
+            AuditKind::LoginFailed,
+            Some(format!("{}: {}", action, reason)),
+        )),
+        (AuthResponse::Ok, LnAuthAction::Login) => Some((AuditKind::LoginSucceeded, None)),
+        (AuthResponse::Ok, LnAuthAction::Auth) if rotation => Some((
+            AuditKind::AdminRotated,
+            new_key.map(|v| format!("new key {}", v)),
+        )),
+        // New admin wallet only scanned the code, the rotation is recorded on confirmation
+        (AuthResponse::Ok, LnAuthAction::Register) if rotation => None,
+        (AuthResponse::Ok, LnAuthAction::Register) => Some((AuditKind::AdminRegistered, None)),
+        (AuthResponse::Ok, LnAuthAction::Link) => Some((AuditKind::UserLinked, None)),
+        (AuthResponse::Ok, _) => None,
+    };
+    if let Some((kind, details)) = event {
+        audit_log
+            .record(kind, Some(&key), Some(&limit.ip), details.as_deref())
+            .await;
+    }
+    res
+}
+
+/// Wake up browsers that are waiting for the k1 to be signed
+async fn notify_k1(k1_sender: &broadcast::Sender<K1>, k1: String) {
+    if let Err(_) = k1_sender.send(k1.clone()) {
+        error!("Failed to notify k1 listeners!");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if let Err(_) = k1_sender.send(k1) {
+            error!("Failed to notify k1 listeners x2!");
+        }
     }
 }
 
 async fn handler_signin(
     cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
     db_mutex: &State<DataBase>,
     k1_sender: &State<broadcast::Sender<K1>>,
     k1: String,
     sig: String,
     key: String,
 ) -> Json<AuthResponse> {
-    let mut cache = cache_mutex.lock().await;
     let db = db_mutex.lock().await;
-    let res = auth_handler(&mut cache, &k1, &sig, &key).await;
+    let res = auth_handler(&mut *cache_mutex.lock().await, &k1, &sig, &key).await;
     match &res {
         AuthResponse::Ok => {
-            if let Some(akey) = db.get().await.admin_key() {
-                if akey == key {
-                    info!("Admin logged in!");
-                    cache.upsert_session(&k1, &key, &vec![Permission::Admin]);
-                    if let Err(_) = k1_sender.send(k1.clone()) {
-                        error!("Failed to notify k1 listeners!");
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        if let Err(_) = k1_sender.send(k1) {
-                            error!("Failed to notify k1 listeners x2!");
-                        }
-                    }
-                    Json(AuthResponse::Ok)
-                } else {
-                    Json(AuthResponse::Error {
-                        reason: "User is not known".to_owned(),
-                    })
-                }
-            } else {
+            let state = db.get().await;
+            if !state.has_admin() {
                 Json(AuthResponse::Error {
                     reason: "Admin is not defined".to_owned(),
                 })
+            } else if let Some(permissions) = state.permissions(&key) {
+                let permissions: Vec<Permission> = permissions.into_iter().collect();
+                info!("User {} logged in with {:?}", key, permissions);
+                // The page k1 is public, the browser picks the session once
+                let session_k1 = generate_k1();
+                sessions.upsert(&session_k1, &key, &permissions, None).await;
+                cache_mutex.lock().await.add_signed(&k1, &session_k1);
+                notify_k1(k1_sender, k1).await;
+                Json(AuthResponse::Ok)
+            } else {
+                Json(AuthResponse::Error {
+                    reason: "User is not known".to_owned(),
+                })
             }
         }
         AuthResponse::Error { reason } => {
@@ -92,6 +202,7 @@ async fn handler_signin(
 
 async fn handler_register(
     cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
     db_mutex: &State<DataBase>,
     k1_sender: &State<broadcast::Sender<K1>>,
     k1: String,
@@ -120,14 +231,12 @@ async fn handler_register(
                 match dbres {
                     Ok(_) => {
                         info!("Admin finalized");
-                        cache.upsert_session(&k1, &key, &vec![Permission::Admin]);
-                        if let Err(_) = k1_sender.send(k1.clone()) {
-                            error!("Failed to notify k1 listeners!");
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            if let Err(_) = k1_sender.send(k1) {
-                                error!("Failed to notify k1 listeners x2!");
-                            }
-                        }
+                        let session_k1 = generate_k1();
+                        sessions
+                            .upsert(&session_k1, &key, &[Permission::Admin], None)
+                            .await;
+                        cache.add_signed(&k1, &session_k1);
+                        notify_k1(k1_sender, k1).await;
                         Json(res)
                     }
                     Err(e) => {
@@ -146,11 +255,419 @@ async fn handler_register(
     }
 }
 
+/// New wallet scanned the rotation code. Remember its key until
+/// the current admin confirms the rotation.
+async fn handler_rotate_new(
+    cache_mutex: &State<AuthCache>,
+    db_mutex: &State<DataBase>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    k1: String,
+    sig: String,
+    key: String,
+) -> Json<AuthResponse> {
+    let db = db_mutex.lock().await;
+    let mut cache = cache_mutex.lock().await;
+    if let Err(e) = check_signature(&k1, &sig, &key) {
+        warn!("New admin wallet failed to sign rotation: {}", e);
+        return Json(AuthResponse::Error {
+            reason: format!("{}", e),
+        });
+    }
+    let known = db.get().await.permissions(&key).is_some();
+    let res = match cache.has_rotation(&k1) {
+        Some(_) if known => Err("The wallet is already linked, use another one"),
+        Some(rotation) if rotation.new_key.is_none() => {
+            rotation.new_key = Some((key.clone(), sig));
+            Ok(())
+        }
+        Some(_) => Err("New wallet is already scanned for the rotation"),
+        None => Err("Outdated or unknown k1. Please, start rotation once more time."),
+    };
+    match res {
+        Ok(_) => {
+            info!("New admin wallet {} scanned rotation code", key);
+            notify_k1(k1_sender, k1).await;
+            Json(AuthResponse::Ok)
+        }
+        Err(reason) => {
+            warn!("New admin wallet {} rejected: {}", key, reason);
+            Json(AuthResponse::Error {
+                reason: reason.to_owned(),
+            })
+        }
+    }
+}
+
+/// Current admin confirms the rotation with the same k1
+async fn handler_rotate_confirm(
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    db_mutex: &State<DataBase>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    k1: String,
+    sig: String,
+    key: String,
+) -> Json<AuthResponse> {
+    let mut db = db_mutex.lock().await;
+    let mut cache = cache_mutex.lock().await;
+    let rotation = match cache.has_rotation(&k1) {
+        Some(v) => v.clone(),
+        None => {
+            return Json(AuthResponse::Error {
+                reason: "Outdated or unknown k1. Please, start rotation once more time.".to_owned(),
+            })
+        }
+    };
+    let (new_key, new_signature) = match rotation.new_key {
+        Some(v) => v,
+        None => {
+            return Json(AuthResponse::Error {
+                reason: "Scan the code with the new wallet first".to_owned(),
+            })
+        }
+    };
+    if key != rotation.old_key {
+        warn!("Rotation confirmation from unexpected key {}", key);
+        return Json(AuthResponse::Error {
+            reason: "Rotation must be confirmed by the current admin wallet".to_owned(),
+        });
+    }
+    if let Err(e) = check_signature(&k1, &sig, &key) {
+        warn!("Admin failed to confirm rotation: {}", e);
+        return Json(AuthResponse::Error {
+            reason: format!("{}", e),
+        });
+    }
+    let dbres = db
+        .update(SystemUpdate::RotateAdmin(RotateAdmin {
+            old_key: key.clone(),
+            new_key: new_key.clone(),
+            k1: k1.clone(),
+            old_signature: sig,
+            new_signature,
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await;
+    match dbres {
+        Ok(_) => {
+            info!("Admin key rotated from {} to {}", key, new_key);
+            cache.remove_rotation(&k1);
+            sessions.remove_user(&key).await;
+            notify_k1(k1_sender, k1).await;
+            Json(AuthResponse::Ok)
+        }
+        Err(e) => {
+            error!("Failed to rotate admin key: {}", e);
+            Json(AuthResponse::Error {
+                reason: format!("Failed to rotate admin key: {}", e),
+            })
+        }
+    }
+}
+
+async fn handler_link(
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    db_mutex: &State<DataBase>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    k1: String,
+    sig: String,
+    key: String,
+) -> Json<AuthResponse> {
+    let mut db = db_mutex.lock().await;
+    let mut cache = cache_mutex.lock().await;
+    let invite = match cache.has_invite(&k1) {
+        Some(v) => v.clone(),
+        None => {
+            return Json(AuthResponse::Error {
+                reason: "Outdated or unknown invite. Please, ask for a new one.".to_owned(),
+            })
+        }
+    };
+    if let Err(e) = check_signature(&k1, &sig, &key) {
+        warn!("User failed to accept invite: {}", e);
+        return Json(AuthResponse::Error {
+            reason: format!("{}", e),
+        });
+    }
+    let dbres = db
+        .update(SystemUpdate::AddUser(AddUser {
+            key: key.clone(),
+            permissions: invite.permissions.iter().cloned().collect(),
+            k1: k1.clone(),
+            signature: sig,
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await;
+    match dbres {
+        Ok(_) => {
+            info!("User {} linked with {:?}", key, invite.permissions);
+            cache.remove_invite(&k1);
+            // The invite k1 is in the link that is sent to the user
+            let session_k1 = generate_k1();
+            sessions
+                .upsert(&session_k1, &key, &invite.permissions, None)
+                .await;
+            cache.add_signed(&k1, &session_k1);
+            notify_k1(k1_sender, k1).await;
+            Json(AuthResponse::Ok)
+        }
+        Err(e) => {
+            warn!("Failed to link user {}: {}", key, e);
+            Json(AuthResponse::Error {
+                reason: format!("Failed to link wallet: {}", e),
+            })
+        }
+    }
+}
+
+/// SEP-10 challenge for Stellar wallet
+#[derive(Serialize, JsonSchema)]
+pub struct StellarChallenge {
+    /// Base64 encoded transaction envelope to sign
+    pub transaction: String,
+    /// Passphrase of the network the transaction is built for
+    pub network_passphrase: String,
+    /// SEP-7 link to sign the transaction in a mobile wallet
+    pub sep7: String,
+}
+
+/// Build SEP-10 challenge for the account. The `k1` is the nonce of the
+/// sign in or invite page that waits for the login.
+#[openapi(tag = "auth")]
+#[get("/auth/stellar?<account>&<k1>")]
+pub async fn stellar_challenge(
+    _limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    stellar: &State<Option<StellarAuth>>,
+    domain: &State<String>,
+    account: String,
+    k1: String,
+) -> Result<Json<StellarChallenge>, Status> {
+    let stellar = stellar.as_ref().ok_or(Status::NotFound)?;
+    let mut cache = cache_mutex.lock().await;
+    if !cache.has_key(&k1) && cache.has_invite(&k1).is_none() {
+        warn!("Stellar challenge for unknown k1");
+        return Err(Status::BadRequest);
+    }
+    let challenge = stellar.challenge(&account).map_err(|e| {
+        warn!("Failed to build Stellar challenge: {}", e);
+        Status::BadRequest
+    })?;
+    cache.add_challenge(&k1, &account, challenge.tx);
+    let callback = format!("url:{}/auth/stellar/callback?k1={}", domain.as_str(), k1);
+    let sep7 = format!(
+        "web+stellar:tx?xdr={}&callback={}&network_passphrase={}",
+        url_encode(&challenge.envelope),
+        url_encode(&callback),
+        url_encode(&stellar.network_passphrase),
+    );
+    Ok(Json(StellarChallenge {
+        transaction: challenge.envelope,
+        network_passphrase: stellar.network_passphrase.clone(),
+        sep7,
+    }))
+}
+
+/// Check the signed SEP-10 challenge and create session for the k1.
+/// If the k1 belongs to an invite, the account is linked as a new user.
+/// Returns the account, the kind of the finished action and k1 of
+/// the created session, which is also left for `signin_finish`.
+async fn finish_stellar_auth(
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    db_mutex: &State<DataBase>,
+    stellar: &StellarAuth,
+    k1: &str,
+    transaction: &str,
+) -> Result<(String, AuditKind, K1), String> {
+    let mut db = db_mutex.lock().await;
+    let mut cache = cache_mutex.lock().await;
+    let challenge = cache.pick_challenge(k1).ok_or_else(|| {
+        "Outdated or unknown challenge. Please, request it once more time.".to_owned()
+    })?;
+    stellar
+        .verify(&challenge.tx, &challenge.account, transaction)
+        .map_err(|e| format!("{}", e))?;
+    let account = challenge.account;
+    let (kind, permissions) = if let Some(invite) = cache.has_invite(k1).cloned() {
+        db.update(SystemUpdate::AddUser(AddUser {
+            key: account.clone(),
+            permissions: invite.permissions.iter().cloned().collect(),
+            k1: k1.to_owned(),
+            signature: transaction.to_owned(),
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await
+        .map_err(|e| format!("Failed to link wallet: {}", e))?;
+        info!("User {} linked with {:?}", account, invite.permissions);
+        cache.remove_invite(k1);
+        (AuditKind::UserLinked, invite.permissions)
+    } else {
+        if !cache.pick(k1) {
+            return Err("Outdated or unknown k1. Please, reload the page.".to_owned());
+        }
+        let state = db.get().await;
+        let permissions: Vec<Permission> = state
+            .permissions(&account)
+            .ok_or_else(|| "User is not known".to_owned())?
+            .into_iter()
+            .collect();
+        info!("User {} logged in with {:?}", account, permissions);
+        (AuditKind::LoginSucceeded, permissions)
+    };
+    // Pending k1s and other requests shouldn't wait for the session store
+    drop(cache);
+    drop(db);
+    let session_k1 = generate_k1();
+    sessions
+        .upsert(&session_k1, &account, &permissions, None)
+        .await;
+    cache_mutex.lock().await.add_signed(k1, &session_k1);
+    Ok((account, kind, session_k1))
+}
+
+/// Signed SEP-10 challenge from the browser
+#[derive(Deserialize, JsonSchema)]
+pub struct StellarSignedChallenge {
+    /// Base64 encoded transaction envelope signed by the account
+    pub transaction: String,
+    /// Nonce of the page that requested the challenge
+    pub k1: String,
+}
+
+/// Finish SEP-10 login from the browser, sets the session cookie
+#[openapi(tag = "auth")]
+#[post("/auth/stellar", data = "<body>")]
+pub async fn stellar_auth(
+    limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    db_mutex: &State<DataBase>,
+    stellar: &State<Option<StellarAuth>>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    audit_log: &State<AuditLog>,
+    domain: &State<String>,
+    cookies: &CookieJar<'_>,
+    body: Json<StellarSignedChallenge>,
+) -> Json<AuthResponse> {
+    let stellar = match stellar.as_ref() {
+        Some(v) => v,
+        None => {
+            return Json(AuthResponse::Error {
+                reason: "Stellar login is disabled".to_owned(),
+            })
+        }
+    };
+    let body = body.into_inner();
+    if !is_pending(cookies, &body.k1) {
+        warn!("Stellar login from a browser that didn't open the page");
+        limit.fail().await;
+        return Json(AuthResponse::Error {
+            reason: "Open the sign in page in this browser once more time".to_owned(),
+        });
+    }
+    let res = finish_stellar_auth(
+        cache_mutex,
+        sessions,
+        db_mutex,
+        stellar,
+        &body.k1,
+        &body.transaction,
+    )
+    .await;
+    match res {
+        Ok((account, kind, session_k1)) => {
+            limit.success().await;
+            audit_log
+                .record(kind, Some(&account), Some(&limit.ip), Some("stellar"))
+                .await;
+            // The browser gets the session right away, not by signin_finish
+            cache_mutex.lock().await.pick_signed(&body.k1);
+            if let Some(v) = cookies.get_private(PENDING_COOKIE) {
+                cookies.remove_private(v);
+            }
+            cookies.add_private(auth_cookie(domain, session_k1));
+            notify_k1(k1_sender, body.k1).await;
+            Json(AuthResponse::Ok)
+        }
+        Err(reason) => {
+            warn!("User failed to login with Stellar: {}", reason);
+            limit.fail().await;
+            let details = format!("stellar: {}", reason);
+            audit_log
+                .record(
+                    AuditKind::LoginFailed,
+                    None,
+                    Some(&limit.ip),
+                    Some(&details),
+                )
+                .await;
+            Json(AuthResponse::Error { reason })
+        }
+    }
+}
+
+/// Signed transaction that SEP-7 wallet posts to the callback
+#[derive(FromForm)]
+pub struct Sep7Callback {
+    xdr: String,
+}
+
+#[openapi(skip)]
+#[post("/auth/stellar/callback?<k1>", data = "<form>")]
+pub async fn stellar_callback(
+    limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    db_mutex: &State<DataBase>,
+    stellar: &State<Option<StellarAuth>>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    audit_log: &State<AuditLog>,
+    k1: String,
+    form: Form<Sep7Callback>,
+) -> Json<AuthResponse> {
+    let stellar = match stellar.as_ref() {
+        Some(v) => v,
+        None => {
+            return Json(AuthResponse::Error {
+                reason: "Stellar login is disabled".to_owned(),
+            })
+        }
+    };
+    match finish_stellar_auth(cache_mutex, sessions, db_mutex, stellar, &k1, &form.xdr).await {
+        Ok((account, kind, _)) => {
+            limit.success().await;
+            audit_log
+                .record(kind, Some(&account), Some(&limit.ip), Some("stellar"))
+                .await;
+            notify_k1(k1_sender, k1).await;
+            Json(AuthResponse::Ok)
+        }
+        Err(reason) => {
+            warn!("User failed to login with SEP-7: {}", reason);
+            limit.fail().await;
+            let details = format!("stellar: {}", reason);
+            audit_log
+                .record(
+                    AuditKind::LoginFailed,
+                    None,
+                    Some(&limit.ip),
+                    Some(&details),
+                )
+                .await;
+            Json(AuthResponse::Error { reason })
+        }
+    }
+}
+
 #[openapi(skip)]
 #[get("/init")]
 pub async fn init(
+    _limit: RateLimit,
     db_mutex: &State<DataBase>,
     cache_mutex: &State<AuthCache>,
+    cookies: &CookieJar<'_>,
     domain: &State<String>,
 ) -> Result<Template, Result<Redirect, Status>> {
     let db = db_mutex.lock().await;
@@ -159,45 +676,89 @@ pub async fn init(
     } else {
         let k1 = generate_k1();
         let lnurl = generate_auth_lnurl(domain, &k1, LnAuthAction::Register).map_err(Err)?;
+        let keyauth = generate_keyauth_url(domain, &k1, LnAuthAction::Register);
         let mut cache = cache_mutex.lock().await;
         cache.add(&k1);
+        cookies.add_private(pending_cookie(domain, k1.clone()));
         let context = HashMap::from([
             ("title", "Bind admin wallet"),
             ("parent", "base"),
             ("k1", &k1),
             ("lnurl", &lnurl),
+            ("keyauth", &keyauth),
         ]);
         Ok(Template::render("init", context))
     }
 }
 
-#[openapi(tag = "events")]
-#[get("/signin/poll?<k1>")]
-pub async fn signin_poll(
+#[openapi(skip)]
+#[get("/invite?<k1>")]
+pub async fn invite(
+    _limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    cookies: &CookieJar<'_>,
+    domain: &State<String>,
+    stellar: &State<Option<StellarAuth>>,
+    k1: String,
+) -> Result<Template, Status> {
+    let mut cache = cache_mutex.lock().await;
+    if cache.has_invite(&k1).is_some() {
+        let lnurl = generate_auth_lnurl(domain, &k1, LnAuthAction::Link)?;
+        let keyauth = generate_keyauth_url(domain, &k1, LnAuthAction::Link);
+        cookies.add_private(pending_cookie(domain, k1.clone()));
+        let mut context = HashMap::from([
+            ("title", "Accept invite"),
+            ("parent", "base"),
+            ("k1", &k1),
+            ("lnurl", &lnurl),
+            ("keyauth", &keyauth),
+        ]);
+        if stellar.is_some() {
+            context.insert("stellar", "true");
+        }
+        Ok(Template::render("invite", context))
+    } else {
+        warn!("Unknown or expired invite is opened");
+        Err(Status::NotFound)
+    }
+}
+
+/// Set the session cookie once the k1 is signed. The page is opened
+/// by the browser when it gets `signed` event from `/events/k1`. Only
+/// the browser that opened the page can finish, and only once.
+#[openapi(skip)]
+#[get("/signin/finish?<k1>")]
+pub async fn signin_finish(
+    limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+    domain: &State<String>,
     cookies: &CookieJar<'_>,
-    k1_sender: &State<broadcast::Sender<K1>>,
     k1: String,
 ) -> Redirect {
-    trace!("Awaiting signin finalization");
-    let polling_timeout = Duration::from_secs(300);
-    let mut k1_receiver = k1_sender.subscribe();
-    loop {
-        match timeout(polling_timeout, k1_receiver.recv()).await {
-            Ok(Ok(k1_received)) if k1_received == k1 => {
-                trace!("Redirect client to index");
-                cookies.add_private(Cookie::new(AUTH_COOKIE, k1));
-                return Redirect::to(uri!("/"));
-            }
-            Ok(Ok(_)) => {
-                trace!("Not ours k1");
-            }
-            Ok(Err(_)) => {
-                error!("We lagged behind high frequently updated k1!");
-            }
-            Err(_) => {
-                trace!("No new events but releasing long poll");
-                return Redirect::to(uri!(signin));
-            }
+    if !is_pending(cookies, &k1) {
+        warn!("Browser tried to finish sign in of a page it didn't open");
+        limit.fail().await;
+        return Redirect::to(uri!(signin));
+    }
+    if let Some(v) = cookies.get_private(PENDING_COOKIE) {
+        cookies.remove_private(v);
+    }
+    let session_k1 = cache_mutex.lock().await.pick_signed(&k1);
+    let session = match &session_k1 {
+        Some(v) => sessions.get(v).await,
+        None => None,
+    };
+    match (session_k1, session) {
+        (Some(session_k1), Some(_)) => {
+            trace!("Redirect client to index");
+            cookies.add_private(auth_cookie(domain, session_k1));
+            Redirect::to(uri!("/"))
+        }
+        _ => {
+            warn!("Browser tried to finish sign in with unknown k1");
+            limit.fail().await;
+            Redirect::to(uri!(signin))
         }
     }
 }
@@ -205,55 +766,75 @@ pub async fn signin_poll(
 #[openapi(skip)]
 #[get("/signin")]
 pub async fn signin(
+    _limit: RateLimit,
     cache_mutex: &State<AuthCache>,
     cookies: &CookieJar<'_>,
     domain: &State<String>,
+    stellar: &State<Option<StellarAuth>>,
 ) -> Result<Template, Result<Redirect, Status>> {
     if let Some(_) = cookies.get_private(AUTH_COOKIE) {
         Err(Ok(Redirect::to(uri!("/"))))
     } else {
         let k1 = generate_k1();
         let lnurl = generate_auth_lnurl(domain, &k1, LnAuthAction::Login).map_err(Err)?;
+        let keyauth = generate_keyauth_url(domain, &k1, LnAuthAction::Login);
         let mut cache = cache_mutex.lock().await;
         cache.add(&k1);
-        let context = HashMap::from([
+        cookies.add_private(pending_cookie(domain, k1.clone()));
+        let mut context = HashMap::from([
             ("title", "Sign in"),
             ("parent", "base"),
             ("k1", &k1),
             ("lnurl", &lnurl),
+            ("keyauth", &keyauth),
         ]);
+        if stellar.is_some() {
+            context.insert("stellar", "true");
+        }
         Ok(Template::render("signin", context))
     }
 }
 
+#[derive(FromForm)]
+pub struct SignoutForm {
+    csrf: String,
+}
+
+/// Drop the session of the browser. Expired sessions never get here,
+/// the guard removes their cookie and redirects to sign in.
 #[openapi(skip)]
-#[get("/signout")]
-pub async fn signout(cookies: &CookieJar<'_>) -> Redirect {
+#[post("/signout", data = "<form>")]
+pub async fn signout(
+    sessions: &State<AuthSessions>,
+    cookies: &CookieJar<'_>,
+    user: AuthedPage<Viewer>,
+    form: Form<SignoutForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    if let Some(k1) = &user.k1 {
+        sessions.remove(k1).await;
+    }
     if let Some(v) = cookies.get_private(AUTH_COOKIE) {
         cookies.remove_private(v);
     }
-    Redirect::to(uri!(signin))
+    Ok(Redirect::to(uri!(signin)))
 }
 
 #[openapi(skip)]
 #[get("/qrcode/signin?<k1>")]
 pub async fn get_qrcode_endpoint(
+    _limit: RateLimit,
     domain: &State<String>,
     cache_mutex: &State<AuthCache>,
+    qr: QrOptions,
     k1: String,
-) -> Result<NamedFile, Status> {
-    let color = [68, 32, 77];
+) -> Result<QrImage, Status> {
     let mut cache = cache_mutex.lock().await;
-    if cache.pick(&k1) {
-        generate_qrcode(
-            &mut cache,
-            &domain,
-            color,
-            (300, 300),
-            LnAuthAction::Login,
-            &k1,
-        )
-        .await
+    // The k1 stays pending with its timeout, refreshing the image
+    // doesn't prolong it
+    if cache.has_key(&k1) {
+        let qrcode = generate_qrcode(domain, &qr, LnAuthAction::Login, &k1)?;
+        Ok(qrcode)
     } else {
         error!("Unknown k1 value from client!");
         Err(Status::BadRequest)
@@ -263,24 +844,58 @@ pub async fn get_qrcode_endpoint(
 #[openapi(skip)]
 #[get("/qrcode/admin?<k1>")]
 pub async fn get_qrcode_admin_endpoint(
+    _limit: RateLimit,
     domain: &State<String>,
     cache_mutex: &State<AuthCache>,
+    qr: QrOptions,
     k1: String,
-) -> Result<NamedFile, Status> {
-    let color = [68, 32, 77];
+) -> Result<QrImage, Status> {
     let mut cache = cache_mutex.lock().await;
-    if cache.pick(&k1) {
-        generate_qrcode(
-            &mut cache,
-            &domain,
-            color,
-            (300, 300),
-            LnAuthAction::Register,
-            &k1,
-        )
-        .await
+    // The k1 stays pending with its timeout, refreshing the image
+    // doesn't prolong it
+    if cache.has_key(&k1) {
+        let qrcode = generate_qrcode(domain, &qr, LnAuthAction::Register, &k1)?;
+        Ok(qrcode)
     } else {
         error!("Unknown k1 value from client!");
         Err(Status::BadRequest)
     }
 }
+
+#[openapi(skip)]
+#[get("/qrcode/invite?<k1>")]
+pub async fn get_qrcode_invite_endpoint(
+    _limit: RateLimit,
+    domain: &State<String>,
+    cache_mutex: &State<AuthCache>,
+    qr: QrOptions,
+    k1: String,
+) -> Result<QrImage, Status> {
+    let mut cache = cache_mutex.lock().await;
+    if cache.has_invite(&k1).is_some() {
+        generate_qrcode(domain, &qr, LnAuthAction::Link, &k1)
+    } else {
+        error!("Unknown invite k1 value from client!");
+        Err(Status::BadRequest)
+    }
+}
+
+#[openapi(skip)]
+#[get("/qrcode/rotate?<k1>")]
+pub async fn get_qrcode_rotate_endpoint(
+    _limit: RateLimit,
+    domain: &State<String>,
+    cache_mutex: &State<AuthCache>,
+    qr: QrOptions,
+    k1: String,
+) -> Result<QrImage, Status> {
+    let mut cache = cache_mutex.lock().await;
+    match cache.has_rotation(&k1) {
+        Some(v) if v.new_key.is_none() => generate_qrcode(domain, &qr, LnAuthAction::Register, &k1),
+        Some(_) => generate_qrcode(domain, &qr, LnAuthAction::Auth, &k1),
+        None => {
+            error!("Unknown rotation k1 value from client!");
+            Err(Status::BadRequest)
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use super::cache::SessionInfo;
+use super::session_id;
+use super::types::{ClientInfo, Permission};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use chrono::Duration;
+use clap::ValueEnum;
+use dividator::db::Pool;
+use log::*;
+use sqlx::types::Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Public identifier of the session, see `session_id`
+pub type SessionId = String;
+
+/// How often the last activity of the session is written to the store
+pub const TOUCH_INTERVAL: i64 = 60;
+
+/// Which storage to use for sessions of logged users
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SessionStoreKind {
+    /// Sessions are lost on restart and not shared between instances
+    Memory,
+    /// Sessions are kept in the database
+    Postgres,
+}
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Sessions of logged users. They are kept outside of the auth cache
+/// mutex, so a slow store doesn't block the checks of pending k1s.
+///
+/// The k1 from the cookie is never stored, sessions are keyed by
+/// `session_id` of it.
+pub struct Sessions {
+    /// Amount of time session persists without activity
+    pub timeout: Duration,
+    /// The session is prolonged in the store not more often than that
+    pub touch_interval: Duration,
+    store: Box<dyn SessionStore>,
+}
+
+impl Sessions {
+    /// 30 minutes default timeout for sessions
+    pub fn new(store: Box<dyn SessionStore>) -> Self {
+        Sessions {
+            timeout: Duration::minutes(30),
+            touch_interval: Duration::seconds(TOUCH_INTERVAL),
+            store,
+        }
+    }
+
+    /// Find active session by k1 from the cookie
+    pub async fn get(&self, k1: &str) -> Option<SessionInfo> {
+        let now = Utc::now().naive_utc();
+        match self.store.get(&session_id(k1)).await {
+            Ok(v) => v.filter(|v| v.timeout >= now),
+            Err(e) => {
+                error!("Failed to read session: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Create session or update existing one. Prolongs the session
+    /// and replaces its permissions with the given ones. Client info
+    /// is updated if provided, login time is preserved.
+    pub async fn upsert(
+        &self,
+        k1: &str,
+        pub_key: &str,
+        permissions: &[Permission],
+        client: Option<&ClientInfo>,
+    ) {
+        let existing = self.get(k1).await;
+        self.write(&session_id(k1), existing, pub_key, permissions, client)
+            .await;
+    }
+
+    /// Prolong the session that is used by the request. The store is
+    /// written only when something is changed or the last write is older
+    /// than `touch_interval`.
+    pub async fn touch(
+        &self,
+        k1: &str,
+        session: &SessionInfo,
+        permissions: &[Permission],
+        client: &ClientInfo,
+    ) {
+        let now = Utc::now().naive_utc();
+        let changed = session.permissions.len() != permissions.len()
+            || permissions.iter().any(|p| !session.permissions.contains(p))
+            || (client.ip.is_some() && client.ip != session.ip)
+            || (client.user_agent.is_some() && client.user_agent != session.user_agent);
+        if changed || session.last_activity + self.touch_interval <= now {
+            let existing = Some(session.clone());
+            self.write(
+                &session_id(k1),
+                existing,
+                &session.key,
+                permissions,
+                Some(client),
+            )
+            .await;
+        }
+    }
+
+    /// Replace permissions of the session found by its id
+    pub async fn set_permissions(
+        &self,
+        id: &str,
+        session: SessionInfo,
+        permissions: &[Permission],
+    ) {
+        let key = session.key.clone();
+        self.write(id, Some(session), &key, permissions, None).await;
+    }
+
+    async fn write(
+        &self,
+        id: &str,
+        existing: Option<SessionInfo>,
+        pub_key: &str,
+        permissions: &[Permission],
+        client: Option<&ClientInfo>,
+    ) {
+        let now = Utc::now().naive_utc();
+        let session = SessionInfo {
+            key: pub_key.to_owned(),
+            timeout: now + self.timeout,
+            permissions: permissions.iter().cloned().collect(),
+            created_at: existing.as_ref().map(|v| v.created_at).unwrap_or(now),
+            last_activity: now,
+            ip: client
+                .and_then(|c| c.ip.clone())
+                .or_else(|| existing.as_ref().and_then(|v| v.ip.clone())),
+            user_agent: client
+                .and_then(|c| c.user_agent.clone())
+                .or_else(|| existing.as_ref().and_then(|v| v.user_agent.clone())),
+        };
+        if let Err(e) = self.store.insert(id, session).await {
+            error!("Failed to store session: {}", e);
+        }
+    }
+
+    /// List active sessions of the user or all sessions if the key is `None`
+    pub async fn list(&self, key: Option<&str>) -> Vec<(SessionId, SessionInfo)> {
+        let now = Utc::now().naive_utc();
+        match self.store.list(key).await {
+            Ok(v) => v.into_iter().filter(|(_, v)| v.timeout >= now).collect(),
+            Err(e) => {
+                error!("Failed to list sessions: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// Forget the session, the user will have to login again
+    pub async fn remove(&self, k1: &str) {
+        self.remove_by_id(&session_id(k1)).await;
+    }
+
+    /// Forget the session found by its id, e.g. when it is revoked
+    pub async fn remove_by_id(&self, id: &str) {
+        if let Err(e) = self.store.remove(id).await {
+            error!("Failed to remove session: {}", e);
+        }
+    }
+
+    /// Forget all sessions of the user, i.e. log out everywhere
+    pub async fn remove_user(&self, key: &str) {
+        if let Err(e) = self.store.remove_all(key).await {
+            error!("Failed to remove sessions of {}: {}", key, e);
+        }
+    }
+
+    /// Remove expired sessions from the storage
+    pub async fn sweep(&self) {
+        if let Err(e) = self.store.sweep(Utc::now().naive_utc()).await {
+            error!("Failed to sweep sessions: {}", e);
+        }
+    }
+}
+
+impl Default for Sessions {
+    /// Default timeouts with in memory sessions
+    fn default() -> Self {
+        Sessions::new(Box::new(MemoryStore::default()))
+    }
+}
+
+/// Storage of the sessions of logged users keyed by `session_id`
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Find session by id. Might return already expired session.
+    async fn get(&self, id: &str) -> Result<Option<SessionInfo>, SessionError>;
+
+    /// Create new session or replace existing one
+    async fn insert(&self, id: &str, session: SessionInfo) -> Result<(), SessionError>;
+
+    /// List sessions of the user or all sessions if the key is `None`.
+    /// Might return already expired sessions.
+    async fn list(&self, key: Option<&str>) -> Result<Vec<(SessionId, SessionInfo)>, SessionError>;
+
+    /// Forget the session
+    async fn remove(&self, id: &str) -> Result<(), SessionError>;
+
+    /// Forget all sessions of the user
+    async fn remove_all(&self, key: &str) -> Result<(), SessionError>;
+
+    /// Remove sessions that are expired before the given time
+    async fn sweep(&self, now: NaiveDateTime) -> Result<(), SessionError>;
+}
+
+/// Sessions that are kept in memory of the process
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<SessionId, SessionInfo>>,
+}
+
+impl MemoryStore {
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, SessionInfo>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn get(&self, id: &str) -> Result<Option<SessionInfo>, SessionError> {
+        Ok(self.sessions().get(id).cloned())
+    }
+
+    async fn insert(&self, id: &str, session: SessionInfo) -> Result<(), SessionError> {
+        self.sessions().insert(id.to_owned(), session);
+        Ok(())
+    }
+
+    async fn list(&self, key: Option<&str>) -> Result<Vec<(SessionId, SessionInfo)>, SessionError> {
+        Ok(self
+            .sessions()
+            .iter()
+            .filter(|(_, v)| key.map(|key| v.key == key).unwrap_or(true))
+            .map(|(id, v)| (id.clone(), v.clone()))
+            .collect())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), SessionError> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+
+    async fn remove_all(&self, key: &str) -> Result<(), SessionError> {
+        self.sessions().retain(|_, v| v.key != key);
+        Ok(())
+    }
+
+    async fn sweep(&self, now: NaiveDateTime) -> Result<(), SessionError> {
+        self.sessions().retain(|_, v| v.timeout >= now);
+        Ok(())
+    }
+}
+
+/// Sessions that are kept in the `sessions` table, so they survive
+/// restarts and are shared between instances of the service.
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool) -> Self {
+        PostgresStore { pool }
+    }
+}
+
+/// Row of the `sessions` table
+type SessionRow = (
+    SessionId,
+    String,
+    Json<Vec<Permission>>,
+    NaiveDateTime,
+    NaiveDateTime,
+    NaiveDateTime,
+    Option<String>,
+    Option<String>,
+);
+
+const SESSION_COLUMNS: &str =
+    "id, key, permissions, timeout, created_at, last_activity, ip, user_agent";
+
+fn from_row(row: SessionRow) -> (SessionId, SessionInfo) {
+    let (id, key, permissions, timeout, created_at, last_activity, ip, user_agent) = row;
+    let session = SessionInfo {
+        key,
+        timeout,
+        permissions: permissions.0.into_iter().collect(),
+        created_at,
+        last_activity,
+        ip,
+        user_agent,
+    };
+    (id, session)
+}
+
+#[async_trait]
+impl SessionStore for PostgresStore {
+    async fn get(&self, id: &str) -> Result<Option<SessionInfo>, SessionError> {
+        let query = format!("select {} from sessions where id = $1", SESSION_COLUMNS);
+        let row: Option<SessionRow> = sqlx::query_as(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| from_row(row).1))
+    }
+
+    async fn insert(&self, id: &str, session: SessionInfo) -> Result<(), SessionError> {
+        let permissions: Vec<Permission> = session.permissions.into_iter().collect();
+        sqlx::query(
+            "insert into sessions (id, key, permissions, timeout, created_at, last_activity, ip, user_agent)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)
+            on conflict (id) do update set
+                key = excluded.key,
+                permissions = excluded.permissions,
+                timeout = excluded.timeout,
+                last_activity = excluded.last_activity,
+                ip = excluded.ip,
+                user_agent = excluded.user_agent",
+        )
+        .bind(id)
+        .bind(session.key)
+        .bind(Json(permissions))
+        .bind(session.timeout)
+        .bind(session.created_at)
+        .bind(session.last_activity)
+        .bind(session.ip)
+        .bind(session.user_agent)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list(&self, key: Option<&str>) -> Result<Vec<(SessionId, SessionInfo)>, SessionError> {
+        let query = format!(
+            "select {} from sessions where $1::text is null or key = $1 order by created_at",
+            SESSION_COLUMNS
+        );
+        let rows: Vec<SessionRow> = sqlx::query_as(&query)
+            .bind(key)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(from_row).collect())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), SessionError> {
+        sqlx::query("delete from sessions where id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_all(&self, key: &str) -> Result<(), SessionError> {
+        sqlx::query("delete from sessions where key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn sweep(&self, now: NaiveDateTime) -> Result<(), SessionError> {
+        sqlx::query("delete from sessions where timeout < $1")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "GKEY";
+
+    /// Put the session into the store bypassing `Sessions`
+    async fn insert(sessions: &Sessions, k1: &str, last_activity: NaiveDateTime) {
+        let session = SessionInfo {
+            key: KEY.to_owned(),
+            timeout: last_activity + Duration::minutes(5),
+            permissions: [Permission::Viewer].into_iter().collect(),
+            created_at: last_activity,
+            last_activity,
+            ip: None,
+            user_agent: None,
+        };
+        sessions
+            .store
+            .insert(&session_id(k1), session)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_hidden_and_swept() {
+        let mut sessions = Sessions::default();
+        sessions.timeout = Duration::seconds(-1);
+        sessions
+            .upsert("k1", KEY, &[Permission::Viewer], None)
+            .await;
+        assert!(sessions.get("k1").await.is_none());
+        assert!(sessions.list(None).await.is_empty());
+        assert!(sessions
+            .store
+            .get(&session_id("k1"))
+            .await
+            .unwrap()
+            .is_some());
+        sessions.sweep().await;
+        assert!(sessions
+            .store
+            .get(&session_id("k1"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stored_by_session_id() {
+        let sessions = Sessions::default();
+        let client = ClientInfo {
+            ip: Some("127.0.0.1".to_owned()),
+            ..Default::default()
+        };
+        sessions
+            .upsert("k1", KEY, &[Permission::Operator], Some(&client))
+            .await;
+        let session = sessions.get("k1").await.unwrap();
+        assert_eq!(session.key, KEY);
+        assert_eq!(session.ip.as_deref(), Some("127.0.0.1"));
+        assert!(sessions.store.get("k1").await.unwrap().is_none());
+        let ids: Vec<_> = sessions
+            .list(Some(KEY))
+            .await
+            .into_iter()
+            .map(|v| v.0)
+            .collect();
+        assert_eq!(ids, vec![session_id("k1")]);
+        // Login time and client survive the next login
+        sessions
+            .upsert("k1", KEY, &[Permission::Signer], None)
+            .await;
+        let next = sessions.get("k1").await.unwrap();
+        assert_eq!(next.created_at, session.created_at);
+        assert_eq!(next.ip.as_deref(), Some("127.0.0.1"));
+        assert!(next.permissions.contains(&Permission::Signer));
+    }
+
+    #[tokio::test]
+    async fn touch_writes_once_per_interval() {
+        let sessions = Sessions::default();
+        let now = Utc::now().naive_utc();
+        let client = ClientInfo::default();
+
+        insert(&sessions, "fresh", now).await;
+        let session = sessions.get("fresh").await.unwrap();
+        sessions
+            .touch("fresh", &session, &[Permission::Viewer], &client)
+            .await;
+        let stored = sessions.get("fresh").await.unwrap();
+        assert_eq!(stored.timeout, session.timeout);
+        assert_eq!(stored.last_activity, session.last_activity);
+
+        insert(&sessions, "old", now - sessions.touch_interval).await;
+        let session = sessions.get("old").await.unwrap();
+        sessions
+            .touch("old", &session, &[Permission::Viewer], &client)
+            .await;
+        let touched = sessions.get("old").await.unwrap();
+        assert!(touched.last_activity >= now);
+        assert!(touched.timeout >= now + sessions.timeout);
+        assert_eq!(touched.created_at, session.created_at);
+
+        // Changed permissions are written right away
+        let session = sessions.get("fresh").await.unwrap();
+        sessions
+            .touch("fresh", &session, &[Permission::Admin], &client)
+            .await;
+        let touched = sessions.get("fresh").await.unwrap();
+        assert!(touched.permissions.contains(&Permission::Admin));
+        assert!(touched.timeout >= now + sessions.timeout);
+    }
+
+    #[tokio::test]
+    async fn removal() {
+        let sessions = Sessions::default();
+        for k1 in ["a", "b", "c"] {
+            sessions.upsert(k1, KEY, &[Permission::Viewer], None).await;
+        }
+        sessions
+            .upsert("d", "GOTHER", &[Permission::Viewer], None)
+            .await;
+        sessions.remove("a").await;
+        assert!(sessions.get("a").await.is_none());
+        sessions.remove_by_id(&session_id("b")).await;
+        assert!(sessions.get("b").await.is_none());
+        assert!(sessions.get("c").await.is_some());
+        sessions.remove_user(KEY).await;
+        assert!(sessions.get("c").await.is_none());
+        assert_eq!(sessions.list(None).await.len(), 1);
+        assert!(sessions.get("d").await.is_some());
+    }
+}
@@ -0,0 +1,442 @@
+use data_encoding::BASE32_NOPAD;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::Rng;
+use secp256k1::hashes::{sha256, Hash};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Passphrase of the public Stellar network
+pub const PUBLIC_NETWORK: &str = "Public Global Stellar Network ; September 2015";
+/// Passphrase of the Stellar test network
+pub const TEST_NETWORK: &str = "Test SDF Network ; September 2015";
+
+/// StrKey version byte for account ids (G...)
+const VERSION_ACCOUNT_ID: u8 = 6 << 3;
+/// StrKey version byte for secret seeds (S...)
+const VERSION_SEED: u8 = 18 << 3;
+
+const ENVELOPE_TYPE_TX: u32 = 2;
+const KEY_TYPE_ED25519: u32 = 0;
+const PRECOND_TIME: u32 = 1;
+const MEMO_NONE: u32 = 0;
+const MANAGE_DATA: u32 = 10;
+const BASE_FEE: u32 = 100;
+/// Maximum amount of signatures in transaction envelope
+const MAX_SIGNATURES: u32 = 20;
+/// Challenge is valid for 15 minutes
+const CHALLENGE_TIMEOUT: u64 = 900;
+
+#[derive(Debug, Error)]
+pub enum StellarError {
+    #[error("Invalid StrKey encoding of {0}")]
+    StrKey(String),
+    #[error("Invalid signing key: {0}")]
+    SigningKey(ed25519_dalek::SignatureError),
+    #[error("Transaction is not base64 encoded: {0}")]
+    Base64(base64::DecodeError),
+    #[error("Signed transaction doesn't match the challenge")]
+    Mismatch,
+    #[error("Malformed transaction envelope")]
+    Malformed,
+    #[error("Transaction is not signed by the account")]
+    NotSigned,
+}
+
+/// CRC16-XModem checksum that is used in StrKey
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+fn decode_strkey(version: u8, s: &str) -> Result<[u8; 32], StellarError> {
+    let err = || StellarError::StrKey(s.to_owned());
+    let raw = BASE32_NOPAD.decode(s.as_bytes()).map_err(|_| err())?;
+    if raw.len() != 35 || raw[0] != version {
+        return Err(err());
+    }
+    let (body, checksum) = raw.split_at(33);
+    if checksum != crc16(body).to_le_bytes() {
+        return Err(err());
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&body[1..]);
+    Ok(key)
+}
+
+fn encode_strkey(version: u8, key: &[u8; 32]) -> String {
+    let mut raw = vec![version];
+    raw.extend_from_slice(key);
+    let checksum = crc16(&raw);
+    raw.extend_from_slice(&checksum.to_le_bytes());
+    BASE32_NOPAD.encode(&raw)
+}
+
+/// Decode G... account id into raw ed25519 public key
+pub fn decode_account_id(account: &str) -> Result<[u8; 32], StellarError> {
+    decode_strkey(VERSION_ACCOUNT_ID, account)
+}
+
+/// Encode raw ed25519 public key as G... account id
+pub fn encode_account_id(key: &[u8; 32]) -> String {
+    encode_strkey(VERSION_ACCOUNT_ID, key)
+}
+
+/// Writer of XDR primitives that we need for challenge transaction
+#[derive(Default)]
+struct XdrWriter(Vec<u8>);
+
+impl XdrWriter {
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn fixed(&mut self, v: &[u8]) {
+        self.0.extend_from_slice(v);
+    }
+
+    fn var(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+        let pad = (4 - v.len() % 4) % 4;
+        self.0.extend(std::iter::repeat(0).take(pad));
+    }
+
+    fn account(&mut self, key: &[u8; 32]) {
+        self.u32(KEY_TYPE_ED25519);
+        self.fixed(key);
+    }
+
+    fn manage_data(&mut self, source: &[u8; 32], name: &str, value: &[u8]) {
+        // Operation source account is present
+        self.u32(1);
+        self.account(source);
+        self.u32(MANAGE_DATA);
+        self.var(name.as_bytes());
+        // Data value is present
+        self.u32(1);
+        self.var(value);
+    }
+}
+
+/// Reader of XDR primitives that we need for signatures of envelope
+struct XdrReader<'a>(&'a [u8]);
+
+impl<'a> XdrReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StellarError> {
+        if self.0.len() < n {
+            return Err(StellarError::Malformed);
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u32(&mut self) -> Result<u32, StellarError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn var(&mut self, max: u32) -> Result<&'a [u8], StellarError> {
+        let len = self.u32()?;
+        if len > max {
+            return Err(StellarError::Malformed);
+        }
+        let v = self.take(len as usize)?;
+        self.take((4 - len as usize % 4) % 4)?;
+        Ok(v)
+    }
+}
+
+/// Challenge transaction that is sent to the client
+pub struct Challenge {
+    /// XDR of the transaction itself, we expect the same bytes back
+    pub tx: Vec<u8>,
+    /// Base64 encoded envelope signed by the server
+    pub envelope: String,
+}
+
+/// Server side of Stellar SEP-10 web authentication.
+///
+/// We only build challenge transactions ourselves and check that the
+/// client returns exactly the same transaction with its signature, so
+/// there is no need for general XDR decoding. The client must sign with
+/// the master key of the account, multisig is not supported.
+pub struct StellarAuth {
+    keypair: Keypair,
+    /// Passphrase of the network that is used to sign transactions
+    pub network_passphrase: String,
+    /// Domain that is included into challenge, without scheme
+    pub home_domain: String,
+}
+
+impl StellarAuth {
+    /// Create from S... secret seed of the server signing key
+    pub fn new(
+        seed: &str,
+        network_passphrase: &str,
+        home_domain: &str,
+    ) -> Result<Self, StellarError> {
+        let seed = decode_strkey(VERSION_SEED, seed)
+            .map_err(|_| StellarError::StrKey("signing key seed".to_owned()))?;
+        let secret = SecretKey::from_bytes(&seed).map_err(StellarError::SigningKey)?;
+        let public = PublicKey::from(&secret);
+        Ok(StellarAuth {
+            keypair: Keypair { secret, public },
+            network_passphrase: network_passphrase.to_owned(),
+            home_domain: home_domain.to_owned(),
+        })
+    }
+
+    /// G... account of the server signing key
+    pub fn account(&self) -> String {
+        encode_account_id(self.keypair.public.as_bytes())
+    }
+
+    /// Hash that is signed by transaction signers
+    fn tx_hash(&self, tx: &[u8]) -> [u8; 32] {
+        let network_id = sha256::Hash::hash(self.network_passphrase.as_bytes());
+        let mut payload = XdrWriter::default();
+        payload.fixed(&network_id.into_inner());
+        payload.u32(ENVELOPE_TYPE_TX);
+        payload.fixed(tx);
+        sha256::Hash::hash(&payload.0).into_inner()
+    }
+
+    /// Build challenge transaction for the client account and sign it
+    pub fn challenge(&self, client_account: &str) -> Result<Challenge, StellarError> {
+        let mut rng = rand::thread_rng();
+        let nonce: Vec<u8> = (0..48).map(|_| rng.gen()).collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or_default();
+        self.challenge_at(client_account, &nonce, now)
+    }
+
+    /// Challenge with the given nonce that is valid since `now`
+    fn challenge_at(
+        &self,
+        client_account: &str,
+        nonce: &[u8],
+        now: u64,
+    ) -> Result<Challenge, StellarError> {
+        let client = decode_account_id(client_account)?;
+        let server = self.keypair.public.to_bytes();
+
+        let mut tx = XdrWriter::default();
+        tx.account(&server);
+        tx.u32(BASE_FEE * 2);
+        // Sequence number is always zero so the transaction is never valid
+        tx.u64(0);
+        tx.u32(PRECOND_TIME);
+        tx.u64(now);
+        tx.u64(now + CHALLENGE_TIMEOUT);
+        tx.u32(MEMO_NONE);
+        tx.u32(2);
+        tx.manage_data(
+            &client,
+            &format!("{} auth", self.home_domain),
+            base64::encode(nonce).as_bytes(),
+        );
+        tx.manage_data(&server, "web_auth_domain", self.home_domain.as_bytes());
+        // Transaction extension
+        tx.u32(0);
+
+        let signature = self.keypair.sign(&self.tx_hash(&tx.0));
+        let mut envelope = XdrWriter::default();
+        envelope.u32(ENVELOPE_TYPE_TX);
+        envelope.fixed(&tx.0);
+        envelope.u32(1);
+        envelope.fixed(&server[28..]);
+        envelope.var(&signature.to_bytes());
+
+        Ok(Challenge {
+            envelope: base64::encode(&envelope.0),
+            tx: tx.0,
+        })
+    }
+
+    /// Check that the envelope contains our challenge transaction
+    /// and that it is signed by the client account.
+    ///
+    /// Only the signature of the account master key is accepted. Other
+    /// signers and thresholds of the account are not looked up, so
+    /// accounts with a disabled master key can't sign in.
+    pub fn verify(
+        &self,
+        challenge_tx: &[u8],
+        client_account: &str,
+        envelope: &str,
+    ) -> Result<(), StellarError> {
+        let client = PublicKey::from_bytes(&decode_account_id(client_account)?)
+            .map_err(|_| StellarError::StrKey(client_account.to_owned()))?;
+        let bytes = base64::decode(envelope.trim()).map_err(StellarError::Base64)?;
+        let mut reader = XdrReader(&bytes);
+        if reader.u32()? != ENVELOPE_TYPE_TX || reader.take(challenge_tx.len())? != challenge_tx {
+            return Err(StellarError::Mismatch);
+        }
+        let hash = self.tx_hash(challenge_tx);
+        let count = reader.u32()?;
+        if count > MAX_SIGNATURES {
+            return Err(StellarError::Malformed);
+        }
+        let mut signed = false;
+        for _ in 0..count {
+            let hint = reader.take(4)?;
+            let signature = reader.var(64)?;
+            if hint != &client.as_bytes()[28..] {
+                continue;
+            }
+            if let Ok(signature) = Signature::try_from(signature) {
+                signed |= client.verify(&hash, &signature).is_ok();
+            }
+        }
+        if !reader.0.is_empty() {
+            return Err(StellarError::Malformed);
+        }
+        if signed {
+            Ok(())
+        } else {
+            Err(StellarError::NotSigned)
+        }
+    }
+}
+
+/// Percent encode value for query of SEP-7 link
+pub fn url_encode(v: &str) -> String {
+    let mut res = String::with_capacity(v.len());
+    for b in v.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                res.push(b as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_SEED: &str = "SDJHRQF4GCMIIKAAAQ6IHY42X73FQFLHUULAPSKKD4DFDM7UXWWCRHBE";
+    const SERVER_ACCOUNT: &str = "GCZHXL5HXQX5ABDM26LHYRCQZ5OJFHLOPLZX47WEBP3V2PF5AVFK2A5D";
+    const CLIENT_SEED: &str = "SD7X7LEHBNMUIKQGKPARG5TDJNBHKC346OUARHGZL5ITC6IJPXHILY36";
+    const CLIENT_ACCOUNT: &str = "GDFQVQCYYB7GKCGSCUSIQYXTPLV5YJ3XWDMWGQMDNM4EAXAL7LITIBQ7";
+    const OTHER_ACCOUNT: &str = "GCFXHS4GXL6BVUCXBWXGTITROWLVYXQKQLF4YH5O5JT3YZXCYPAFBJZB";
+    const HOME_DOMAIN: &str = "example.com";
+    const NOW: u64 = 1_600_000_000;
+
+    /// Challenge for CLIENT_ACCOUNT with nonce 0..48 at NOW, signed by the
+    /// server. Encoded outside of this module from the Stellar XDR
+    /// definitions and SEP-10, the way SDKs build it.
+    const CHALLENGE: &str = "AAAAAgAAAACye6+nvC/QBGzXlnxEUM9ckp1uevN+fsQL9108vQVKrQAAAMgAAAAAAAAAAAAAAAEAAAAAX14QAAAAAABfXhOEAAAAAAAAAAIAAAABAAAAAMsKwFjAfmUI0hUkiGLzeuvcJ3ew2WNBg2s4QFwL+tE0AAAACgAAABBleGFtcGxlLmNvbSBhdXRoAAAAAQAAAEBBQUVDQXdRRkJnY0lDUW9MREEwT0R4QVJFaE1VRlJZWEdCa2FHeHdkSGg4Z0lTSWpKQ1VtSnlncEtpc3NMUzR2AAAAAQAAAACye6+nvC/QBGzXlnxEUM9ckp1uevN+fsQL9108vQVKrQAAAAoAAAAPd2ViX2F1dGhfZG9tYWluAAAAAAEAAAALZXhhbXBsZS5jb20AAAAAAAAAAAG9BUqtAAAAQGQCG/vmSQFs/Lg0O1HA0CVj1MQA6Ya8l2a8PCHvz4ucY/JL5su8pP3kwp34iOHMbihmlOslB8vdFTtVwHwSKAc=";
+    /// The same envelope after the client appended its master key signature
+    const SIGNED: &str = "AAAAAgAAAACye6+nvC/QBGzXlnxEUM9ckp1uevN+fsQL9108vQVKrQAAAMgAAAAAAAAAAAAAAAEAAAAAX14QAAAAAABfXhOEAAAAAAAAAAIAAAABAAAAAMsKwFjAfmUI0hUkiGLzeuvcJ3ew2WNBg2s4QFwL+tE0AAAACgAAABBleGFtcGxlLmNvbSBhdXRoAAAAAQAAAEBBQUVDQXdRRkJnY0lDUW9MREEwT0R4QVJFaE1VRlJZWEdCa2FHeHdkSGg4Z0lTSWpKQ1VtSnlncEtpc3NMUzR2AAAAAQAAAACye6+nvC/QBGzXlnxEUM9ckp1uevN+fsQL9108vQVKrQAAAAoAAAAPd2ViX2F1dGhfZG9tYWluAAAAAAEAAAALZXhhbXBsZS5jb20AAAAAAAAAAAK9BUqtAAAAQGQCG/vmSQFs/Lg0O1HA0CVj1MQA6Ya8l2a8PCHvz4ucY/JL5su8pP3kwp34iOHMbihmlOslB8vdFTtVwHwSKAcL+tE0AAAAQDm0GLtI1sX4f37AXkiat8BAl4Q+YcWkX4J1TSp8I8Pt3yAEsqxGBT61i2ltgtxw8ECRRHr5BvO0tVeh90/g9QU=";
+
+    fn server() -> StellarAuth {
+        StellarAuth::new(SERVER_SEED, PUBLIC_NETWORK, HOME_DOMAIN).unwrap()
+    }
+
+    fn challenge() -> Challenge {
+        let nonce: Vec<u8> = (0..48).collect();
+        server().challenge_at(CLIENT_ACCOUNT, &nonce, NOW).unwrap()
+    }
+
+    #[test]
+    fn strkey_vectors() {
+        assert_eq!(server().account(), SERVER_ACCOUNT);
+        let seed = decode_strkey(VERSION_SEED, CLIENT_SEED).unwrap();
+        let public = PublicKey::from(&SecretKey::from_bytes(&seed).unwrap());
+        assert_eq!(encode_account_id(public.as_bytes()), CLIENT_ACCOUNT);
+        let key = decode_account_id(OTHER_ACCOUNT).unwrap();
+        assert_eq!(encode_account_id(&key), OTHER_ACCOUNT);
+    }
+
+    #[test]
+    fn strkey_rejects_invalid() {
+        // Last character changes the checksum
+        let mut broken = CLIENT_ACCOUNT.to_owned();
+        broken.replace_range(55.., "A");
+        assert!(decode_account_id(&broken).is_err());
+        // Seed is not an account id
+        assert!(decode_account_id(CLIENT_SEED).is_err());
+        assert!(decode_account_id(&CLIENT_ACCOUNT[1..]).is_err());
+    }
+
+    #[test]
+    fn challenge_matches_vector() {
+        assert_eq!(challenge().envelope, CHALLENGE);
+    }
+
+    #[test]
+    fn verify_signed_challenge() {
+        let challenge = challenge();
+        server()
+            .verify(&challenge.tx, CLIENT_ACCOUNT, SIGNED)
+            .unwrap();
+    }
+
+    #[test]
+    fn verify_round_trip() {
+        let server = server();
+        let challenge = server.challenge(CLIENT_ACCOUNT).unwrap();
+        let seed = decode_strkey(VERSION_SEED, CLIENT_SEED).unwrap();
+        let secret = SecretKey::from_bytes(&seed).unwrap();
+        let public = PublicKey::from(&secret);
+        let client = Keypair { secret, public };
+        let signature = client.sign(&server.tx_hash(&challenge.tx));
+
+        let mut envelope = XdrWriter::default();
+        envelope.fixed(&base64::decode(&challenge.envelope).unwrap());
+        // Bump the signature count of the server envelope
+        let count = 4 + challenge.tx.len();
+        envelope.0[count..count + 4].copy_from_slice(&2u32.to_be_bytes());
+        envelope.fixed(&public.as_bytes()[28..]);
+        envelope.var(&signature.to_bytes());
+        let envelope = base64::encode(&envelope.0);
+
+        server
+            .verify(&challenge.tx, CLIENT_ACCOUNT, &envelope)
+            .unwrap();
+        assert!(matches!(
+            server.verify(&challenge.tx, OTHER_ACCOUNT, &envelope),
+            Err(StellarError::NotSigned)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_unsigned() {
+        let challenge = challenge();
+        assert!(matches!(
+            server().verify(&challenge.tx, CLIENT_ACCOUNT, CHALLENGE),
+            Err(StellarError::NotSigned)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_other_challenge() {
+        let nonce = [7u8; 48];
+        let other = server().challenge_at(CLIENT_ACCOUNT, &nonce, NOW).unwrap();
+        assert!(matches!(
+            server().verify(&other.tx, CLIENT_ACCOUNT, SIGNED),
+            Err(StellarError::Mismatch)
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//! LUD-04 conformance of LNURL-auth encoding and signature checks
+
+use super::*;
+use secp256k1::{ecdsa::Signature, PublicKey, SecretKey};
+
+/// Published example from LUD-01
+const LUD01_LNURL: &str = "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS";
+const LUD01_URL: &str =
+    "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+
+/// k1 from LUD-04 example
+const K1: &str = "e2af6254a8df433264fa23f67eb8188635d15ce883e8fc020989d5f82ae6f11e";
+const SITE_LNURL: &str = "lnurl1dp68gurn8ghj7umfw3jjucm0d5lhgct884kx7emfdcnxkvfav5exze3kxg6ngcfcv3nrgvenxgmrgenpxgekvd3hv43rsvfc8qmrxdtyxy6kxefc8qek2wrxvvcryvpe8qukgdtx8qexzefkvccnzefxv93hg6t0dc7kcmm8d9hqetuxuw";
+
+/// LUD-04 doesn't publish signatures, so these are made for the LUD-04
+/// k1 by OpenSSL with the secret key 1, the linking key is the generator
+/// point. The high S one is the same signature with S replaced by `n - S`.
+const VECTOR_KEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const VECTOR_SIG: &str = "3045022100d7fec350489b57194e30f7c7ad9453c0a196e4410de24c9f6e3ea3ad867136a6022030829ca608d9ed5df717e24e2b152d9a965561340caf78acf4ab122ec8926c07";
+const VECTOR_HIGH_S_SIG: &str = "3046022100d7fec350489b57194e30f7c7ad9453c0a196e4410de24c9f6e3ea3ad867136a6022100cf7d6359f72612a208e81db1d4ead26424597bb2a299278ecb274c5e07a3d53a";
+
+/// Order of secp256k1 curve, used to build high S signatures
+const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+fn linking_key() -> (SecretKey, PublicKey) {
+    let secp = Secp256k1::new();
+    let secret = SecretKey::from_slice(&[0x11; 32]).unwrap();
+    (secret, PublicKey::from_secret_key(&secp, &secret))
+}
+
+fn sign(k1: &str, secret: &SecretKey) -> Signature {
+    let secp = Secp256k1::new();
+    let message = Message::from_slice(&hex::decode(k1).unwrap()).unwrap();
+    secp.sign_ecdsa(&message, secret)
+}
+
+/// Same signature with S replaced by `n - S`
+fn high_s(signature: &Signature) -> Signature {
+    let mut compact = signature.serialize_compact();
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let v = CURVE_ORDER[i] as i16 - compact[32 + i] as i16 - borrow;
+        borrow = (v < 0) as i16;
+        compact[32 + i] = (v + 256 * borrow) as u8;
+    }
+    Signature::from_compact(&compact).unwrap()
+}
+
+#[test]
+fn decodes_published_lnurl_in_both_cases() {
+    assert_eq!(decode_lnurl(LUD01_LNURL).unwrap(), LUD01_URL);
+    assert_eq!(
+        decode_lnurl(&LUD01_LNURL.to_lowercase()).unwrap(),
+        LUD01_URL
+    );
+    assert_eq!(
+        decode_lnurl(&format!("lightning:{}", LUD01_LNURL)).unwrap(),
+        LUD01_URL
+    );
+}
+
+#[test]
+fn rejects_mixed_case_and_foreign_prefix() {
+    let mixed = format!("lnurl{}", &LUD01_LNURL[5..]);
+    assert!(matches!(decode_lnurl(&mixed), Err(LnUrlError::Bech32(_))));
+    let other = bech32::encode("lnbc", b"x".to_base32(), Variant::Bech32).unwrap();
+    assert!(matches!(decode_lnurl(&other), Err(LnUrlError::Prefix(_))));
+}
+
+#[test]
+fn auth_lnurl_has_only_lud04_parameters() {
+    let lnurl = generate_auth_lnurl("https://site.com", K1, LnAuthAction::Login).unwrap();
+    assert_eq!(lnurl, SITE_LNURL);
+    assert_eq!(lnurl, lnurl.to_lowercase());
+    assert_eq!(
+        decode_lnurl(&lnurl).unwrap(),
+        format!("https://site.com?tag=login&k1={}&action=login", K1)
+    );
+    let trailing = generate_auth_lnurl("https://site.com/", K1, LnAuthAction::Login).unwrap();
+    assert_eq!(trailing, SITE_LNURL);
+}
+
+#[test]
+fn auth_lnurl_actions() {
+    for (action, name) in [
+        (LnAuthAction::Register, "register"),
+        (LnAuthAction::Login, "login"),
+        (LnAuthAction::Link, "link"),
+        (LnAuthAction::Auth, "auth"),
+    ] {
+        let lnurl = generate_auth_lnurl("https://site.com", K1, action).unwrap();
+        let url = decode_lnurl(&lnurl).unwrap();
+        assert!(url.ends_with(&format!("&action={}", name)), "{}", url);
+    }
+}
+
+#[test]
+fn keyauth_link_points_to_the_same_handler() {
+    assert_eq!(
+        generate_keyauth_url("https://site.com/", K1, LnAuthAction::Login),
+        format!("keyauth://site.com?tag=login&k1={}&action=login", K1)
+    );
+}
+
+#[test]
+fn accepts_valid_signature() {
+    let (secret, public) = linking_key();
+    let sig = sign(K1, &secret).serialize_der().to_string();
+    assert!(check_signature(K1, &sig, &public.to_string()).is_ok());
+}
+
+#[test]
+fn accepts_high_s_signature() {
+    let (secret, public) = linking_key();
+    let sig = high_s(&sign(K1, &secret)).serialize_der().to_string();
+    assert!(check_signature(K1, &sig, &public.to_string()).is_ok());
+}
+
+#[test]
+fn accepts_signature_vectors() {
+    assert!(check_signature(K1, VECTOR_SIG, VECTOR_KEY).is_ok());
+    assert!(check_signature(K1, VECTOR_HIGH_S_SIG, VECTOR_KEY).is_ok());
+    assert!(check_signature(&K1.to_uppercase(), VECTOR_SIG, VECTOR_KEY).is_ok());
+    let (_, other) = linking_key();
+    assert!(matches!(
+        check_signature(K1, VECTOR_SIG, &other.to_string()),
+        Err(SigError::SignatureVerify(_))
+    ));
+}
+
+#[test]
+fn high_s_der_is_normalized() {
+    let secp = Secp256k1::new();
+    let message = Message::from_slice(&hex::decode(K1).unwrap()).unwrap();
+    let key = PublicKey::from_str(VECTOR_KEY).unwrap();
+    let mut signature = Signature::from_str(VECTOR_HIGH_S_SIG).unwrap();
+    // libsecp256k1 rejects high S as is
+    assert!(secp.verify_ecdsa(&message, &signature, &key).is_err());
+    signature.normalize_s();
+    assert_eq!(signature.serialize_der().to_string(), VECTOR_SIG);
+    assert!(secp.verify_ecdsa(&message, &signature, &key).is_ok());
+}
+
+#[test]
+fn rejects_signature_of_other_k1() {
+    let (secret, public) = linking_key();
+    let other = "00".repeat(32);
+    let sig = sign(&other, &secret).serialize_der().to_string();
+    assert!(matches!(
+        check_signature(K1, &sig, &public.to_string()),
+        Err(SigError::SignatureVerify(_))
+    ));
+}
+
+#[test]
+fn rejects_uncompressed_linking_key() {
+    let (secret, public) = linking_key();
+    let sig = sign(K1, &secret).serialize_der().to_string();
+    let key = hex::encode(public.serialize_uncompressed());
+    assert!(matches!(
+        check_signature(K1, &sig, &key),
+        Err(SigError::UncompressedKey)
+    ));
+}
+
+#[test]
+fn rejects_malformed_inputs() {
+    let (secret, public) = linking_key();
+    let key = public.to_string();
+    let sig = sign(K1, &secret).serialize_der().to_string();
+    assert!(matches!(
+        check_signature("not hex", &sig, &key),
+        Err(SigError::HexK1(_))
+    ));
+    assert!(matches!(
+        check_signature("abcd", &sig, &key),
+        Err(SigError::InvalidK1(_))
+    ));
+    assert!(matches!(
+        check_signature(K1, "3006", &key),
+        Err(SigError::SignatureDecode(_))
+    ));
+    let compact = hex::encode(sign(K1, &secret).serialize_compact());
+    assert!(matches!(
+        check_signature(K1, &compact, &key),
+        Err(SigError::SignatureDecode(_))
+    ));
+}
+
+#[test]
+fn responses_have_lud04_shape() {
+    assert_eq!(
+        rocket::serde::json::to_string(&AuthResponse::Ok).unwrap(),
+        r#"{"status":"OK"}"#
+    );
+    let error = AuthResponse::Error {
+        reason: "Bad k1".to_owned(),
+    };
+    assert_eq!(
+        rocket::serde::json::to_string(&error).unwrap(),
+        r#"{"status":"ERROR","reason":"Bad k1"}"#
+    );
+}
@@ -1,14 +1,12 @@
+pub use dividator::state::Permission;
 use rocket::form::FromFormField;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
 use rocket_okapi::JsonSchema;
+use std::convert::Infallible;
 use std::fmt;
 
-/// Permissions that session could have
-#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
-pub enum Permission {
-    /// All included
-    Admin,
-}
-
 /// Action that encoded in LNUrl
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, FromFormField, JsonSchema)]
 pub enum LnAuthAction {
@@ -28,3 +26,41 @@ impl fmt::Display for LnAuthAction {
         }
     }
 }
+
+/// Info about browser that is used to make the request
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    /// Remote IP address, taken from `--ip-header` behind a proxy
+    pub ip: Option<String>,
+    /// Value of `User-Agent` header
+    pub user_agent: Option<String>,
+    /// API token from `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            ip: req.client_ip().map(|v| v.to_string()),
+            user_agent: req.headers().get_one("User-Agent").map(|v| v.to_owned()),
+            token: req
+                .headers()
+                .get_one("Authorization")
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| v.trim().to_owned()),
+        })
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for ClientInfo {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
@@ -0,0 +1,661 @@
+use super::audit::AuditLog;
+use super::auth::guard::{Operator, Signer, Viewer};
+use super::auth::stellar::url_encode;
+use super::auth::types::Permission;
+use super::auth::AuthedPage;
+use super::holders::TxExplorer;
+use super::rounds::{approve, cancel, correct, diff_rounds, find_round, DIFF_LIMIT};
+use super::types::*;
+use dividator::state::round::{
+    format_amount, BalanceChange, Concentration, PaymentStatus, RoundInfo, STROOPS,
+};
+use dividator::state::{RoundId, RoundStatus, ScheduleId, ScheduleInfo};
+use rocket::form::{Form, FromForm, FromFormField};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::{get, post, uri, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// How many payouts are shown on one page
+const PAYOUTS_PAGE_SIZE: usize = 50;
+/// Share of a single holder in percents that is worth a warning
+const LARGE_SHARE: f64 = 10.0;
+
+/// Round as it is shown in the list of rounds
+#[derive(Serialize)]
+struct RoundRow {
+    id: RoundId,
+    name: String,
+    asset: String,
+    total: String,
+    status: String,
+    holders: usize,
+    payees: usize,
+    paid: String,
+    created_at: String,
+    corrects: Option<RoundId>,
+}
+
+impl From<&RoundInfo> for RoundRow {
+    fn from(v: &RoundInfo) -> Self {
+        let totals = v.totals();
+        RoundRow {
+            id: v.id,
+            name: v.name.clone(),
+            asset: v.asset.clone(),
+            total: format_amount(v.total),
+            status: v.status.to_string(),
+            holders: totals.holders,
+            payees: totals.payees,
+            paid: format_amount(totals.paid),
+            created_at: v.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            corrects: v.corrects,
+        }
+    }
+}
+
+/// Schedule as it is shown under the list of rounds
+#[derive(Serialize)]
+struct ScheduleRow {
+    id: ScheduleId,
+    name: String,
+    asset: String,
+    total: String,
+    period: String,
+    next_run: String,
+    last_round: Option<RoundId>,
+}
+
+impl From<&ScheduleInfo> for ScheduleRow {
+    fn from(v: &ScheduleInfo) -> Self {
+        ScheduleRow {
+            id: v.id,
+            name: v.name.clone(),
+            asset: v.asset.clone(),
+            total: format_amount(v.total),
+            period: v.period.to_string(),
+            next_run: v.next_run().format("%Y-%m-%d %H:%M").to_string(),
+            last_round: v.last_round,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct IndexContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    rounds: Vec<RoundRow>,
+    schedules: Vec<ScheduleRow>,
+    status: Option<String>,
+    statuses: Vec<String>,
+}
+
+#[openapi(skip)]
+#[get("/?<status>")]
+pub async fn index(
+    db: &State<DataBase>,
+    user: AuthedPage<Viewer>,
+    status: Option<RoundStatus>,
+) -> Template {
+    let db = db.lock().await;
+    let state = db.get().await;
+    let rounds = state
+        .rounds
+        .values()
+        .rev()
+        .filter(|v| status.map(|s| v.status == s).unwrap_or(true))
+        .map(|v| v.into())
+        .collect();
+    let context = IndexContext {
+        base: BaseContext::new("Dashboard", &user.session, user.csrf()),
+        rounds,
+        schedules: state.schedules.values().map(|v| v.into()).collect(),
+        status: status.map(|v| v.to_string()),
+        statuses: [
+            RoundStatus::Draft,
+            RoundStatus::Approved,
+            RoundStatus::Paid,
+            RoundStatus::Cancelled,
+        ]
+        .iter()
+        .map(|v| v.to_string())
+        .collect(),
+    };
+    Template::render("index", context)
+}
+
+/// Column of the payout table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum PayoutSort {
+    Account,
+    Balance,
+    Share,
+    Amount,
+    Status,
+}
+
+impl PayoutSort {
+    const ALL: [(PayoutSort, &'static str); 5] = [
+        (PayoutSort::Account, "Account"),
+        (PayoutSort::Balance, "Balance"),
+        (PayoutSort::Share, "Share"),
+        (PayoutSort::Amount, "Amount"),
+        (PayoutSort::Status, "Payment"),
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            PayoutSort::Account => "account",
+            PayoutSort::Balance => "balance",
+            PayoutSort::Share => "share",
+            PayoutSort::Amount => "amount",
+            PayoutSort::Status => "status",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Link to the page of the payout table that keeps the search
+fn payouts_href(
+    id: RoundId,
+    q: Option<&str>,
+    sort: PayoutSort,
+    order: SortOrder,
+    page: usize,
+) -> String {
+    let mut href = format!(
+        "/rounds/{}?sort={}&order={}&page={}",
+        id,
+        sort.as_str(),
+        order.as_str(),
+        page
+    );
+    if let Some(q) = q.filter(|v| !v.is_empty()) {
+        href.push_str(&format!("&q={}", url_encode(q)));
+    }
+    href
+}
+
+/// Payout as it is shown in the payout table
+#[derive(Serialize)]
+struct PayoutRow {
+    account: String,
+    balance: String,
+    share: String,
+    amount: String,
+    previous: String,
+    payment: Option<String>,
+    /// Link to the payment transaction in the explorer
+    tx_link: Option<String>,
+}
+
+/// Header of the payout table that sorts by the column
+#[derive(Serialize)]
+struct SortLink {
+    title: &'static str,
+    href: String,
+    active: bool,
+    desc: bool,
+}
+
+#[derive(Serialize)]
+struct TotalsView {
+    holders: usize,
+    excluded: usize,
+    payees: usize,
+    payout_total: String,
+    previous: String,
+    dust: String,
+    /// Set when payouts exceed the total
+    overspend: Option<String>,
+    paid: String,
+    failed: usize,
+}
+
+#[derive(Serialize)]
+struct ExclusionRow {
+    account: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct RoundContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    round: RoundRow,
+    snapshot_taken_at: Option<String>,
+    approved_by: Option<String>,
+    cancelled_by: Option<String>,
+    totals: TotalsView,
+    warnings: Vec<String>,
+    exclusions: Vec<ExclusionRow>,
+    corrections: Vec<RoundRow>,
+    payouts: Vec<PayoutRow>,
+    columns: Vec<SortLink>,
+    q: Option<String>,
+    page: usize,
+    pages: usize,
+    prev: Option<String>,
+    next: Option<String>,
+    can_approve: bool,
+    can_cancel: bool,
+    can_import: bool,
+    can_correct: bool,
+}
+
+/// Things that operators should check before the approval
+fn round_warnings(round: &RoundInfo) -> Vec<String> {
+    let mut warnings = vec![];
+    let snapshot = match &round.snapshot {
+        Some(v) => v,
+        None => return vec!["There is no snapshot of holder balances yet".to_owned()],
+    };
+    let payouts = round.payouts();
+    let totals = round.totals();
+    match round.corrects {
+        Some(parent) if payouts.is_empty() => {
+            warnings.push(format!("Nobody is underpaid by round #{}", parent));
+        }
+        None if payouts.is_empty() => {
+            warnings.push("No holders are eligible for the payout".to_owned());
+        }
+        _ => (),
+    }
+    for account in round.exclusions.keys() {
+        if !snapshot.balances.contains_key(account) {
+            warnings.push(format!(
+                "Excluded account {} is not in the snapshot",
+                account
+            ));
+        }
+    }
+    let zero = payouts.iter().filter(|v| v.amount == 0).count();
+    if zero > 0 {
+        warnings.push(format!(
+            "{} holders get nothing as their payout is less than 1 stroop",
+            zero
+        ));
+    }
+    for payout in payouts.iter().filter(|v| v.share > LARGE_SHARE) {
+        warnings.push(format!(
+            "Holder {} gets {:.2}% of the round",
+            payout.account, payout.share
+        ));
+    }
+    if totals.dust >= STROOPS {
+        warnings.push(format!(
+            "{} {} is not distributed due to rounding",
+            format_amount(totals.dust),
+            round.asset
+        ));
+    }
+    if totals.overspend > 0 {
+        let what = match round.corrects {
+            Some(parent) => format!("Payouts and payments of round #{}", parent),
+            None => "Payouts".to_owned(),
+        };
+        warnings.push(format!(
+            "{} exceed the total by {} {}",
+            what,
+            format_amount(totals.overspend),
+            round.asset
+        ));
+    }
+    if !round.adjustments.is_empty() {
+        warnings.push(format!(
+            "{} payouts are adjusted manually",
+            round.adjustments.len()
+        ));
+    }
+    if totals.failed > 0 {
+        warnings.push(format!("{} payments failed", totals.failed));
+    }
+    for payout in payouts.iter() {
+        if let Some(payment) = round.payments.get(&payout.account) {
+            if payment.amount != payout.amount {
+                warnings.push(format!(
+                    "Payment to {} is {} while the payout is {}",
+                    payout.account,
+                    format_amount(payment.amount),
+                    format_amount(payout.amount)
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+#[openapi(skip)]
+#[get("/rounds/<id>?<q>&<sort>&<order>&<page>")]
+pub async fn round_page(
+    db: &State<DataBase>,
+    explorer: &State<TxExplorer>,
+    user: AuthedPage<Viewer>,
+    id: RoundId,
+    q: Option<String>,
+    sort: Option<PayoutSort>,
+    order: Option<SortOrder>,
+    page: Option<usize>,
+) -> Result<Template, Status> {
+    let round = find_round(db, id).await?;
+    let sort = sort.unwrap_or(PayoutSort::Amount);
+    let order = order.unwrap_or(SortOrder::Desc);
+    let search = q.as_deref().unwrap_or("").trim().to_uppercase();
+
+    let status_of = |account: &str| round.payments.get(account).map(|p| p.status);
+    let mut payouts: Vec<_> = round
+        .payouts()
+        .into_iter()
+        .filter(|v| search.is_empty() || v.account.contains(&search))
+        .collect();
+    payouts.sort_by(|a, b| {
+        let res = match sort {
+            PayoutSort::Account => a.account.cmp(&b.account),
+            PayoutSort::Balance => a.balance.cmp(&b.balance),
+            PayoutSort::Share => a.share.partial_cmp(&b.share).unwrap_or(Ordering::Equal),
+            PayoutSort::Amount => a.amount.cmp(&b.amount),
+            PayoutSort::Status => status_of(&a.account)
+                .map(|v| v.to_string())
+                .cmp(&status_of(&b.account).map(|v| v.to_string())),
+        };
+        match order {
+            SortOrder::Asc => res,
+            SortOrder::Desc => res.reverse(),
+        }
+    });
+
+    let pages = ((payouts.len() + PAYOUTS_PAGE_SIZE - 1) / PAYOUTS_PAGE_SIZE).max(1);
+    let page = page.unwrap_or(1).clamp(1, pages);
+    let rows = payouts
+        .iter()
+        .skip((page - 1) * PAYOUTS_PAGE_SIZE)
+        .take(PAYOUTS_PAGE_SIZE)
+        .map(|v| {
+            let payment = round.payments.get(&v.account);
+            PayoutRow {
+                account: v.account.clone(),
+                balance: format_amount(v.balance),
+                share: format!("{:.4}", v.share),
+                amount: format_amount(v.amount),
+                previous: format_amount(v.previous),
+                payment: payment.map(|p| p.status.to_string()),
+                tx_link: payment
+                    .filter(|p| p.status != PaymentStatus::Failed)
+                    .and_then(|p| explorer.link(&p.tx_hash)),
+            }
+        })
+        .collect();
+    let columns = PayoutSort::ALL
+        .iter()
+        .map(|&(column, title)| {
+            let active = column == sort;
+            let next_order = if active && order == SortOrder::Desc {
+                SortOrder::Asc
+            } else {
+                SortOrder::Desc
+            };
+            SortLink {
+                title,
+                href: payouts_href(id, q.as_deref(), column, next_order, 1),
+                active,
+                desc: order == SortOrder::Desc,
+            }
+        })
+        .collect();
+    let totals = round.totals();
+    let can_approve = round.status == RoundStatus::Draft
+        && user.check_permissions(&[Permission::Signer])
+        && totals.payees > 0
+        && totals.overspend == 0;
+    let can_cancel = matches!(round.status, RoundStatus::Draft | RoundStatus::Approved)
+        && round.payments.is_empty()
+        && user.check_permissions(&[Permission::Operator]);
+    let can_import =
+        round.status == RoundStatus::Draft && user.check_permissions(&[Permission::Operator]);
+    let (corrections, corrected): (Vec<RoundRow>, bool) = {
+        let db = db.lock().await;
+        let state = db.get().await;
+        let corrections: Vec<&RoundInfo> = state
+            .rounds
+            .values()
+            .filter(|v| v.corrects == Some(id))
+            .collect();
+        let corrected = corrections
+            .iter()
+            .any(|v| v.status != RoundStatus::Cancelled);
+        (
+            corrections.into_iter().map(|v| v.into()).collect(),
+            corrected,
+        )
+    };
+    // The next correction is made to the live one
+    let can_correct = round.status == RoundStatus::Paid
+        && !corrected
+        && user.check_permissions(&[Permission::Operator]);
+    let context = RoundContext {
+        base: BaseContext::new(&round.name, &user.session, user.csrf()),
+        round: (&round).into(),
+        snapshot_taken_at: round
+            .snapshot
+            .as_ref()
+            .map(|v| v.taken_at.format("%Y-%m-%d %H:%M").to_string()),
+        approved_by: round.approved_by.clone(),
+        cancelled_by: round.cancelled_by.clone(),
+        totals: TotalsView {
+            holders: totals.holders,
+            excluded: totals.excluded,
+            payees: totals.payees,
+            payout_total: format_amount(totals.payout_total),
+            previous: format_amount(totals.previous),
+            dust: format_amount(totals.dust),
+            overspend: Some(totals.overspend).filter(|v| *v > 0).map(format_amount),
+            paid: format_amount(totals.paid),
+            failed: totals.failed,
+        },
+        warnings: round_warnings(&round),
+        exclusions: round
+            .exclusions
+            .iter()
+            .map(|(account, reason)| ExclusionRow {
+                account: account.clone(),
+                reason: reason.clone(),
+            })
+            .collect(),
+        corrections,
+        payouts: rows,
+        columns,
+        q: q.clone(),
+        page,
+        pages,
+        prev: (page > 1).then(|| payouts_href(id, q.as_deref(), sort, order, page - 1)),
+        next: (page < pages).then(|| payouts_href(id, q.as_deref(), sort, order, page + 1)),
+        can_approve,
+        can_cancel,
+        can_import,
+        can_correct,
+    };
+    Ok(Template::render("round", context))
+}
+
+/// Holder balance change as it is shown on the diff page
+#[derive(Serialize)]
+struct ChangeRow {
+    account: String,
+    before: String,
+    after: String,
+    change: String,
+}
+
+impl From<&BalanceChange> for ChangeRow {
+    fn from(v: &BalanceChange) -> Self {
+        let sign = if v.change < 0 { "-" } else { "+" };
+        ChangeRow {
+            account: v.account.clone(),
+            before: format_amount(v.before),
+            after: format_amount(v.after),
+            change: format!("{}{}", sign, format_amount(v.before.abs_diff(v.after))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ConcentrationView {
+    holders: usize,
+    top1: String,
+    top10: String,
+    hhi: String,
+}
+
+impl From<&Concentration> for ConcentrationView {
+    fn from(v: &Concentration) -> Self {
+        ConcentrationView {
+            holders: v.holders,
+            top1: format!("{:.2}", v.top1),
+            top10: format!("{:.2}", v.top10),
+            hhi: format!("{:.0}", v.hhi),
+        }
+    }
+}
+
+/// Round that can be chosen as the base of the diff
+#[derive(Serialize)]
+struct BaseOption {
+    id: RoundId,
+    name: String,
+    selected: bool,
+}
+
+#[derive(Serialize)]
+struct DiffContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    round: RoundRow,
+    base_round: RoundRow,
+    bases: Vec<BaseOption>,
+    taken_at: String,
+    base_taken_at: String,
+    new_holders: Vec<ChangeRow>,
+    new_count: usize,
+    exited_holders: Vec<ChangeRow>,
+    exited_count: usize,
+    increases: Vec<ChangeRow>,
+    decreases: Vec<ChangeRow>,
+    before: ConcentrationView,
+    after: ConcentrationView,
+}
+
+/// Compare snapshot of the round with the previous one to spot large
+/// holders that moved in just before the record date
+#[openapi(skip)]
+#[get("/rounds/<id>/diff?<base>")]
+pub async fn diff_page(
+    db: &State<DataBase>,
+    user: AuthedPage<Viewer>,
+    id: RoundId,
+    base: Option<RoundId>,
+) -> Result<Template, Status> {
+    let diff = diff_rounds(db, id, base, DIFF_LIMIT).await?;
+    let db = db.lock().await;
+    let state = db.get().await;
+    let round = state.rounds.get(&id).ok_or(Status::NotFound)?;
+    let base_round = state.rounds.get(&diff.base).ok_or(Status::NotFound)?;
+    let bases = state
+        .rounds
+        .values()
+        .rev()
+        .filter(|v| v.id != id && v.snapshot.is_some())
+        .map(|v| BaseOption {
+            id: v.id,
+            name: v.name.clone(),
+            selected: v.id == diff.base,
+        })
+        .collect();
+    let rows = |v: &[BalanceChange]| -> Vec<ChangeRow> { v.iter().map(|v| v.into()).collect() };
+    let context = DiffContext {
+        base: BaseContext::new(&round.name, &user.session, user.csrf()),
+        round: round.into(),
+        base_round: base_round.into(),
+        bases,
+        taken_at: diff.taken_at.format("%Y-%m-%d %H:%M").to_string(),
+        base_taken_at: diff.base_taken_at.format("%Y-%m-%d %H:%M").to_string(),
+        new_holders: rows(&diff.diff.new_holders),
+        new_count: diff.diff.new_count,
+        exited_holders: rows(&diff.diff.exited_holders),
+        exited_count: diff.diff.exited_count,
+        increases: rows(&diff.diff.increases),
+        decreases: rows(&diff.diff.decreases),
+        before: (&diff.diff.before).into(),
+        after: (&diff.diff.after).into(),
+    };
+    Ok(Template::render("diff", context))
+}
+
+#[derive(FromForm)]
+pub struct RoundActionForm {
+    csrf: String,
+}
+
+#[openapi(skip)]
+#[post("/rounds/<id>/approve", data = "<form>")]
+pub async fn approve_page(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Signer>,
+    id: RoundId,
+    form: Form<RoundActionForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    approve(db, audit_log, &user.session, id).await?;
+    Ok(Redirect::to(uri!(round_page(id, _, _, _, _))))
+}
+
+#[openapi(skip)]
+#[post("/rounds/<id>/cancel", data = "<form>")]
+pub async fn cancel_page(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Operator>,
+    id: RoundId,
+    form: Form<RoundActionForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    cancel(db, audit_log, &user.session, id).await?;
+    Ok(Redirect::to(uri!(round_page(id, _, _, _, _))))
+}
+
+#[derive(FromForm)]
+pub struct CorrectionForm {
+    csrf: String,
+    name: Option<String>,
+}
+
+#[openapi(skip)]
+#[post("/rounds/<id>/correct", data = "<form>")]
+pub async fn correct_page(
+    db: &State<DataBase>,
+    user: AuthedPage<Operator>,
+    id: RoundId,
+    form: Form<CorrectionForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let form = form.into_inner();
+    let round = correct(db, &user.session, id, form.name).await?;
+    Ok(Redirect::to(uri!(round_page(round.id, _, _, _, _))))
+}
@@ -0,0 +1,99 @@
+use super::auth::limit::RateLimit;
+use super::types::*;
+use dividator::state::K1;
+use log::*;
+use rocket::get;
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::State;
+use rocket_okapi::openapi;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{timeout_at, Instant};
+
+/// Forward messages of the broadcast channel that pass the `filter` to
+/// the browser as server-sent events with the given name.
+///
+/// * `initial` - event to send right away, e.g. when the awaited thing
+///   happened before the browser subscribed.
+/// * `once` - close the stream after the first event.
+/// * `timeout` - close the stream when nothing happened for so long.
+pub fn broadcast_events<T, F>(
+    mut receiver: broadcast::Receiver<T>,
+    name: &'static str,
+    initial: Option<T>,
+    once: bool,
+    timeout: Duration,
+    filter: F,
+) -> EventStream![]
+where
+    T: Clone + Serialize + Send + 'static,
+    F: Fn(&T) -> bool + Send + 'static,
+{
+    EventStream! {
+        let mut done = false;
+        if let Some(v) = initial {
+            yield Event::json(&v).event(name);
+            done = once;
+        }
+        let deadline = Instant::now() + timeout;
+        while !done {
+            let received = match timeout_at(deadline, receiver.recv()).await {
+                Ok(v) => v,
+                Err(_) => {
+                    trace!("Closing {} events by timeout", name);
+                    break;
+                }
+            };
+            match received {
+                Ok(v) if filter(&v) => {
+                    yield Event::json(&v).event(name);
+                    done = once;
+                }
+                Ok(_) => {
+                    trace!("Not ours event");
+                }
+                Err(RecvError::Lagged(n)) => {
+                    error!("We lagged behind high frequently updated events, skipped {}!", n);
+                }
+                Err(RecvError::Closed) => done = true,
+            }
+        }
+    }
+}
+
+/// Sends `signed` event when the k1 is signed by a wallet. Used by
+/// sign in, invite and admin rotation pages. Only k1s that wait for a
+/// wallet are accepted, the stream is closed after the login timeout.
+#[openapi(skip)]
+#[get("/events/k1?<k1>")]
+pub async fn k1_events(
+    _limit: RateLimit,
+    cache_mutex: &State<AuthCache>,
+    k1_sender: &State<broadcast::Sender<K1>>,
+    k1: String,
+) -> Result<EventStream![], Status> {
+    let receiver = k1_sender.subscribe();
+    let mut cache = cache_mutex.lock().await;
+    // The wallet might be faster than the browser
+    let signed = cache.has_signed(&k1);
+    let pending =
+        cache.has_key(&k1) || cache.has_invite(&k1).is_some() || cache.has_rotation(&k1).is_some();
+    if !signed && !pending {
+        warn!("Browser waits for unknown k1");
+        return Err(Status::NotFound);
+    }
+    let timeout = cache.login_timeout.to_std().unwrap_or_default();
+    drop(cache);
+    let initial = if signed { Some(k1.clone()) } else { None };
+    Ok(broadcast_events(
+        receiver,
+        "signed",
+        initial,
+        true,
+        timeout,
+        move |v| *v == k1,
+    ))
+}
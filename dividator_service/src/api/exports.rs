@@ -0,0 +1,338 @@
+use super::auth::guard::Viewer;
+use super::auth::AuthedUser;
+use super::rounds::find_round;
+use super::types::*;
+use dividator::state::round::{format_adjustment, format_amount};
+use dividator::state::{RoundId, RoundInfo};
+use log::*;
+use rocket::form::FromFormField;
+use rocket::http::{ContentType, Header, Status};
+use rocket::serde::json;
+use rocket::{get, Responder, State};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{MediaType, RefOr, Response, Responses};
+use rocket_okapi::okapi::Map;
+use rocket_okapi::openapi;
+use rocket_okapi::response::OpenApiResponderInner;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+
+/// Format of the exported file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField, JsonSchema)]
+#[schemars(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// Exported file that is downloaded as attachment
+#[derive(Responder)]
+pub struct ExportFile {
+    body: Vec<u8>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+impl OpenApiResponderInner for ExportFile {
+    fn responses(_gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut content = Map::new();
+        content.insert("text/csv".to_owned(), MediaType::default());
+        content.insert("application/json".to_owned(), MediaType::default());
+        let mut responses = Responses::default();
+        responses.responses.insert(
+            "200".to_owned(),
+            RefOr::Object(Response {
+                description: "File that is downloaded as attachment, CSV with header \
+                    row or JSON array of the same rows"
+                    .to_owned(),
+                content,
+                ..Default::default()
+            }),
+        );
+        Ok(responses)
+    }
+}
+
+/// Row of the export that can be written as CSV. The header lists the
+/// fields in the order they are serialized, it is written even when
+/// there are no rows.
+trait CsvRow: Serialize {
+    const HEADER: &'static [&'static str];
+}
+
+/// Balance of the holder in the snapshot
+#[derive(Serialize)]
+struct SnapshotRow {
+    account: String,
+    balance: String,
+}
+
+impl CsvRow for SnapshotRow {
+    const HEADER: &'static [&'static str] = &["account", "balance"];
+}
+
+/// Calculated payout with the payment if there is one
+#[derive(Serialize)]
+struct PayoutRow {
+    account: String,
+    balance: String,
+    share: String,
+    amount: String,
+    adjustment: String,
+    tx_hash: Option<String>,
+    status: Option<String>,
+}
+
+impl CsvRow for PayoutRow {
+    const HEADER: &'static [&'static str] = &[
+        "account",
+        "balance",
+        "share",
+        "amount",
+        "adjustment",
+        "tx_hash",
+        "status",
+    ];
+}
+
+/// Executed payment
+#[derive(Serialize)]
+struct PaymentRow {
+    account: String,
+    amount: String,
+    tx_hash: String,
+    status: String,
+    timestamp: String,
+}
+
+impl CsvRow for PaymentRow {
+    const HEADER: &'static [&'static str] =
+        &["account", "amount", "tx_hash", "status", "timestamp"];
+}
+
+fn to_csv<R: CsvRow>(rows: &[R]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .terminator(csv::Terminator::CRLF)
+        .from_writer(vec![]);
+    writer.write_record(R::HEADER)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+fn export_file<R: CsvRow>(
+    id: RoundId,
+    name: &str,
+    format: ExportFormat,
+    rows: &[R],
+) -> Result<ExportFile, Status> {
+    let (body, content_type) = match format {
+        ExportFormat::Csv => {
+            let body = to_csv(rows).map_err(|e| {
+                error!("Failed to write CSV export of round {}: {}", id, e);
+                Status::InternalServerError
+            })?;
+            (body, ContentType::CSV)
+        }
+        ExportFormat::Json => {
+            let body = json::to_pretty_string(rows).map_err(|e| {
+                error!("Failed to encode export of round {}: {}", id, e);
+                Status::InternalServerError
+            })?;
+            (body.into_bytes(), ContentType::JSON)
+        }
+    };
+    let filename = format!("round-{}-{}.{}", id, name, format.extension());
+    Ok(ExportFile {
+        body,
+        content_type,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        ),
+    })
+}
+
+fn payout_rows(round: &RoundInfo) -> Vec<PayoutRow> {
+    round
+        .payouts()
+        .into_iter()
+        .map(|v| {
+            let payment = round.payments.get(&v.account);
+            PayoutRow {
+                balance: format_amount(v.balance),
+                share: format!("{:.7}", v.share),
+                amount: format_amount(v.amount),
+                adjustment: format_adjustment(v.adjustment),
+                tx_hash: payment.map(|p| p.tx_hash.clone()),
+                status: payment.map(|p| p.status.to_string()),
+                account: v.account,
+            }
+        })
+        .collect()
+}
+
+/// Download holder balances of the round snapshot. Amounts are in units
+/// of the asset, CSV by default.
+#[openapi(tag = "exports")]
+#[get("/api/v1/rounds/<id>/export/snapshot?<format>")]
+pub async fn export_snapshot(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+    format: Option<ExportFormat>,
+) -> Result<ExportFile, Status> {
+    let round = find_round(db, id).await?;
+    let snapshot = round.snapshot.as_ref().ok_or(Status::NotFound)?;
+    let rows: Vec<SnapshotRow> = snapshot
+        .balances
+        .iter()
+        .map(|(account, balance)| SnapshotRow {
+            account: account.clone(),
+            balance: format_amount(*balance),
+        })
+        .collect();
+    export_file(id, "snapshot", format.unwrap_or(ExportFormat::Csv), &rows)
+}
+
+/// Download calculated payouts with share percent, tx hash and status
+/// of the payments
+#[openapi(tag = "exports")]
+#[get("/api/v1/rounds/<id>/export/payouts?<format>")]
+pub async fn export_payouts(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+    format: Option<ExportFormat>,
+) -> Result<ExportFile, Status> {
+    let round = find_round(db, id).await?;
+    let rows = payout_rows(&round);
+    export_file(id, "payouts", format.unwrap_or(ExportFormat::Csv), &rows)
+}
+
+/// Download executed payments of the round
+#[openapi(tag = "exports")]
+#[get("/api/v1/rounds/<id>/export/payments?<format>")]
+pub async fn export_payments(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+    format: Option<ExportFormat>,
+) -> Result<ExportFile, Status> {
+    let round = find_round(db, id).await?;
+    let rows: Vec<PaymentRow> = round
+        .payments
+        .iter()
+        .map(|(account, payment)| PaymentRow {
+            account: account.clone(),
+            amount: format_amount(payment.amount),
+            tx_hash: payment.tx_hash.clone(),
+            status: payment.status.to_string(),
+            timestamp: payment.timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        })
+        .collect();
+    export_file(id, "payments", format.unwrap_or(ExportFormat::Csv), &rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::serde_json;
+
+    fn csv_string<R: CsvRow>(rows: &[R]) -> String {
+        String::from_utf8(to_csv(rows).unwrap()).unwrap()
+    }
+
+    /// Every CSV column has the value of the JSON field with its name
+    fn assert_parity<R: CsvRow>(row: R) {
+        let json = serde_json::to_value(&row).unwrap();
+        let fields = json.as_object().unwrap();
+        let csv = csv_string(&[row]);
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let header: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        let mut columns = header.clone();
+        columns.sort();
+        let mut keys: Vec<String> = fields.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(columns, keys);
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!(record.len(), header.len());
+        for (name, value) in header.iter().zip(record.iter()) {
+            assert_eq!(value, fields[name].as_str().unwrap_or(""), "{}", name);
+        }
+    }
+
+    #[test]
+    fn header_is_written_without_rows() {
+        assert_eq!(
+            csv_string::<PaymentRow>(&[]),
+            "account,amount,tx_hash,status,timestamp\r\n"
+        );
+        assert_eq!(
+            csv_string::<PayoutRow>(&[]),
+            "account,balance,share,amount,adjustment,tx_hash,status\r\n"
+        );
+    }
+
+    #[test]
+    fn fields_are_quoted() {
+        let rows = [
+            SnapshotRow {
+                account: "GA,B".to_owned(),
+                balance: "say \"hi\"\nagain".to_owned(),
+            },
+            SnapshotRow {
+                account: "GC".to_owned(),
+                balance: "1.5".to_owned(),
+            },
+        ];
+        assert_eq!(
+            csv_string(&rows),
+            "account,balance\r\n\"GA,B\",\"say \"\"hi\"\"\nagain\"\r\nGC,1.5\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_columns_match_json_fields() {
+        assert_parity(SnapshotRow {
+            account: "GA".to_owned(),
+            balance: "1.0000000".to_owned(),
+        });
+        assert_parity(PayoutRow {
+            account: "GA".to_owned(),
+            balance: "2.0000000".to_owned(),
+            share: "50.0000000".to_owned(),
+            amount: "0.5000000".to_owned(),
+            adjustment: "-0.1000000".to_owned(),
+            tx_hash: Some("ab".repeat(32)),
+            status: Some("success".to_owned()),
+        });
+        assert_parity(PayoutRow {
+            account: "GB".to_owned(),
+            balance: "2.0000000".to_owned(),
+            share: "50.0000000".to_owned(),
+            amount: "0.5000000".to_owned(),
+            adjustment: "0".to_owned(),
+            tx_hash: None,
+            status: None,
+        });
+        assert_parity(PaymentRow {
+            account: "GA".to_owned(),
+            amount: "0.5000000".to_owned(),
+            tx_hash: "cd".repeat(32),
+            status: "failed".to_owned(),
+            timestamp: "2022-08-01T00:00:00".to_owned(),
+        });
+    }
+}
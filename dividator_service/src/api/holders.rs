@@ -0,0 +1,407 @@
+use super::auth::limit::HolderLimit;
+use super::auth::stellar::{decode_account_id, PUBLIC_NETWORK, TEST_NETWORK};
+use super::types::*;
+use dividator::state::round::{
+    format_adjustment, format_amount, Account, Adjustment, Amount, Payment, PaymentStatus,
+};
+use dividator::state::{RoundId, RoundInfo, RoundStatus};
+use log::*;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Where holders and users can see the payment transactions. There are
+/// no links for networks that the explorer doesn't know.
+pub struct TxExplorer(Option<String>);
+
+impl TxExplorer {
+    /// Explorer of the network with the given passphrase
+    pub fn new(network: &str) -> Self {
+        let name = match network {
+            PUBLIC_NETWORK => Some("public"),
+            TEST_NETWORK => Some("testnet"),
+            _ => None,
+        };
+        TxExplorer(name.map(|v| format!("https://stellar.expert/explorer/{}/tx/", v)))
+    }
+
+    /// Link to the transaction with the hash
+    pub fn link(&self, tx_hash: &str) -> Option<String> {
+        self.0.as_ref().map(|v| format!("{}{}", v, tx_hash))
+    }
+}
+
+/// Dividend of the holder in one round. Amounts are in stroops.
+#[derive(Serialize, JsonSchema)]
+pub struct HolderDividend {
+    pub round: RoundId,
+    /// Human readable name of the round
+    pub name: String,
+    /// Code of the asset that is paid
+    pub asset: String,
+    pub status: RoundStatus,
+    /// Balance of the holder in the snapshot
+    pub balance: Amount,
+    /// Share of the holder among eligible holders in percents
+    pub share: Option<f64>,
+    /// Amount to pay, none if the holder is excluded
+    pub amount: Option<Amount>,
+    /// Manual change of the pro-rata amount
+    pub adjustment: Adjustment,
+    /// Why the holder doesn't get dividends of the round
+    pub exclusion: Option<String>,
+    pub payment: Option<Payment>,
+    /// Link to the payment transaction in the explorer
+    pub tx_link: Option<String>,
+}
+
+/// Dividends of the holder in approved, paid and cancelled rounds
+#[derive(Serialize, JsonSchema)]
+pub struct HolderReport {
+    pub account: Account,
+    /// Newest rounds first
+    pub dividends: Vec<HolderDividend>,
+    /// Approved payouts without confirmed payment, in stroops per asset
+    pub unpaid: BTreeMap<String, Amount>,
+}
+
+/// Collect dividends of the holder from the rounds, newest first
+fn holder_report(rounds: &[RoundInfo], explorer: &TxExplorer, account: &str) -> HolderReport {
+    let mut dividends = vec![];
+    let mut unpaid = BTreeMap::new();
+    for round in rounds {
+        let balance = round
+            .snapshot
+            .as_ref()
+            .and_then(|v| v.balances.get(account))
+            .cloned();
+        let exclusion = round.exclusions.get(account).cloned();
+        if balance.is_none() && exclusion.is_none() {
+            continue;
+        }
+        let payout = round.payout(account);
+        let payment = round.payments.get(account).cloned();
+        let paid = payment
+            .as_ref()
+            .map(|v| v.status == PaymentStatus::Success)
+            .unwrap_or(false);
+        if let Some(payout) = payout.as_ref() {
+            if round.status == RoundStatus::Approved && !paid {
+                *unpaid.entry(round.asset.clone()).or_insert(0) += payout.amount;
+            }
+        }
+        dividends.push(HolderDividend {
+            round: round.id,
+            name: round.name.clone(),
+            asset: round.asset.clone(),
+            status: round.status,
+            balance: balance.unwrap_or(0),
+            share: payout.as_ref().map(|v| v.share),
+            amount: payout.as_ref().map(|v| v.amount),
+            adjustment: payout.as_ref().map(|v| v.adjustment).unwrap_or(0),
+            exclusion,
+            tx_link: payment
+                .as_ref()
+                .filter(|v| v.status != PaymentStatus::Failed)
+                .and_then(|v| explorer.link(&v.tx_hash)),
+            payment,
+        });
+    }
+    HolderReport {
+        account: account.to_owned(),
+        dividends,
+        unpaid,
+    }
+}
+
+/// Payouts are calculated from the whole snapshot, so only the rounds of
+/// the holder are copied under the lock and the report is built after.
+/// Draft rounds are not shown as their payouts can still change.
+async fn find_holder(db: &DataBase, explorer: &TxExplorer, account: &str) -> HolderReport {
+    let rounds: Vec<RoundInfo> = {
+        let db = db.lock().await;
+        let state = db.get().await;
+        state
+            .rounds
+            .values()
+            .rev()
+            .filter(|v| v.status != RoundStatus::Draft)
+            .filter(|v| {
+                v.exclusions.contains_key(account)
+                    || v.snapshot
+                        .as_ref()
+                        .map(|s| s.balances.contains_key(account))
+                        .unwrap_or(false)
+            })
+            .cloned()
+            .collect()
+    };
+    holder_report(&rounds, explorer, account)
+}
+
+/// Past dividends of the holder, payments and payouts that are not paid
+/// yet. Doesn't require authentication.
+#[openapi(tag = "holders")]
+#[get("/api/v1/holders/<account>")]
+pub async fn holder_json(
+    _limit: HolderLimit,
+    db: &State<DataBase>,
+    explorer: &State<TxExplorer>,
+    account: String,
+) -> Result<Json<HolderReport>, Status> {
+    let account = account.trim().to_uppercase();
+    decode_account_id(&account).map_err(|e| {
+        warn!("Lookup of invalid holder account: {}", e);
+        Status::UnprocessableEntity
+    })?;
+    Ok(Json(find_holder(db, explorer, &account).await))
+}
+
+/// Dividend as it is shown on the holder page
+#[derive(Serialize)]
+struct DividendRow {
+    round: RoundId,
+    name: String,
+    asset: String,
+    status: String,
+    balance: String,
+    share: Option<String>,
+    amount: Option<String>,
+    adjustment: Option<String>,
+    exclusion: Option<String>,
+    payment: Option<String>,
+    tx_link: Option<String>,
+}
+
+impl From<HolderDividend> for DividendRow {
+    fn from(v: HolderDividend) -> Self {
+        DividendRow {
+            round: v.round,
+            name: v.name,
+            asset: v.asset,
+            status: v.status.to_string(),
+            balance: format_amount(v.balance),
+            share: v.share.map(|v| format!("{:.4}", v)),
+            amount: v.amount.map(format_amount),
+            adjustment: Some(v.adjustment)
+                .filter(|v| *v != 0)
+                .map(format_adjustment),
+            exclusion: v.exclusion,
+            payment: v.payment.map(|v| v.status.to_string()),
+            tx_link: v.tx_link,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UnpaidRow {
+    asset: String,
+    amount: String,
+}
+
+#[derive(Serialize)]
+struct HolderContext {
+    title: &'static str,
+    parent: &'static str,
+    account: Option<String>,
+    error: Option<String>,
+    dividends: Vec<DividendRow>,
+    unpaid: Vec<UnpaidRow>,
+}
+
+/// Page where holders check their dividends by G-address
+#[openapi(skip)]
+#[get("/holders?<account>")]
+pub async fn holder_page(
+    _limit: HolderLimit,
+    db: &State<DataBase>,
+    explorer: &State<TxExplorer>,
+    account: Option<String>,
+) -> Template {
+    let account = account
+        .map(|v| v.trim().to_uppercase())
+        .filter(|v| !v.is_empty());
+    let mut context = HolderContext {
+        title: "Dividends",
+        parent: "base",
+        account: account.clone(),
+        error: None,
+        dividends: vec![],
+        unpaid: vec![],
+    };
+    if let Some(account) = account {
+        if decode_account_id(&account).is_err() {
+            context.error = Some(format!("{} is not a valid Stellar account", account));
+        } else {
+            let report = find_holder(db, explorer, &account).await;
+            context.dividends = report.dividends.into_iter().map(|v| v.into()).collect();
+            context.unpaid = report
+                .unpaid
+                .into_iter()
+                .map(|(asset, amount)| UnpaidRow {
+                    asset,
+                    amount: format_amount(amount),
+                })
+                .collect();
+        }
+    }
+    Template::render("holder", context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use dividator::state::round::{AddCorrection, AddRound, Snapshot};
+
+    fn time() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(0, 0)
+    }
+
+    fn round(
+        id: RoundId,
+        asset: &str,
+        status: RoundStatus,
+        balances: &[(&str, Amount)],
+    ) -> RoundInfo {
+        let mut round: RoundInfo = AddRound {
+            id,
+            name: format!("Round {}", id),
+            asset: asset.to_owned(),
+            total: 100,
+            created_by: "key".to_owned(),
+            timestamp: time(),
+        }
+        .into();
+        round.status = status;
+        round.snapshot = Some(Snapshot {
+            taken_at: time(),
+            balances: balances.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        });
+        round
+    }
+
+    /// Record payment of the whole payout
+    fn pay(round: &mut RoundInfo, account: &str, status: PaymentStatus) {
+        let amount = round.payout(account).unwrap().amount;
+        round.payments.insert(
+            account.to_owned(),
+            Payment {
+                tx_hash: "ab".repeat(32),
+                amount,
+                status,
+                timestamp: time(),
+            },
+        );
+    }
+
+    fn report(rounds: &[RoundInfo], account: &str) -> HolderReport {
+        holder_report(rounds, &TxExplorer::new(TEST_NETWORK), account)
+    }
+
+    #[test]
+    fn unpaid_sums_approved_payouts_per_asset() {
+        let holders = [("GA", 1), ("GB", 1)];
+        let waiting = round(1, "DIV", RoundStatus::Approved, &holders);
+        let mut pending = round(2, "DIV", RoundStatus::Approved, &[("GA", 3), ("GB", 1)]);
+        pay(&mut pending, "GA", PaymentStatus::Pending);
+        let mut failed = round(3, "DIV", RoundStatus::Approved, &holders);
+        pay(&mut failed, "GA", PaymentStatus::Failed);
+        let mut success = round(4, "DIV", RoundStatus::Approved, &holders);
+        pay(&mut success, "GA", PaymentStatus::Success);
+        let mut paid = round(5, "MTL", RoundStatus::Paid, &holders);
+        pay(&mut paid, "GA", PaymentStatus::Success);
+        let other = round(6, "EUR", RoundStatus::Approved, &[("GA", 1)]);
+        let cancelled = round(7, "DIV", RoundStatus::Cancelled, &holders);
+        let rounds = [cancelled, other, paid, success, failed, pending, waiting];
+
+        let report = report(&rounds, "GA");
+        assert_eq!(
+            report.unpaid,
+            BTreeMap::from([("DIV".to_owned(), 175), ("EUR".to_owned(), 100)])
+        );
+        let ids: Vec<_> = report.dividends.iter().map(|v| v.round).collect();
+        assert_eq!(ids, vec![7, 6, 5, 4, 3, 2, 1]);
+        let links: Vec<_> = report
+            .dividends
+            .iter()
+            .map(|v| v.tx_link.is_some())
+            .collect();
+        assert_eq!(links, vec![false, false, true, true, false, true, false]);
+    }
+
+    #[test]
+    fn excluded_holders_are_shown_without_amounts() {
+        let mut absent = round(1, "DIV", RoundStatus::Approved, &[("GB", 1)]);
+        absent
+            .exclusions
+            .insert("GA".to_owned(), "issuer".to_owned());
+        let mut present = round(2, "DIV", RoundStatus::Paid, &[("GA", 5), ("GB", 1)]);
+        present
+            .exclusions
+            .insert("GA".to_owned(), "exchange".to_owned());
+        let unrelated = round(3, "DIV", RoundStatus::Approved, &[("GB", 1)]);
+        let rounds = [unrelated, present, absent];
+
+        let report = report(&rounds, "GA");
+        assert!(report.unpaid.is_empty());
+        assert_eq!(report.dividends.len(), 2);
+        let present = &report.dividends[0];
+        assert_eq!(present.balance, 5);
+        assert_eq!(present.amount, None);
+        assert_eq!(present.share, None);
+        assert_eq!(present.exclusion.as_deref(), Some("exchange"));
+        let absent = &report.dividends[1];
+        assert_eq!(absent.balance, 0);
+        assert_eq!(absent.amount, None);
+        assert_eq!(absent.exclusion.as_deref(), Some("issuer"));
+    }
+
+    #[test]
+    fn corrections_count_only_the_difference() {
+        let mut parent = round(1, "DIV", RoundStatus::Paid, &[("GA", 1), ("GB", 1)]);
+        parent.adjustments = BTreeMap::from([("GA".to_owned(), -20), ("GB".to_owned(), 20)]);
+        pay(&mut parent, "GA", PaymentStatus::Success);
+        pay(&mut parent, "GB", PaymentStatus::Success);
+        let mut correction = parent.correction(AddCorrection {
+            id: 2,
+            parent: 1,
+            name: "Correction".to_owned(),
+            created_by: "key".to_owned(),
+            timestamp: time(),
+        });
+        correction.adjustments.clear();
+        correction.status = RoundStatus::Approved;
+        let rounds = [correction, parent];
+
+        let underpaid = report(&rounds, "GA");
+        assert_eq!(underpaid.unpaid, BTreeMap::from([("DIV".to_owned(), 20)]));
+        let amounts: Vec<_> = underpaid.dividends.iter().map(|v| v.amount).collect();
+        assert_eq!(amounts, vec![Some(20), Some(30)]);
+
+        let overpaid = report(&rounds, "GB");
+        assert!(overpaid.unpaid.is_empty());
+        let amounts: Vec<_> = overpaid.dividends.iter().map(|v| v.amount).collect();
+        assert_eq!(amounts, vec![None, Some(70)]);
+    }
+
+    #[test]
+    fn explorer_follows_network() {
+        let public = TxExplorer::new(PUBLIC_NETWORK);
+        assert_eq!(
+            public.link("abc").as_deref(),
+            Some("https://stellar.expert/explorer/public/tx/abc")
+        );
+        let testnet = TxExplorer::new(TEST_NETWORK);
+        assert_eq!(
+            testnet.link("abc").as_deref(),
+            Some("https://stellar.expert/explorer/testnet/tx/abc")
+        );
+        assert_eq!(TxExplorer::new("Private network").link("abc"), None);
+    }
+}
@@ -0,0 +1,706 @@
+use super::audit::{AuditKind, AuditLog};
+use super::auth::cache::SessionInfo;
+use super::auth::guard::Operator;
+use super::auth::stellar::decode_account_id;
+use super::auth::{AuthedChange, AuthedPage};
+use super::types::*;
+use chrono::prelude::*;
+use dividator::state::round::{
+    format_adjustment, format_amount, parse_adjustment, parse_amount, Account, Adjustment, Amount,
+    SetAdjustments, SetSnapshot, Snapshot,
+};
+use dividator::state::{RoundId, RoundInfo, RoundStatus, SystemUpdate};
+use log::*;
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::form::error::ErrorKind;
+use rocket::form::{self, DataField, Form, FromForm, FromFormField, ValueField};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{get, post, uri, Responder, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// What the imported CSV contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportKind {
+    /// Rows of (account, balance) that replace the snapshot
+    Snapshot,
+    /// Rows of (account, adjustment) that replace manual changes of payouts
+    Adjustments,
+}
+
+impl ImportKind {
+    pub const ALL: [ImportKind; 2] = [ImportKind::Snapshot, ImportKind::Adjustments];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportKind::Snapshot => "snapshot",
+            ImportKind::Adjustments => "adjustments",
+        }
+    }
+}
+
+impl fmt::Display for ImportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Problem that blocks the import
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ImportIssue {
+    /// Line of the CSV, 0 for problems of the whole file
+    pub line: usize,
+    pub message: String,
+}
+
+impl ImportIssue {
+    fn new(line: usize, message: String) -> Self {
+        ImportIssue { line, message }
+    }
+}
+
+/// Change of the holder that the import makes. Amounts are in units of
+/// the asset.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ImportChange {
+    pub account: Account,
+    /// Balance or adjustment before the import
+    pub old: Option<String>,
+    /// Balance or adjustment after the import
+    pub new: Option<String>,
+    pub old_payout: Option<String>,
+    pub new_payout: Option<String>,
+}
+
+/// Diff of the round that the import makes
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ImportPreview {
+    pub kind: ImportKind,
+    /// Valid rows in the CSV
+    pub rows: usize,
+    /// The import is rejected if there are any
+    pub issues: Vec<ImportIssue>,
+    /// Only holders with changed balance or adjustment
+    pub changes: Vec<ImportChange>,
+    pub payout_total_before: String,
+    pub payout_total_after: String,
+    /// The import is applied to the round
+    pub committed: bool,
+}
+
+impl ImportPreview {
+    fn new(
+        kind: ImportKind,
+        rows: usize,
+        issues: Vec<ImportIssue>,
+        changes: Vec<ImportChange>,
+        before: &RoundInfo,
+        after: &RoundInfo,
+    ) -> Self {
+        ImportPreview {
+            kind,
+            rows,
+            issues,
+            changes,
+            payout_total_before: format_amount(before.totals().payout_total),
+            payout_total_after: format_amount(after.totals().payout_total),
+            committed: false,
+        }
+    }
+}
+
+/// Row of the CSV with the line number for error messages
+struct CsvLine {
+    line: usize,
+    account: Account,
+    value: String,
+}
+
+/// Split the CSV into (account, value) rows. The optional header and
+/// the byte order mark of spreadsheet exports are skipped, invalid
+/// StrKeys and duplicates are reported as issues.
+fn parse_rows(csv: &str, issues: &mut Vec<ImportIssue>) -> Vec<CsvLine> {
+    let csv = csv.strip_prefix('\u{feff}').unwrap_or(csv);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes());
+    let mut rows = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut first = true;
+    for record in reader.records() {
+        let record = match record {
+            Ok(v) => v,
+            Err(e) => {
+                let line = e.position().map(|v| v.line() as usize).unwrap_or(0);
+                issues.push(ImportIssue::new(line, format!("Invalid CSV: {}", e)));
+                continue;
+            }
+        };
+        let line = record.position().map(|v| v.line() as usize).unwrap_or(0);
+        if record.iter().all(|v| v.is_empty()) {
+            continue;
+        }
+        if std::mem::take(&mut first) && record[0].eq_ignore_ascii_case("account") {
+            continue;
+        }
+        if record.len() != 2 {
+            let message = format!("Expected 2 columns, found {}", record.len());
+            issues.push(ImportIssue::new(line, message));
+            continue;
+        }
+        let account = record[0].to_owned();
+        if decode_account_id(&account).is_err() {
+            let message = format!("{} is not a valid Stellar account", account);
+            issues.push(ImportIssue::new(line, message));
+            continue;
+        }
+        if let Some(prev) = seen.get(&account) {
+            let message = format!("Duplicate of {} on line {}", account, prev);
+            issues.push(ImportIssue::new(line, message));
+            continue;
+        }
+        seen.insert(account.clone(), line);
+        rows.push(CsvLine {
+            line,
+            account,
+            value: record[1].to_owned(),
+        });
+    }
+    rows
+}
+
+fn payout_amounts(round: &RoundInfo) -> BTreeMap<Account, Amount> {
+    round
+        .payouts()
+        .into_iter()
+        .map(|v| (v.account, v.amount))
+        .collect()
+}
+
+/// Holders whose value differs in `old` and `new` with their payouts
+/// before and after the import
+fn diff<V: PartialEq>(
+    before: &RoundInfo,
+    after: &RoundInfo,
+    old: &BTreeMap<Account, V>,
+    new: &BTreeMap<Account, V>,
+    format: impl Fn(&V) -> String,
+) -> Vec<ImportChange> {
+    let old_payouts = payout_amounts(before);
+    let new_payouts = payout_amounts(after);
+    let accounts: BTreeSet<&Account> = old.keys().chain(new.keys()).collect();
+    accounts
+        .into_iter()
+        .filter(|v| old.get(*v) != new.get(*v))
+        .map(|account| ImportChange {
+            account: account.clone(),
+            old: old.get(account).map(&format),
+            new: new.get(account).map(&format),
+            old_payout: old_payouts.get(account).map(|v| format_amount(*v)),
+            new_payout: new_payouts.get(account).map(|v| format_amount(*v)),
+        })
+        .collect()
+}
+
+/// Why the adjustment can't be applied to the payout of the holder,
+/// `payouts` are calculated without any adjustments
+fn adjustment_issue(
+    payouts: &BTreeMap<Account, Amount>,
+    account: &Account,
+    adjustment: Adjustment,
+) -> Option<String> {
+    match payouts.get(account) {
+        None => Some(format!("{} has no payout in the round", account)),
+        Some(amount) if (*amount as i128) + (adjustment as i128) < 0 => {
+            Some(format!("Adjustment makes payout of {} negative", account))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Adjustments can make payouts larger than the round total
+fn total_issue(round: &RoundInfo) -> Option<ImportIssue> {
+    let overspend = round.totals().overspend;
+    if overspend > 0 {
+        let message = format!(
+            "Payouts exceed the round total by {}",
+            format_amount(overspend)
+        );
+        Some(ImportIssue::new(0, message))
+    } else {
+        None
+    }
+}
+
+fn prepare_snapshot(
+    round: &RoundInfo,
+    csv: &str,
+    taken_at: NaiveDateTime,
+    now: NaiveDateTime,
+) -> (ImportPreview, SystemUpdate) {
+    let mut issues = vec![];
+    let mut balances = BTreeMap::new();
+    for row in parse_rows(csv, &mut issues) {
+        match parse_amount(&row.value) {
+            Some(v) => {
+                balances.insert(row.account, v);
+            }
+            None => {
+                let message = format!("Invalid balance {}", row.value);
+                issues.push(ImportIssue::new(row.line, message));
+            }
+        }
+    }
+    if balances.is_empty() && issues.is_empty() {
+        issues.push(ImportIssue::new(
+            0,
+            "There are no holders in the file".to_owned(),
+        ));
+    }
+    let snapshot = Snapshot { taken_at, balances };
+    let mut imported = round.clone();
+    imported.snapshot = Some(snapshot.clone());
+    // Adjustments of the round are kept and must fit the new payouts
+    let mut base = imported.clone();
+    base.adjustments.clear();
+    let base_payouts = payout_amounts(&base);
+    for (account, adjustment) in &round.adjustments {
+        if let Some(message) = adjustment_issue(&base_payouts, account, *adjustment) {
+            let message = format!("{}, change the adjustments first", message);
+            issues.push(ImportIssue::new(0, message));
+        }
+    }
+    issues.extend(total_issue(&imported));
+    let empty = BTreeMap::new();
+    let old = round
+        .snapshot
+        .as_ref()
+        .map(|v| &v.balances)
+        .unwrap_or(&empty);
+    let changes = diff(round, &imported, old, &snapshot.balances, |v| {
+        format_amount(*v)
+    });
+    let rows = snapshot.balances.len();
+    let preview = ImportPreview::new(
+        ImportKind::Snapshot,
+        rows,
+        issues,
+        changes,
+        round,
+        &imported,
+    );
+    let update = SystemUpdate::SetSnapshot(SetSnapshot {
+        round: round.id,
+        snapshot,
+        timestamp: now,
+    });
+    (preview, update)
+}
+
+fn prepare_adjustments(
+    round: &RoundInfo,
+    csv: &str,
+    now: NaiveDateTime,
+) -> (ImportPreview, SystemUpdate) {
+    let mut issues = vec![];
+    let mut base = round.clone();
+    base.adjustments.clear();
+    let base_payouts = payout_amounts(&base);
+    let mut adjustments = BTreeMap::new();
+    for row in parse_rows(csv, &mut issues) {
+        let adjustment = match parse_adjustment(&row.value) {
+            Some(v) => v,
+            None => {
+                let message = format!("Invalid adjustment {}", row.value);
+                issues.push(ImportIssue::new(row.line, message));
+                continue;
+            }
+        };
+        if let Some(message) = adjustment_issue(&base_payouts, &row.account, adjustment) {
+            issues.push(ImportIssue::new(row.line, message));
+        } else if adjustment != 0 {
+            adjustments.insert(row.account, adjustment);
+        }
+    }
+    let mut imported = round.clone();
+    imported.adjustments = adjustments.clone();
+    issues.extend(total_issue(&imported));
+    let changes = diff(round, &imported, &round.adjustments, &adjustments, |v| {
+        format_adjustment(*v)
+    });
+    let preview = ImportPreview::new(
+        ImportKind::Adjustments,
+        adjustments.len(),
+        issues,
+        changes,
+        round,
+        &imported,
+    );
+    let update = SystemUpdate::SetAdjustments(SetAdjustments {
+        round: round.id,
+        adjustments,
+        timestamp: now,
+    });
+    (preview, update)
+}
+
+/// Parse time of the snapshot from the `datetime-local` input
+fn parse_taken_at(v: Option<&str>) -> Result<Option<NaiveDateTime>, Status> {
+    match v.map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        None => Ok(None),
+        Some(v) => NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M"))
+            .map(Some)
+            .map_err(|e| {
+                warn!("Invalid snapshot time {}: {}", v, e);
+                Status::UnprocessableEntity
+            }),
+    }
+}
+
+/// Uploaded CSV with its kind
+pub struct ImportData<'a> {
+    pub kind: ImportKind,
+    pub csv: &'a str,
+    /// Time of the imported snapshot, now if not set
+    pub taken_at: Option<NaiveDateTime>,
+}
+
+/// Validate the CSV against the draft round and apply it as single
+/// update when `commit` is set and there are no issues. The round is
+/// locked for the whole check, so the preview matches what is applied.
+pub async fn import_round(
+    db: &DataBase,
+    audit_log: &AuditLog,
+    session: &SessionInfo,
+    id: RoundId,
+    data: ImportData<'_>,
+    commit: bool,
+) -> Result<ImportPreview, Status> {
+    let kind = data.kind;
+    let mut db = db.lock().await;
+    let round = db
+        .get()
+        .await
+        .rounds
+        .get(&id)
+        .cloned()
+        .ok_or(Status::NotFound)?;
+    if round.status != RoundStatus::Draft {
+        warn!("Import to round {} that is {}", id, round.status);
+        return Err(Status::Conflict);
+    }
+    let now = Utc::now().naive_utc();
+    let (mut preview, update) = match kind {
+        ImportKind::Snapshot => {
+            prepare_snapshot(&round, data.csv, data.taken_at.unwrap_or(now), now)
+        }
+        ImportKind::Adjustments => prepare_adjustments(&round, data.csv, now),
+    };
+    if commit && preview.issues.is_empty() {
+        db.update(update).await.map_err(|e| {
+            warn!("Failed to import {} of round {}: {}", kind, id, e);
+            Status::Conflict
+        })?;
+        preview.committed = true;
+        info!(
+            "User {} imported {} of round {} with {} rows",
+            session.key, kind, id, preview.rows
+        );
+        let details = format!("{} of round {}, {} rows", kind, id, preview.rows);
+        audit_log
+            .record(
+                AuditKind::RoundImported,
+                Some(&session.key),
+                session.ip.as_deref(),
+                Some(&details),
+            )
+            .await;
+    }
+    Ok(preview)
+}
+
+/// Max size of uploaded CSV files, other request bodies keep the default
+/// limits of Rocket
+pub struct ImportLimit(pub ByteUnit);
+
+/// Import CSV into the draft round. Snapshot rows are (account, balance),
+/// adjustment rows are (account, adjustment) with amounts in units of the
+/// asset. Only the preview is returned unless `commit` is set.
+#[openapi(tag = "rounds")]
+#[post(
+    "/api/v1/rounds/<id>/import?<kind>&<commit>&<taken_at>",
+    format = "text/csv",
+    data = "<csv>"
+)]
+pub async fn import_csv(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    limit: &State<ImportLimit>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+    kind: ImportKind,
+    commit: Option<bool>,
+    taken_at: Option<String>,
+    csv: Data<'_>,
+) -> Result<Json<ImportPreview>, Status> {
+    let taken_at = parse_taken_at(taken_at.as_deref())?;
+    let csv = csv.open(limit.0).into_string().await.map_err(|e| {
+        warn!("Failed to read CSV: {}", e);
+        Status::BadRequest
+    })?;
+    if !csv.is_complete() {
+        warn!("CSV is larger than {}", limit.0);
+        return Err(Status::PayloadTooLarge);
+    }
+    let data = ImportData {
+        kind,
+        csv: &csv,
+        taken_at,
+    };
+    let commit = commit.unwrap_or(false);
+    let preview = import_round(db, audit_log, &user.session, id, data, commit).await?;
+    Ok(Json(preview))
+}
+
+#[derive(Serialize)]
+struct ImportContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    id: RoundId,
+    name: String,
+    kinds: Vec<&'static str>,
+    kind: Option<&'static str>,
+    taken_at: Option<String>,
+    preview: Option<ImportPreview>,
+    csv: Option<String>,
+}
+
+fn import_context(
+    user: &AuthedPage<Operator>,
+    round: &RoundInfo,
+    preview: Option<ImportPreview>,
+    form: Option<&ImportForm>,
+) -> ImportContext {
+    ImportContext {
+        base: BaseContext::new("Import", &user.session, user.csrf()),
+        id: round.id,
+        name: round.name.clone(),
+        kinds: ImportKind::ALL.iter().map(|v| v.as_str()).collect(),
+        kind: form.map(|v| v.kind.as_str()),
+        taken_at: form.and_then(|v| v.taken_at.clone()),
+        preview,
+        csv: form.map(|v| v.csv.0.clone()),
+    }
+}
+
+#[openapi(skip)]
+#[get("/rounds/<id>/import")]
+pub async fn import_page(
+    db: &State<DataBase>,
+    user: AuthedPage<Operator>,
+    id: RoundId,
+) -> Result<Template, Status> {
+    let round = super::rounds::find_round(db, id).await?;
+    let context = import_context(&user, &round, None, None);
+    Ok(Template::render("import", context))
+}
+
+/// CSV file of the upload form. Read with `ImportLimit` instead of the
+/// `string` limit that is used for other form fields.
+pub struct CsvUpload(String);
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for CsvUpload {
+    fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
+        Ok(CsvUpload(field.value.to_owned()))
+    }
+
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        let limit = match field.request.rocket().state::<ImportLimit>() {
+            Some(v) => v.0,
+            None => Limits::STRING,
+        };
+        let csv = field
+            .data
+            .open(limit)
+            .into_string()
+            .await
+            .map_err(form::Error::custom)?;
+        if !csv.is_complete() {
+            let max = Some(limit.as_u64());
+            return Err(form::Error::from(ErrorKind::InvalidLength { min: None, max }).into());
+        }
+        Ok(CsvUpload(csv.into_inner()))
+    }
+}
+
+/// Uploaded CSV. The first submit shows the preview, the second one
+/// with `commit` applies it.
+#[derive(FromForm)]
+pub struct ImportForm {
+    csrf: String,
+    kind: ImportKind,
+    csv: CsvUpload,
+    taken_at: Option<String>,
+    commit: bool,
+}
+
+#[derive(Responder)]
+pub enum ImportResponse {
+    Redirect(Redirect),
+    Preview(Template),
+}
+
+#[openapi(skip)]
+#[post("/rounds/<id>/import", data = "<form>")]
+pub async fn import_submit(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Operator>,
+    id: RoundId,
+    form: Form<ImportForm>,
+) -> Result<ImportResponse, Status> {
+    user.check_csrf(&form.csrf)?;
+    let data = ImportData {
+        kind: form.kind,
+        csv: &form.csv.0,
+        taken_at: parse_taken_at(form.taken_at.as_deref())?,
+    };
+    let preview = import_round(db, audit_log, &user.session, id, data, form.commit).await?;
+    if preview.committed {
+        let uri = uri!(super::dashboard::round_page(id, _, _, _, _));
+        return Ok(ImportResponse::Redirect(Redirect::to(uri)));
+    }
+    let round = super::rounds::find_round(db, id).await?;
+    let context = import_context(&user, &round, Some(preview), Some(&*form));
+    Ok(ImportResponse::Preview(Template::render("import", context)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dividator::state::round::{AddRound, STROOPS};
+
+    const ALICE: &str = "GDFQVQCYYB7GKCGSCUSIQYXTPLV5YJ3XWDMWGQMDNM4EAXAL7LITIBQ7";
+    const BOB: &str = "GCFXHS4GXL6BVUCXBWXGTITROWLVYXQKQLF4YH5O5JT3YZXCYPAFBJZB";
+
+    fn rows(csv: &str) -> (Vec<(usize, String, String)>, Vec<ImportIssue>) {
+        let mut issues = vec![];
+        let rows = parse_rows(csv, &mut issues)
+            .into_iter()
+            .map(|v| (v.line, v.account, v.value))
+            .collect();
+        (rows, issues)
+    }
+
+    fn round(balances: &[(&str, Amount)]) -> RoundInfo {
+        let mut round: RoundInfo = AddRound {
+            id: 1,
+            name: "Test".to_owned(),
+            asset: "DIV".to_owned(),
+            total: 100 * STROOPS,
+            created_by: "key".to_owned(),
+            timestamp: NaiveDateTime::from_timestamp(0, 0),
+        }
+        .into();
+        round.snapshot = Some(Snapshot {
+            taken_at: NaiveDateTime::from_timestamp(0, 0),
+            balances: balances.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        });
+        round
+    }
+
+    #[test]
+    fn rows_skip_bom_header_and_quotes() {
+        let csv = format!(
+            "\u{feff}account,balance\n\n\"{}\",\" 1.5\"\r\n{},2\n",
+            ALICE, BOB
+        );
+        let (rows, issues) = rows(&csv);
+        assert!(issues.is_empty());
+        assert_eq!(
+            rows,
+            vec![
+                (3, ALICE.to_owned(), "1.5".to_owned()),
+                (4, BOB.to_owned(), "2".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_reject_bad_strkey() {
+        let mut bad = ALICE.to_owned();
+        bad.pop();
+        bad.push('A');
+        let (rows, issues) = rows(&format!("{},1\nGABC,1\n", bad));
+        assert!(rows.is_empty());
+        assert_eq!(
+            issues.iter().map(|v| v.line).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn rows_reject_duplicates_and_extra_columns() {
+        let (rows, issues) = rows(&format!("{},1\n{},2\n{},1,2\n", ALICE, ALICE, BOB));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].line, 2);
+        assert!(issues[0].message.contains("line 1"));
+        assert_eq!(issues[1].line, 3);
+    }
+
+    #[test]
+    fn amounts_reject_negative_and_extra_decimals() {
+        assert_eq!(parse_amount("1.5"), Some(15_000_000));
+        assert_eq!(parse_amount("0.0000001"), Some(1));
+        assert_eq!(parse_amount("-1"), None);
+        assert_eq!(parse_amount("0.00000001"), None);
+        assert_eq!(parse_amount("1e3"), None);
+        assert_eq!(parse_adjustment("-0.5"), Some(-5_000_000));
+        assert_eq!(parse_adjustment("+0.00000001"), None);
+    }
+
+    #[test]
+    fn snapshot_rejects_negative_balances() {
+        let now = NaiveDateTime::from_timestamp(0, 0);
+        let csv = format!("{},-1\n{},1\n", ALICE, BOB);
+        let (preview, _) = prepare_snapshot(&round(&[]), &csv, now, now);
+        assert_eq!(preview.rows, 1);
+        assert_eq!(preview.issues.len(), 1);
+        assert_eq!(preview.issues[0].line, 1);
+    }
+
+    #[test]
+    fn adjustments_reject_negative_payouts() {
+        let now = NaiveDateTime::from_timestamp(0, 0);
+        let round = round(&[(ALICE, 1), (BOB, 1)]);
+        let csv = format!("{},-50.0000001\n{},-50\n", ALICE, BOB);
+        let (preview, _) = prepare_adjustments(&round, &csv, now);
+        assert_eq!(preview.rows, 1);
+        assert_eq!(preview.issues.len(), 1);
+        assert_eq!(preview.issues[0].line, 1);
+    }
+
+    #[test]
+    fn snapshot_keeps_adjustments_valid() {
+        let now = NaiveDateTime::from_timestamp(0, 0);
+        let mut round = round(&[(ALICE, 1), (BOB, 1)]);
+        round.adjustments.insert(BOB.to_owned(), -10 * STROOPS);
+        let (preview, _) = prepare_snapshot(&round, &format!("{},1\n", BOB), now, now);
+        assert!(preview.issues.is_empty());
+        let (preview, _) = prepare_snapshot(&round, &format!("{},1\n", ALICE), now, now);
+        assert_eq!(preview.issues.len(), 1);
+        assert!(preview.issues[0].message.contains(BOB));
+    }
+}
@@ -0,0 +1,145 @@
+use super::auth::limit::AuthMetrics;
+use super::auth::types::Permission;
+use super::types::*;
+use dividator::state::SystemState;
+use figment::Figment;
+use log::*;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post, routes, State};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Result of the operational task
+#[derive(Serialize)]
+pub struct TaskResult {
+    /// Human readable summary of what was done
+    pub message: String,
+}
+
+impl TaskResult {
+    fn new(message: String) -> Json<Self> {
+        info!("Internal API: {}", message);
+        Json(TaskResult { message })
+    }
+}
+
+#[get("/ping")]
+fn ping() -> Json<()> {
+    Json(())
+}
+
+/// Current state that is restored from the append log
+#[get("/state")]
+async fn inspect_state(db: &State<DataBase>) -> Json<SystemState> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    Json(state.clone())
+}
+
+/// Compact the append_db event log: write the current state as a
+/// snapshot record, so the next start doesn't need to replay the whole
+/// log. It has nothing to do with the holder snapshots of the rounds.
+#[post("/snapshot")]
+async fn force_snapshot(db: &State<DataBase>) -> Result<Json<TaskResult>, Status> {
+    let mut db = db.lock().await;
+    db.snapshot().await.map_err(|e| {
+        error!("Failed to make snapshot: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(TaskResult::new(
+        "Snapshot of the state is written, the event log is compacted".to_owned(),
+    ))
+}
+
+/// Bring sessions and pending auth actions in line with the state: drop
+/// sessions of unlinked users and replace permissions of the others,
+/// drop admin rotations that were started by a key that is not the admin
+/// any more. Also removes expired sessions and pending k1s.
+#[post("/reconcile")]
+async fn reconcile(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    sessions: &State<AuthSessions>,
+) -> Json<TaskResult> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    let admin = state.admin.as_ref().map(|v| v.key.clone());
+    let mut cache = cache_mutex.lock().await;
+    cache.cleanup();
+    let rotations = cache.rotations.len();
+    cache
+        .rotations
+        .retain(|_, v| Some(&v.old_key) == admin.as_ref());
+    let dropped = rotations - cache.rotations.len();
+    drop(cache);
+    sessions.sweep().await;
+    let mut removed = 0;
+    let mut updated = 0;
+    for (id, session) in sessions.list(None).await {
+        let stored: BTreeSet<Permission> = session.permissions.iter().cloned().collect();
+        match state.permissions(&session.key) {
+            None => {
+                sessions.remove_by_id(&id).await;
+                removed += 1;
+            }
+            Some(actual) if actual != stored => {
+                let actual: Vec<Permission> = actual.into_iter().collect();
+                sessions.set_permissions(&id, session, &actual).await;
+                updated += 1;
+            }
+            Some(_) => (),
+        }
+    }
+    TaskResult::new(format!(
+        "Sessions reconciled, {} removed, {} updated, {} stale rotations dropped",
+        removed, updated, dropped
+    ))
+}
+
+/// Counters of rejected auth attempts
+#[get("/metrics/auth")]
+async fn auth_metrics(
+    limiter: &State<AuthLimiter>,
+    cache_mutex: &State<AuthCache>,
+) -> Json<AuthMetrics> {
+    let evicted_k1 = cache_mutex.lock().await.evicted_keys;
+    let metrics = limiter.lock().await.metrics.clone();
+    Json(AuthMetrics {
+        evicted_k1,
+        ..metrics
+    })
+}
+
+/// Serve private API for operational tasks. There is no authentication,
+/// it must listen only on localhost or be protected by a reverse proxy.
+pub async fn serve_internal_api(
+    start_notify: Arc<Notify>,
+    api_config: Figment,
+    db: DataBase,
+    auth_cache: AuthCache,
+    auth_sessions: AuthSessions,
+    auth_limiter: AuthLimiter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let on_ready = AdHoc::on_liftoff("Internal API Start!", |_| {
+        Box::pin(async move {
+            start_notify.notify_one();
+        })
+    });
+    let _ = rocket::custom(api_config)
+        .mount(
+            "/",
+            routes![ping, inspect_state, force_snapshot, reconcile, auth_metrics,],
+        )
+        .attach(on_ready)
+        .manage(db)
+        .manage(auth_cache)
+        .manage(auth_sessions)
+        .manage(auth_limiter)
+        .launch()
+        .await?;
+    Ok(())
+}
@@ -1,25 +1,43 @@
+pub mod admin;
+pub mod audit;
 pub mod auth;
+pub mod dashboard;
+pub mod events;
+pub mod exports;
+pub mod holders;
+pub mod imports;
+pub mod internal;
+pub mod qr;
+pub mod rounds;
+pub mod schedules;
+pub mod sessions;
+pub mod tokens;
 pub mod types;
+pub mod users;
 
-use auth::guard_auth;
-use auth::types::Permission;
-use dividator::state::{K1};
+use audit::AuditLog;
+use auth::guard::Admin;
+use auth::limit::{AuthMetrics, HolderLimiter};
+use auth::stellar::StellarAuth;
+use auth::AuthedUser;
+use dividator::db::Pool;
+use dividator::state::K1;
 use figment::Figment;
+use holders::TxExplorer;
+use imports::ImportLimit;
+use qr::QrStyle;
+use rocket::data::ToByteUnit;
 use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
-use rocket::http::CookieJar;
-use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::State;
-use rocket::{get, routes};
+use rocket::{catchers, get, routes};
 use rocket_dyn_templates::Template;
 use rocket_okapi::{openapi, openapi_get_routes, swagger_ui::*};
-use std::borrow::Cow;
-use std::collections::HashMap;
-use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{broadcast, Mutex, Notify};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
 use types::*;
 
 #[openapi(tag = "ping")]
@@ -28,39 +46,34 @@ fn ping() -> Json<()> {
     Json(())
 }
 
-#[openapi(skip)]
-#[get("/")]
-async fn index(
-    db: &State<DataBase>,
-    cookies: &CookieJar<'_>,
+/// Counters of rejected auth attempts
+#[openapi(tag = "metrics")]
+#[get("/api/metrics/auth")]
+async fn auth_metrics(
+    limiter: &State<AuthLimiter>,
     cache_mutex: &State<AuthCache>,
-    hedge_cache: &State<SystemCache>,
-) -> Result<Template, Redirect> {
-    guard_auth(
-        db,
-        cookies,
-        cache_mutex.deref().clone(),
-        &vec![Permission::Admin],
-        async move {
-            let db = db.lock().await;
-            let state = db.get().await;
-            
-            let context = HashMap::from([
-                ("title", Cow::Borrowed("Dashboard")),
-                ("parent", Cow::Borrowed("base")),
-                ("signout", Cow::Borrowed("true")),
-            ]);
-            Template::render("index", context)
-        },
-    )
-    .await
+    _user: AuthedUser<Admin>,
+) -> Json<AuthMetrics> {
+    let evicted_k1 = cache_mutex.lock().await.evicted_keys;
+    let metrics = limiter.lock().await.metrics.clone();
+    Json(AuthMetrics {
+        evicted_k1,
+        ..metrics
+    })
 }
 
 pub async fn serve_api(
     start_notify: Arc<Notify>,
     api_config: Figment,
     db: DataBase,
+    pool: Pool,
     hedge_cache: SystemCache,
+    auth_cache: AuthCache,
+    auth_sessions: AuthSessions,
+    auth_limiter: AuthLimiter,
+    holder_limiter: HolderLimiter,
+    stellar_auth: Option<StellarAuth>,
+    qr_style: QrStyle,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let on_ready = AdHoc::on_liftoff("API Start!", |_| {
         Box::pin(async move {
@@ -69,7 +82,24 @@ pub async fn serve_api(
     });
     let domain: String = api_config.extract_inner("domain")?;
     let static_path: PathBuf = api_config.extract_inner("static_path").unwrap();
-    let auth_cache = Arc::new(Mutex::new(auth::cache::Cache::default()));
+    let import_limit: u64 = api_config.extract_inner("import_limit")?;
+    let stellar_network: String = api_config.extract_inner("stellar_network")?;
+    let sweeper_sessions = auth_sessions.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            sweeper_sessions.sweep().await;
+        }
+    });
+    let runner_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            schedules::run_schedules(&runner_db).await;
+        }
+    });
     let (k1_sender, _): (broadcast::Sender<K1>, broadcast::Receiver<K1>) = broadcast::channel(1024);
     let _ = rocket::custom(api_config)
         .mount("/", FileServer::from(static_path))
@@ -77,19 +107,86 @@ pub async fn serve_api(
             "/",
             openapi_get_routes![
                 ping,
+                auth_metrics,
                 auth::routes::index_handler,
                 auth::routes::get_qrcode_endpoint,
                 auth::routes::get_qrcode_admin_endpoint,
+                auth::routes::get_qrcode_invite_endpoint,
+                auth::routes::get_qrcode_rotate_endpoint,
+                sessions::sessions_json,
+                audit::audit_json,
+                auth::routes::stellar_challenge,
+                auth::routes::stellar_auth,
+                rounds::list_rounds,
+                rounds::create_round,
+                rounds::get_round,
+                rounds::update_round_info,
+                rounds::delete_round,
+                rounds::get_snapshot,
+                rounds::set_snapshot,
+                rounds::get_payouts,
+                rounds::get_diff,
+                rounds::get_exclusions,
+                rounds::add_exclusion,
+                rounds::remove_exclusion,
+                rounds::approve_round,
+                rounds::cancel_round,
+                rounds::correct_round,
+                rounds::get_payments,
+                rounds::add_payment,
+                schedules::list_schedules,
+                schedules::create_schedule,
+                schedules::delete_schedule,
+                exports::export_snapshot,
+                exports::export_payouts,
+                exports::export_payments,
+                imports::import_csv,
+                holders::holder_json,
             ],
         )
         .mount(
             "/",
             routes![
-                index,
+                dashboard::index,
+                dashboard::round_page,
+                dashboard::diff_page,
+                dashboard::approve_page,
+                dashboard::cancel_page,
+                dashboard::correct_page,
+                imports::import_page,
+                imports::import_submit,
+                holders::holder_page,
                 auth::routes::init,
                 auth::routes::signin,
-                auth::routes::signin_poll,
+                auth::routes::signin_finish,
+                events::k1_events,
                 auth::routes::signout,
+                users::users,
+                users::set_role,
+                users::remove_user,
+                users::create_invite,
+                users::invite_created,
+                auth::routes::invite,
+                auth::routes::stellar_callback,
+                sessions::sessions,
+                sessions::revoke,
+                sessions::revoke_all,
+                admin::start_rotate,
+                admin::rotate,
+                admin::rotate_confirm,
+                admin::rotate_next,
+                tokens::tokens,
+                tokens::mint,
+                tokens::revoke_token,
+                audit::audit,
+            ],
+        )
+        .register(
+            "/",
+            catchers![
+                auth::guard::unauthorized,
+                auth::guard::forbidden,
+                auth::limit::too_many_requests,
             ],
         )
         .mount(
@@ -103,9 +200,17 @@ pub async fn serve_api(
         .attach(on_ready)
         .manage(domain)
         .manage(auth_cache)
+        .manage(auth_sessions)
+        .manage(auth_limiter)
+        .manage(holder_limiter)
         .manage(hedge_cache)
         .manage(db)
+        .manage(AuditLog::new(pool))
         .manage(k1_sender)
+        .manage(stellar_auth)
+        .manage(qr_style)
+        .manage(ImportLimit(import_limit.bytes()))
+        .manage(TxExplorer::new(&stellar_network))
         .launch()
         .await?;
     Ok(())
@@ -0,0 +1,169 @@
+use clap::ValueEnum;
+use image::{DynamicImage, ImageOutputFormat, Rgb};
+use log::*;
+use qrcode::render::svg;
+use qrcode::{EcLevel, QrCode};
+use rocket::form::FromFormField;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Responder;
+use thiserror::Error;
+
+/// Smallest and largest size of QR code that clients can request
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 1024;
+
+/// Image format of rendered QR code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum QrFormat {
+    Png,
+    Svg,
+}
+
+/// Error correction level of QR code, higher levels survive more
+/// damage of the picture at cost of density.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QrEcLevel {
+    /// Restores 7% of the code
+    Low,
+    /// Restores 15% of the code
+    Medium,
+    /// Restores 25% of the code
+    Quartile,
+    /// Restores 30% of the code
+    High,
+}
+
+impl From<QrEcLevel> for EcLevel {
+    fn from(v: QrEcLevel) -> Self {
+        match v {
+            QrEcLevel::Low => EcLevel::L,
+            QrEcLevel::Medium => EcLevel::M,
+            QrEcLevel::Quartile => EcLevel::Q,
+            QrEcLevel::High => EcLevel::H,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QrError {
+    #[error("Failed to encode QR code: {0}")]
+    Encode(#[from] qrcode::types::QrError),
+    #[error("Failed to write PNG image: {0}")]
+    Png(#[from] image::ImageError),
+    #[error("Invalid color {0}, expected hex RGB like #44204d")]
+    Color(String),
+}
+
+/// Parse hex RGB color like `#44204d`
+pub fn parse_color(v: &str) -> Result<[u8; 3], QrError> {
+    let err = || QrError::Color(v.to_owned());
+    let bytes = hex::decode(v.trim_start_matches('#')).map_err(|_| err())?;
+    bytes.try_into().map_err(|_| err())
+}
+
+fn hex_color(v: [u8; 3]) -> String {
+    format!("#{}", hex::encode(v))
+}
+
+/// Look of rendered QR codes, configured on startup
+#[derive(Debug, Clone)]
+pub struct QrStyle {
+    /// RGB for dark modules
+    pub dark: [u8; 3],
+    /// RGB for background
+    pub light: [u8; 3],
+    /// Minimal width and height of the image in pixels
+    pub size: u32,
+    pub ec_level: QrEcLevel,
+}
+
+impl Default for QrStyle {
+    fn default() -> Self {
+        QrStyle {
+            dark: [68, 32, 77],
+            light: [255, 255, 255],
+            size: 300,
+            ec_level: QrEcLevel::Medium,
+        }
+    }
+}
+
+/// Rendered QR code with its content type
+#[derive(Responder)]
+pub struct QrImage(pub Vec<u8>, pub ContentType);
+
+/// Render QR code for arbitrary data in memory. Used for LNURL, SEP-7
+/// links or anything else that is shown to wallets.
+pub fn render_qrcode(data: &str, style: &QrStyle, format: QrFormat) -> Result<QrImage, QrError> {
+    let code = QrCode::with_error_correction_level(data, style.ec_level.into())?;
+    match format {
+        QrFormat::Png => {
+            let image = code
+                .render::<Rgb<u8>>()
+                .dark_color(Rgb(style.dark))
+                .light_color(Rgb(style.light))
+                .min_dimensions(style.size, style.size)
+                .build();
+            let mut bytes = vec![];
+            DynamicImage::ImageRgb8(image).write_to(&mut bytes, ImageOutputFormat::Png)?;
+            Ok(QrImage(bytes, ContentType::PNG))
+        }
+        QrFormat::Svg => {
+            let dark = hex_color(style.dark);
+            let light = hex_color(style.light);
+            let image = code
+                .render::<svg::Color>()
+                .dark_color(svg::Color(&dark))
+                .light_color(svg::Color(&light))
+                .min_dimensions(style.size, style.size)
+                .build();
+            Ok(QrImage(image.into_bytes(), ContentType::SVG))
+        }
+    }
+}
+
+/// Request guard with QR code style for the request. The format is
+/// taken from `format` query parameter or `Accept` header, PNG by
+/// default. Size can be overridden with `size` query parameter.
+pub struct QrOptions {
+    pub style: QrStyle,
+    pub format: QrFormat,
+}
+
+impl QrOptions {
+    /// Render the data with the style of the request
+    pub fn render(&self, data: &str) -> Result<QrImage, Status> {
+        render_qrcode(data, &self.style, self.format).map_err(|e| {
+            error!("Failed to render qrcode: {}", e);
+            Status::InternalServerError
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for QrOptions {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let mut style = req.rocket().state::<QrStyle>().cloned().unwrap_or_default();
+        if let Some(Ok(size)) = req.query_value::<u32>("size") {
+            style.size = size.clamp(MIN_SIZE, MAX_SIZE);
+        }
+        let format = match req.query_value::<QrFormat>("format") {
+            Some(Ok(v)) => v,
+            _ => {
+                let svg = req
+                    .accept()
+                    .map(|v| v.preferred().media_type() == &ContentType::SVG.0)
+                    .unwrap_or(false);
+                if svg {
+                    QrFormat::Svg
+                } else {
+                    QrFormat::Png
+                }
+            }
+        };
+        Outcome::Success(QrOptions { style, format })
+    }
+}
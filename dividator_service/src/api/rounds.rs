@@ -0,0 +1,646 @@
+use super::audit::{AuditKind, AuditLog};
+use super::auth::cache::SessionInfo;
+use super::auth::guard::{Operator, Signer, Viewer};
+use super::auth::stellar::decode_account_id;
+use super::auth::{AuthedChange, AuthedUser};
+use super::types::*;
+use chrono::prelude::*;
+use dividator::state::round::{
+    Account, AddCorrection, AddExclusion, AddPayment, AddRound, Adjustment, Amount, ApproveRound,
+    CancelRound, Payment, PaymentStatus, Payout, RemoveExclusion, RemoveRound, RoundTotals,
+    SetSnapshot, Snapshot, SnapshotDiff, UpdateRound,
+};
+use dividator::state::{RoundId, RoundInfo, RoundStatus, SystemUpdate};
+use log::*;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, put, State};
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How many holders each list of the snapshot diff has by default
+pub const DIFF_LIMIT: usize = 20;
+
+/// Round without snapshot and payments
+#[derive(Serialize, JsonSchema)]
+pub struct RoundSummary {
+    pub id: RoundId,
+    pub name: String,
+    /// Code of the asset that is paid
+    pub asset: String,
+    /// Amount in stroops that is distributed among the holders
+    pub total: Amount,
+    pub status: RoundStatus,
+    /// Key of the user that created the round
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    /// Key of the user that approved the payouts
+    pub approved_by: Option<String>,
+    /// Key of the user that cancelled the round
+    pub cancelled_by: Option<String>,
+    /// When the holder balances were taken
+    pub snapshot_taken_at: Option<NaiveDateTime>,
+    /// Round which payouts are corrected by this one
+    pub corrects: Option<RoundId>,
+    pub totals: RoundTotals,
+}
+
+impl From<&RoundInfo> for RoundSummary {
+    fn from(v: &RoundInfo) -> Self {
+        RoundSummary {
+            id: v.id,
+            name: v.name.clone(),
+            asset: v.asset.clone(),
+            total: v.total,
+            status: v.status,
+            created_by: v.created_by.clone(),
+            created_at: v.created_at,
+            approved_by: v.approved_by.clone(),
+            cancelled_by: v.cancelled_by.clone(),
+            snapshot_taken_at: v.snapshot.as_ref().map(|s| s.taken_at),
+            corrects: v.corrects,
+            totals: v.totals(),
+        }
+    }
+}
+
+/// Round with the excluded holders
+#[derive(Serialize, JsonSchema)]
+pub struct RoundDetails {
+    #[serde(flatten)]
+    pub summary: RoundSummary,
+    /// Holders that don't get dividends with the reason why
+    pub exclusions: BTreeMap<Account, String>,
+    /// Manual changes of the payouts in stroops
+    pub adjustments: BTreeMap<Account, Adjustment>,
+}
+
+impl From<&RoundInfo> for RoundDetails {
+    fn from(v: &RoundInfo) -> Self {
+        RoundDetails {
+            summary: v.into(),
+            exclusions: v.exclusions.clone(),
+            adjustments: v.adjustments.clone(),
+        }
+    }
+}
+
+/// Comparison of the round snapshot with the snapshot of the base round
+#[derive(Serialize, JsonSchema)]
+pub struct RoundDiff {
+    pub round: RoundId,
+    pub base: RoundId,
+    /// When the balances of the round were taken
+    pub taken_at: NaiveDateTime,
+    /// When the balances of the base round were taken
+    pub base_taken_at: NaiveDateTime,
+    #[serde(flatten)]
+    pub diff: SnapshotDiff,
+}
+
+/// Parameters of new or draft round
+#[derive(Deserialize, JsonSchema)]
+pub struct RoundRequest {
+    /// Human readable name of the round
+    pub name: String,
+    /// Code of the asset that is paid
+    pub asset: String,
+    /// Amount in stroops that is distributed among the holders
+    pub total: Amount,
+}
+
+impl RoundRequest {
+    pub fn validate(&self) -> Result<(), Status> {
+        let asset_valid = (1..=12).contains(&self.asset.len())
+            && self.asset.chars().all(|c| c.is_ascii_alphanumeric());
+        if self.name.trim().is_empty() || !asset_valid || self.total == 0 {
+            warn!("Invalid round parameters");
+            return Err(Status::UnprocessableEntity);
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the correction round
+#[derive(Deserialize, JsonSchema)]
+pub struct CorrectionRequest {
+    /// Human readable name of the round, derived from the corrected
+    /// round if not set
+    pub name: Option<String>,
+}
+
+/// Balances of the holders
+#[derive(Deserialize, JsonSchema)]
+pub struct SnapshotRequest {
+    /// When the balances were taken, now if not set
+    pub taken_at: Option<NaiveDateTime>,
+    /// Balance in stroops of each holder
+    pub balances: BTreeMap<Account, Amount>,
+}
+
+/// Why the holder is excluded
+#[derive(Deserialize, JsonSchema)]
+pub struct ExclusionRequest {
+    pub reason: String,
+}
+
+/// Submitted payment of the payout
+#[derive(Deserialize, JsonSchema)]
+pub struct PaymentRequest {
+    /// Holder account
+    pub account: Account,
+    /// Hex encoded hash of the Stellar transaction
+    pub tx_hash: String,
+    /// Paid amount in stroops, the payout amount if not set
+    pub amount: Option<Amount>,
+    pub status: PaymentStatus,
+}
+
+/// Check that the account is valid Stellar account id
+pub fn check_account(account: &str) -> Result<(), Status> {
+    decode_account_id(account).map(|_| ()).map_err(|e| {
+        warn!("Invalid holder account: {}", e);
+        Status::UnprocessableEntity
+    })
+}
+
+/// Find the round in the state
+pub async fn find_round(db: &DataBase, id: RoundId) -> Result<RoundInfo, Status> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    state.rounds.get(&id).cloned().ok_or(Status::NotFound)
+}
+
+/// Apply the update to existing round and return its new version.
+/// Updates that are not allowed in the current round status fail
+/// with `Conflict`.
+pub async fn update_round(
+    db: &DataBase,
+    id: RoundId,
+    update: SystemUpdate,
+) -> Result<RoundInfo, Status> {
+    let mut db = db.lock().await;
+    if !db.get().await.rounds.contains_key(&id) {
+        return Err(Status::NotFound);
+    }
+    db.update(update).await.map_err(|e| {
+        warn!("Failed to update round {}: {}", id, e);
+        Status::Conflict
+    })?;
+    let state = db.get().await;
+    state.rounds.get(&id).cloned().ok_or(Status::NotFound)
+}
+
+/// List rounds, newest first
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds?<status>")]
+pub async fn list_rounds(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    status: Option<RoundStatus>,
+) -> Json<Vec<RoundSummary>> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    Json(
+        state
+            .rounds
+            .values()
+            .rev()
+            .filter(|v| status.map(|s| v.status == s).unwrap_or(true))
+            .map(|v| v.into())
+            .collect(),
+    )
+}
+
+/// Create draft round
+#[openapi(tag = "rounds")]
+#[post("/api/v1/rounds", data = "<body>")]
+pub async fn create_round(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    body: Json<RoundRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    body.validate()?;
+    let mut db = db.lock().await;
+    let id = db.get().await.next_round_id();
+    let body = body.into_inner();
+    db.update(SystemUpdate::AddRound(AddRound {
+        id,
+        name: body.name,
+        asset: body.asset,
+        total: body.total,
+        created_by: user.key.clone(),
+        timestamp: Utc::now().naive_utc(),
+    }))
+    .await
+    .map_err(|e| {
+        error!("Failed to create round: {}", e);
+        Status::InternalServerError
+    })?;
+    info!("User {} created round {}", user.key, id);
+    let state = db.get().await;
+    let round = state.rounds.get(&id).ok_or(Status::InternalServerError)?;
+    Ok(Json(round.into()))
+}
+
+/// Round with totals and exclusions
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>")]
+pub async fn get_round(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+) -> Result<Json<RoundDetails>, Status> {
+    let round = find_round(db, id).await?;
+    Ok(Json((&round).into()))
+}
+
+/// Change name, asset or total of the draft round
+#[openapi(tag = "rounds")]
+#[put("/api/v1/rounds/<id>", data = "<body>")]
+pub async fn update_round_info(
+    db: &State<DataBase>,
+    _user: AuthedChange<Operator>,
+    id: RoundId,
+    body: Json<RoundRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    body.validate()?;
+    let body = body.into_inner();
+    let update = SystemUpdate::UpdateRound(UpdateRound {
+        id,
+        name: body.name,
+        asset: body.asset,
+        total: body.total,
+        timestamp: Utc::now().naive_utc(),
+    });
+    let round = update_round(db, id, update).await?;
+    Ok(Json((&round).into()))
+}
+
+/// Delete the draft round
+#[openapi(tag = "rounds")]
+#[delete("/api/v1/rounds/<id>")]
+pub async fn delete_round(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+) -> Result<Json<()>, Status> {
+    let mut db = db.lock().await;
+    if !db.get().await.rounds.contains_key(&id) {
+        return Err(Status::NotFound);
+    }
+    db.update(SystemUpdate::RemoveRound(RemoveRound {
+        id,
+        timestamp: Utc::now().naive_utc(),
+    }))
+    .await
+    .map_err(|e| {
+        warn!("Failed to delete round {}: {}", id, e);
+        Status::Conflict
+    })?;
+    info!("User {} deleted round {}", user.key, id);
+    Ok(Json(()))
+}
+
+/// Balances of the holders
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>/snapshot")]
+pub async fn get_snapshot(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+) -> Result<Json<Snapshot>, Status> {
+    let round = find_round(db, id).await?;
+    round.snapshot.map(Json).ok_or(Status::NotFound)
+}
+
+/// Replace balances of the holders in the draft round
+#[openapi(tag = "rounds")]
+#[put("/api/v1/rounds/<id>/snapshot", data = "<body>")]
+pub async fn set_snapshot(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+    body: Json<SnapshotRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    let body = body.into_inner();
+    for account in body.balances.keys() {
+        check_account(account)?;
+    }
+    let now = Utc::now().naive_utc();
+    let holders = body.balances.len();
+    let update = SystemUpdate::SetSnapshot(SetSnapshot {
+        round: id,
+        snapshot: Snapshot {
+            taken_at: body.taken_at.unwrap_or(now),
+            balances: body.balances,
+        },
+        timestamp: now,
+    });
+    let round = update_round(db, id, update).await?;
+    info!(
+        "User {} set snapshot of round {} with {} holders",
+        user.key, id, holders
+    );
+    Ok(Json((&round).into()))
+}
+
+/// Compare snapshot of the round with the base round. By default the base
+/// is the latest earlier round of the same asset that has a snapshot and
+/// is not cancelled.
+pub async fn diff_rounds(
+    db: &DataBase,
+    id: RoundId,
+    base: Option<RoundId>,
+    limit: usize,
+) -> Result<RoundDiff, Status> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    let round = state.rounds.get(&id).ok_or(Status::NotFound)?;
+    let snapshot = round.snapshot.as_ref().ok_or(Status::NotFound)?;
+    let base = match base {
+        Some(base) => state.rounds.get(&base),
+        None => state.rounds.range(..id).rev().map(|(_, v)| v).find(|v| {
+            v.asset == round.asset && v.snapshot.is_some() && v.status != RoundStatus::Cancelled
+        }),
+    }
+    .ok_or(Status::NotFound)?;
+    let base_snapshot = base.snapshot.as_ref().ok_or(Status::NotFound)?;
+    Ok(RoundDiff {
+        round: id,
+        base: base.id,
+        taken_at: snapshot.taken_at,
+        base_taken_at: base_snapshot.taken_at,
+        diff: snapshot.diff(base_snapshot, limit),
+    })
+}
+
+/// New and exited holders, biggest balance changes and change of the
+/// concentration since the base round. Amounts are in stroops, `limit`
+/// cuts each list of holders.
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>/diff?<base>&<limit>")]
+pub async fn get_diff(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+    base: Option<RoundId>,
+    limit: Option<usize>,
+) -> Result<Json<RoundDiff>, Status> {
+    let diff = diff_rounds(db, id, base, limit.unwrap_or(DIFF_LIMIT)).await?;
+    Ok(Json(diff))
+}
+
+/// Calculated payouts of the eligible holders
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>/payouts")]
+pub async fn get_payouts(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+) -> Result<Json<Vec<Payout>>, Status> {
+    let round = find_round(db, id).await?;
+    Ok(Json(round.payouts()))
+}
+
+/// Holders that don't get dividends with the reason why
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>/exclusions")]
+pub async fn get_exclusions(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+) -> Result<Json<BTreeMap<Account, String>>, Status> {
+    let round = find_round(db, id).await?;
+    Ok(Json(round.exclusions))
+}
+
+/// Exclude the holder from the draft round
+#[openapi(tag = "rounds")]
+#[put("/api/v1/rounds/<id>/exclusions/<account>", data = "<body>")]
+pub async fn add_exclusion(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+    account: String,
+    body: Json<ExclusionRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    check_account(&account)?;
+    let update = SystemUpdate::AddExclusion(AddExclusion {
+        round: id,
+        account: account.clone(),
+        reason: body.into_inner().reason,
+        timestamp: Utc::now().naive_utc(),
+    });
+    let round = update_round(db, id, update).await?;
+    info!("User {} excluded {} from round {}", user.key, account, id);
+    Ok(Json((&round).into()))
+}
+
+/// Return the excluded holder to the draft round
+#[openapi(tag = "rounds")]
+#[delete("/api/v1/rounds/<id>/exclusions/<account>")]
+pub async fn remove_exclusion(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+    account: String,
+) -> Result<Json<RoundDetails>, Status> {
+    let update = SystemUpdate::RemoveExclusion(RemoveExclusion {
+        round: id,
+        account: account.clone(),
+        timestamp: Utc::now().naive_utc(),
+    });
+    let round = update_round(db, id, update).await?;
+    info!("User {} returned {} to round {}", user.key, account, id);
+    Ok(Json((&round).into()))
+}
+
+/// Approve the draft round on behalf of the user
+pub async fn approve(
+    db: &DataBase,
+    audit_log: &AuditLog,
+    session: &SessionInfo,
+    id: RoundId,
+) -> Result<RoundInfo, Status> {
+    let update = SystemUpdate::ApproveRound(ApproveRound {
+        round: id,
+        key: session.key.clone(),
+        timestamp: Utc::now().naive_utc(),
+    });
+    let round = update_round(db, id, update).await?;
+    info!("User {} approved round {}", session.key, id);
+    let details = format!("round {}", id);
+    audit_log
+        .record(
+            AuditKind::RoundApproved,
+            Some(&session.key),
+            session.ip.as_deref(),
+            Some(&details),
+        )
+        .await;
+    Ok(round)
+}
+
+/// Cancel the round on behalf of the user
+pub async fn cancel(
+    db: &DataBase,
+    audit_log: &AuditLog,
+    session: &SessionInfo,
+    id: RoundId,
+) -> Result<RoundInfo, Status> {
+    let update = SystemUpdate::CancelRound(CancelRound {
+        round: id,
+        key: session.key.clone(),
+        timestamp: Utc::now().naive_utc(),
+    });
+    let round = update_round(db, id, update).await?;
+    info!("User {} cancelled round {}", session.key, id);
+    let details = format!("round {}", id);
+    audit_log
+        .record(
+            AuditKind::RoundCancelled,
+            Some(&session.key),
+            session.ip.as_deref(),
+            Some(&details),
+        )
+        .await;
+    Ok(round)
+}
+
+/// Create correction of the paid round on behalf of the user
+pub async fn correct(
+    db: &DataBase,
+    session: &SessionInfo,
+    id: RoundId,
+    name: Option<String>,
+) -> Result<RoundInfo, Status> {
+    let mut db = db.lock().await;
+    let (new_id, name) = {
+        let state = db.get().await;
+        let parent = state.rounds.get(&id).ok_or(Status::NotFound)?;
+        let name = name
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| format!("Correction of {}", parent.name));
+        (state.next_round_id(), name)
+    };
+    db.update(SystemUpdate::AddCorrection(AddCorrection {
+        id: new_id,
+        parent: id,
+        name,
+        created_by: session.key.clone(),
+        timestamp: Utc::now().naive_utc(),
+    }))
+    .await
+    .map_err(|e| {
+        warn!("Failed to correct round {}: {}", id, e);
+        Status::Conflict
+    })?;
+    info!(
+        "User {} created round {} to correct round {}",
+        session.key, new_id, id
+    );
+    let state = db.get().await;
+    state
+        .rounds
+        .get(&new_id)
+        .cloned()
+        .ok_or(Status::InternalServerError)
+}
+
+/// Fix payouts of the draft round
+#[openapi(tag = "rounds")]
+#[post("/api/v1/rounds/<id>/approve")]
+pub async fn approve_round(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedChange<Signer>,
+    id: RoundId,
+) -> Result<Json<RoundDetails>, Status> {
+    let round = approve(db, audit_log, &user.session, id).await?;
+    Ok(Json((&round).into()))
+}
+
+/// Cancel the round that is not paid yet
+#[openapi(tag = "rounds")]
+#[post("/api/v1/rounds/<id>/cancel")]
+pub async fn cancel_round(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+) -> Result<Json<RoundDetails>, Status> {
+    let round = cancel(db, audit_log, &user.session, id).await?;
+    Ok(Json((&round).into()))
+}
+
+/// Create draft round that pays the difference to holders that were
+/// underpaid or missed by the paid round. A round with a correction that
+/// is not cancelled is corrected by correcting that round. Fix exclusions,
+/// adjustments or snapshot of the new round, its payouts are reduced by
+/// what the holders already got.
+#[openapi(tag = "rounds")]
+#[post("/api/v1/rounds/<id>/correction", data = "<body>")]
+pub async fn correct_round(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: RoundId,
+    body: Json<CorrectionRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    let round = correct(db, &user.session, id, body.into_inner().name).await?;
+    Ok(Json((&round).into()))
+}
+
+/// Executed payments of the round
+#[openapi(tag = "rounds")]
+#[get("/api/v1/rounds/<id>/payments")]
+pub async fn get_payments(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+    id: RoundId,
+) -> Result<Json<BTreeMap<Account, Payment>>, Status> {
+    let round = find_round(db, id).await?;
+    Ok(Json(round.payments))
+}
+
+/// Record payment of the approved payout. The round becomes paid when
+/// all payouts have successful payments.
+#[openapi(tag = "rounds")]
+#[post("/api/v1/rounds/<id>/payments", data = "<body>")]
+pub async fn add_payment(
+    db: &State<DataBase>,
+    user: AuthedChange<Signer>,
+    id: RoundId,
+    body: Json<PaymentRequest>,
+) -> Result<Json<RoundDetails>, Status> {
+    let body = body.into_inner();
+    if body.tx_hash.len() != 64 || hex::decode(&body.tx_hash).is_err() {
+        warn!("Invalid transaction hash {}", body.tx_hash);
+        return Err(Status::UnprocessableEntity);
+    }
+    let payout = find_round(db, id)
+        .await?
+        .payout(&body.account)
+        .ok_or(Status::NotFound)?;
+    let now = Utc::now().naive_utc();
+    let update = SystemUpdate::AddPayment(AddPayment {
+        round: id,
+        account: body.account.clone(),
+        payment: Payment {
+            tx_hash: body.tx_hash.to_lowercase(),
+            amount: body.amount.unwrap_or(payout.amount),
+            status: body.status,
+            timestamp: now,
+        },
+        timestamp: now,
+    });
+    let round = update_round(db, id, update).await?;
+    info!(
+        "User {} recorded {} payment to {} in round {}",
+        user.key, body.status, body.account, id
+    );
+    Ok(Json((&round).into()))
+}
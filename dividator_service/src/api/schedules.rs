@@ -0,0 +1,129 @@
+use super::auth::guard::{Operator, Viewer};
+use super::auth::{AuthedChange, AuthedUser};
+use super::rounds::RoundRequest;
+use super::types::*;
+use chrono::prelude::*;
+use dividator::state::schedule::{AddSchedule, RemoveSchedule, RunSchedule};
+use dividator::state::{ScheduleId, ScheduleInfo, SchedulePeriod, SystemUpdate};
+use log::*;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, State};
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::Deserialize;
+
+/// Parameters of the rounds that the schedule creates
+#[derive(Deserialize, JsonSchema)]
+pub struct ScheduleRequest {
+    #[serde(flatten)]
+    pub round: RoundRequest,
+    /// Rounds are created at the end of each period in UTC
+    pub period: SchedulePeriod,
+}
+
+/// Create the draft rounds of the schedules which periods are over. Each
+/// period gets one round, so it is safe to call it again after restart.
+pub async fn run_schedules(db: &DataBase) {
+    let now = Utc::now().naive_utc();
+    let mut db = db.lock().await;
+    let due: Vec<_> = db
+        .get()
+        .await
+        .schedules
+        .values()
+        .filter_map(|v| v.due(now).map(|run_at| (v.id, run_at)))
+        .collect();
+    for (schedule, run_at) in due {
+        let round = db.get().await.next_round_id();
+        let res = db
+            .update(SystemUpdate::RunSchedule(RunSchedule {
+                schedule,
+                round,
+                run_at,
+                timestamp: now,
+            }))
+            .await;
+        match res {
+            Ok(_) => info!(
+                "Schedule {} created round {} for the period ending at {}",
+                schedule, round, run_at
+            ),
+            Err(e) => error!("Failed to run schedule {}: {}", schedule, e),
+        }
+    }
+}
+
+/// List schedules of the draft rounds
+#[openapi(tag = "schedules")]
+#[get("/api/v1/schedules")]
+pub async fn list_schedules(
+    db: &State<DataBase>,
+    _user: AuthedUser<Viewer>,
+) -> Json<Vec<ScheduleInfo>> {
+    let db = db.lock().await;
+    let state = db.get().await;
+    Json(state.schedules.values().cloned().collect())
+}
+
+/// Create draft round at the end of each period. The snapshot is not
+/// taken by the schedule, set or import it before the approval.
+#[openapi(tag = "schedules")]
+#[post("/api/v1/schedules", data = "<body>")]
+pub async fn create_schedule(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    body: Json<ScheduleRequest>,
+) -> Result<Json<ScheduleInfo>, Status> {
+    body.round.validate()?;
+    let mut db = db.lock().await;
+    let id = db.get().await.next_schedule_id();
+    let body = body.into_inner();
+    db.update(SystemUpdate::AddSchedule(AddSchedule {
+        id,
+        name: body.round.name,
+        asset: body.round.asset,
+        total: body.round.total,
+        period: body.period,
+        created_by: user.key.clone(),
+        timestamp: Utc::now().naive_utc(),
+    }))
+    .await
+    .map_err(|e| {
+        error!("Failed to create schedule: {}", e);
+        Status::InternalServerError
+    })?;
+    info!("User {} created {} schedule {}", user.key, body.period, id);
+    let state = db.get().await;
+    state
+        .schedules
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or(Status::InternalServerError)
+}
+
+/// Stop the schedule, the rounds it created are kept
+#[openapi(tag = "schedules")]
+#[delete("/api/v1/schedules/<id>")]
+pub async fn delete_schedule(
+    db: &State<DataBase>,
+    user: AuthedChange<Operator>,
+    id: ScheduleId,
+) -> Result<Json<()>, Status> {
+    let mut db = db.lock().await;
+    if !db.get().await.schedules.contains_key(&id) {
+        return Err(Status::NotFound);
+    }
+    db.update(SystemUpdate::RemoveSchedule(RemoveSchedule {
+        id,
+        timestamp: Utc::now().naive_utc(),
+    }))
+    .await
+    .map_err(|e| {
+        error!("Failed to delete schedule {}: {}", id, e);
+        Status::InternalServerError
+    })?;
+    info!("User {} deleted schedule {}", user.key, id);
+    Ok(Json(()))
+}
@@ -0,0 +1,184 @@
+use super::audit::{AuditKind, AuditLog};
+use super::auth::cache::SessionInfo;
+use super::auth::guard::Viewer;
+use super::auth::types::Permission;
+use super::auth::{session_id, AuthedPage, AuthedUser};
+use super::types::*;
+use chrono::NaiveDateTime;
+use log::*;
+use rocket::form::{Form, FromForm};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::{get, post, uri, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use rocket_okapi::JsonSchema;
+use serde::Serialize;
+
+/// Active session as it is shown to the user
+#[derive(Serialize, JsonSchema)]
+pub struct SessionView {
+    /// Public identifier of the session, used for revocation
+    pub id: String,
+    /// Linking key of the session owner
+    pub key: String,
+    /// Permissions of the session
+    pub permissions: Vec<Permission>,
+    /// When the user logged in
+    pub created_at: NaiveDateTime,
+    /// Last time the session was used
+    pub last_activity: NaiveDateTime,
+    /// IP address of the browser
+    pub ip: Option<String>,
+    /// User agent of the browser
+    pub user_agent: Option<String>,
+    /// The session is used to make the request
+    pub current: bool,
+}
+
+impl SessionView {
+    fn new(id: String, session: SessionInfo, current: Option<&str>) -> Self {
+        let mut permissions: Vec<Permission> = session.permissions.into_iter().collect();
+        permissions.sort();
+        SessionView {
+            current: current == Some(id.as_str()),
+            id,
+            key: session.key,
+            permissions,
+            created_at: session.created_at,
+            last_activity: session.last_activity,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        }
+    }
+}
+
+/// Admins see sessions of all users, others only their own
+async fn visible_sessions(
+    store: &AuthSessions,
+    session: &SessionInfo,
+    current: Option<&str>,
+) -> Vec<SessionView> {
+    let current = current.map(session_id);
+    let key = if session.check_permissions(&[Permission::Admin]) {
+        None
+    } else {
+        Some(session.key.as_str())
+    };
+    let mut sessions: Vec<SessionView> = store
+        .list(key)
+        .await
+        .into_iter()
+        .map(|(id, v)| SessionView::new(id, v, current.as_deref()))
+        .collect();
+    sessions.sort_by_key(|v| std::cmp::Reverse(v.last_activity));
+    sessions
+}
+
+#[derive(Serialize)]
+struct SessionsContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    sessions: Vec<SessionView>,
+}
+
+#[openapi(skip)]
+#[get("/sessions")]
+pub async fn sessions(store: &State<AuthSessions>, user: AuthedPage<Viewer>) -> Template {
+    let sessions = visible_sessions(store, &user.session, user.k1.as_deref()).await;
+    let context = SessionsContext {
+        base: BaseContext::new("Sessions", &user.session, user.csrf()),
+        sessions,
+    };
+    Template::render("sessions", context)
+}
+
+/// List active sessions. Admins get sessions of all users.
+#[openapi(tag = "sessions")]
+#[get("/api/sessions")]
+pub async fn sessions_json(
+    store: &State<AuthSessions>,
+    user: AuthedUser<Viewer>,
+) -> Json<Vec<SessionView>> {
+    Json(visible_sessions(store, &user.session, user.k1.as_deref()).await)
+}
+
+#[derive(FromForm)]
+pub struct RevokeForm {
+    csrf: String,
+    id: String,
+}
+
+#[openapi(skip)]
+#[post("/sessions/revoke", data = "<form>")]
+pub async fn revoke(
+    store: &State<AuthSessions>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Viewer>,
+    form: Form<RevokeForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let is_admin = user.check_permissions(&[Permission::Admin]);
+    let found = store
+        .list(None)
+        .await
+        .into_iter()
+        .find(|(id, _)| *id == form.id);
+    match found {
+        Some((id, v)) if is_admin || v.key == user.key => {
+            info!("User {} revoked session of {}", user.key, v.key);
+            store.remove_by_id(&id).await;
+            let details = format!("session {} of {}", form.id, v.key);
+            audit_log
+                .record(
+                    AuditKind::SessionRevoked,
+                    Some(&user.key),
+                    user.ip.as_deref(),
+                    Some(&details),
+                )
+                .await;
+            Ok(Redirect::to(uri!(sessions)))
+        }
+        _ => {
+            warn!("User {} tried to revoke unknown session", user.key);
+            Err(Status::NotFound)
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct RevokeAllForm {
+    csrf: String,
+    /// Whose sessions to revoke, own ones if not set. Only admins
+    /// can revoke sessions of other users.
+    key: Option<String>,
+}
+
+#[openapi(skip)]
+#[post("/sessions/revoke_all", data = "<form>")]
+pub async fn revoke_all(
+    store: &State<AuthSessions>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Viewer>,
+    form: Form<RevokeAllForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let key = form.key.clone().unwrap_or(user.key.clone());
+    if key != user.key && !user.check_permissions(&[Permission::Admin]) {
+        warn!("User {} tried to revoke sessions of {}", user.key, key);
+        return Err(Status::Forbidden);
+    }
+    info!("User {} revoked all sessions of {}", user.key, key);
+    store.remove_user(&key).await;
+    let details = format!("all sessions of {}", key);
+    audit_log
+        .record(
+            AuditKind::SessionRevoked,
+            Some(&user.key),
+            user.ip.as_deref(),
+            Some(&details),
+        )
+        .await;
+    Ok(Redirect::to(uri!(sessions)))
+}
@@ -0,0 +1,195 @@
+use super::audit::{AuditKind, AuditLog};
+use super::auth::guard::Admin;
+use super::auth::types::Permission;
+use super::auth::{generate_token, token_hash, AuthedPage};
+use super::types::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use dividator::state::token::{AddToken, RevokeToken};
+use dividator::state::SystemUpdate;
+use log::*;
+use rocket::form::{Form, FromForm};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::{get, post, uri, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// API token as it is shown on the tokens page
+#[derive(Serialize)]
+struct TokenView {
+    id: String,
+    name: String,
+    role: String,
+    created_by: String,
+    created_at: String,
+    expires_at: String,
+    expired: bool,
+}
+
+#[derive(Serialize)]
+struct TokensContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    tokens: Vec<TokenView>,
+    roles: Vec<String>,
+    /// Freshly minted token that is shown only once
+    minted: Option<String>,
+}
+
+async fn render_tokens(db: &DataBase, base: BaseContext, minted: Option<String>) -> Template {
+    let db = db.lock().await;
+    let state = db.get().await;
+    let now = Utc::now().naive_utc();
+    let mut tokens: Vec<_> = state.tokens.values().collect();
+    tokens.sort_by_key(|v| v.created_at);
+    let tokens = tokens
+        .into_iter()
+        .map(|v| TokenView {
+            id: v.id.clone(),
+            name: v.name.clone(),
+            role: v
+                .permissions
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            created_by: v.created_by.clone(),
+            created_at: v.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            expires_at: v.expires_at.format("%Y-%m-%d %H:%M").to_string(),
+            expired: v.expires_at < now,
+        })
+        .collect();
+    let context = TokensContext {
+        base,
+        tokens,
+        roles: Permission::ALL.iter().map(|p| p.to_string()).collect(),
+        minted,
+    };
+    Template::render("tokens", context)
+}
+
+#[openapi(skip)]
+#[get("/tokens")]
+pub async fn tokens(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    user: AuthedPage<Admin>,
+) -> Template {
+    let minted = match &user.k1 {
+        Some(k1) => cache_mutex.lock().await.pick_minted(k1),
+        None => None,
+    };
+    let base = BaseContext::new("API tokens", &user.session, user.csrf());
+    render_tokens(db, base, minted).await
+}
+
+#[derive(FromForm)]
+pub struct MintForm {
+    csrf: String,
+    #[field(validate = len(1..64))]
+    name: String,
+    role: Permission,
+    #[field(validate = range(1..=365))]
+    days: i64,
+}
+
+#[openapi(skip)]
+#[post("/tokens", data = "<form>")]
+pub async fn mint(
+    db: &State<DataBase>,
+    cache_mutex: &State<AuthCache>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    form: Form<MintForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    // The token is shown after redirect to the session that minted it
+    let k1 = user.k1.clone().ok_or_else(|| {
+        warn!("User {} tried to mint token with API token", user.key);
+        Status::Forbidden
+    })?;
+    let (id, token) = generate_token();
+    let now = Utc::now().naive_utc();
+    let res = db
+        .lock()
+        .await
+        .update(SystemUpdate::AddToken(AddToken {
+            id: id.clone(),
+            name: form.name.clone(),
+            hash: token_hash(&token),
+            permissions: BTreeSet::from([form.role]),
+            created_by: user.key.clone(),
+            expires_at: now + Duration::days(form.days),
+            timestamp: now,
+        }))
+        .await;
+    match res {
+        Ok(_) => {
+            info!(
+                "User {} minted token {} with role {}",
+                user.key, id, form.role
+            );
+            let details = format!("{} ({}) with role {}", id, form.name, form.role);
+            audit_log
+                .record(
+                    AuditKind::TokenMinted,
+                    Some(&user.key),
+                    user.ip.as_deref(),
+                    Some(&details),
+                )
+                .await;
+            cache_mutex.lock().await.add_minted(&k1, token);
+            Ok(Redirect::to(uri!(tokens)))
+        }
+        Err(e) => {
+            error!("Failed to mint token: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct RevokeTokenForm {
+    csrf: String,
+    id: String,
+}
+
+#[openapi(skip)]
+#[post("/tokens/revoke", data = "<form>")]
+pub async fn revoke_token(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    form: Form<RevokeTokenForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let res = db
+        .lock()
+        .await
+        .update(SystemUpdate::RevokeToken(RevokeToken {
+            id: form.id.clone(),
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await;
+    match res {
+        Ok(_) => {
+            info!("User {} revoked token {}", user.key, form.id);
+            audit_log
+                .record(
+                    AuditKind::TokenRevoked,
+                    Some(&user.key),
+                    user.ip.as_deref(),
+                    Some(&form.id),
+                )
+                .await;
+            Ok(Redirect::to(uri!(tokens)))
+        }
+        Err(e) => {
+            warn!("Failed to revoke token {}: {}", form.id, e);
+            Err(Status::BadRequest)
+        }
+    }
+}
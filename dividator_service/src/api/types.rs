@@ -1,5 +1,8 @@
+use crate::api::auth::cache::SessionInfo;
+use crate::api::auth::types::Permission;
 use dividator::db::{AppendDb, Postgres};
 use dividator::state::SystemState;
+use serde::Serialize;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -7,5 +10,39 @@ use tokio::sync::Mutex;
 pub type DataBase = Arc<Mutex<AppendDb<Postgres<SystemState>>>>;
 /// Shortcase for in memory cache for auth sessions
 pub type AuthCache = Arc<Mutex<crate::api::auth::cache::Cache>>;
+/// Shortcase for sessions of logged users, they have own locking
+pub type AuthSessions = Arc<crate::api::auth::session::Sessions>;
+/// Shortcase for rate limiter of auth endpoints
+pub type AuthLimiter = Arc<Mutex<crate::api::auth::limit::RateLimiter>>;
 /// Shortcase for in memory cache for hedging
-pub type SystemCache = Arc<Mutex<dividator::cache::Cache>>;
\ No newline at end of file
+pub type SystemCache = Arc<Mutex<dividator::cache::Cache>>;
+
+/// Context that is shared by all pages rendered for logged in user
+#[derive(Serialize)]
+pub struct BaseContext {
+    /// Title of the page
+    pub title: String,
+    /// Name of the parent template
+    pub parent: &'static str,
+    /// Show sign out button
+    pub signout: bool,
+    /// Most powerful role of the user to show in the nav bar
+    pub role: Option<String>,
+    /// Show links to admin pages
+    pub admin: bool,
+    /// CSRF token that every form must include
+    pub csrf: Option<String>,
+}
+
+impl BaseContext {
+    pub fn new(title: &str, session: &SessionInfo, csrf: Option<String>) -> Self {
+        BaseContext {
+            title: title.to_owned(),
+            parent: "base",
+            signout: true,
+            role: session.role().map(|v| v.to_string()),
+            admin: session.check_permissions(&[Permission::Admin]),
+            csrf,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use super::audit::{AuditKind, AuditLog};
+use super::auth::guard::Admin;
+use super::auth::types::Permission;
+use super::auth::{generate_k1, AuthedPage};
+use super::types::*;
+use chrono::prelude::*;
+use dividator::state::user::{RemoveUser, SetPermissions};
+use dividator::state::SystemUpdate;
+use log::*;
+use rocket::form::{Form, FromForm};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::{get, post, uri, State};
+use rocket_dyn_templates::Template;
+use rocket_okapi::openapi;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Linked user as it is shown on the users page
+#[derive(Serialize)]
+struct UserView {
+    key: String,
+    role: String,
+    created_at: String,
+    editable: bool,
+}
+
+#[derive(Serialize)]
+struct UsersContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    users: Vec<UserView>,
+    roles: Vec<String>,
+    /// Current user is the admin wallet and can rotate it
+    can_rotate: bool,
+}
+
+#[openapi(skip)]
+#[get("/users")]
+pub async fn users(db: &State<DataBase>, user: AuthedPage<Admin>) -> Template {
+    let db = db.lock().await;
+    let state = db.get().await;
+
+    let mut users = vec![];
+    if let Some(admin) = &state.admin {
+        users.push(UserView {
+            key: admin.key.clone(),
+            role: Permission::Admin.to_string(),
+            created_at: admin.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            editable: false,
+        });
+    }
+    let mut linked: Vec<_> = state.users.values().collect();
+    linked.sort_by_key(|v| v.created_at);
+    for linked_user in linked {
+        users.push(UserView {
+            key: linked_user.key.clone(),
+            role: linked_user
+                .permissions
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            created_at: linked_user.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            editable: true,
+        });
+    }
+
+    let context = UsersContext {
+        base: BaseContext::new("Users", &user.session, user.csrf()),
+        users,
+        roles: Permission::ALL.iter().map(|p| p.to_string()).collect(),
+        can_rotate: state.admin_key() == Some(user.key.clone()),
+    };
+    Template::render("users", context)
+}
+
+#[derive(FromForm)]
+pub struct RoleForm {
+    csrf: String,
+    key: String,
+    role: Permission,
+}
+
+#[openapi(skip)]
+#[post("/users/role", data = "<form>")]
+pub async fn set_role(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    form: Form<RoleForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let mut db = db.lock().await;
+    let res = db
+        .update(SystemUpdate::SetPermissions(SetPermissions {
+            key: form.key.clone(),
+            permissions: BTreeSet::from([form.role]),
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await;
+    match res {
+        Ok(_) => {
+            info!(
+                "User {} changed role of {} to {}",
+                user.key, form.key, form.role
+            );
+            let details = format!("{} to {}", form.key, form.role);
+            audit_log
+                .record(
+                    AuditKind::RoleChanged,
+                    Some(&user.key),
+                    user.ip.as_deref(),
+                    Some(&details),
+                )
+                .await;
+            Ok(Redirect::to(uri!(users)))
+        }
+        Err(e) => {
+            warn!("Failed to change role of {}: {}", form.key, e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct InviteForm {
+    csrf: String,
+    role: Permission,
+}
+
+#[openapi(skip)]
+#[post("/users/invite", data = "<form>")]
+pub async fn create_invite(
+    cache_mutex: &State<AuthCache>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    form: Form<InviteForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let k1 = generate_k1();
+    let mut cache = cache_mutex.lock().await;
+    cache.add_invite(&k1, &[form.role]);
+    drop(cache);
+    info!("User {} created invite with role {}", user.key, form.role);
+    let details = format!("role {}", form.role);
+    audit_log
+        .record(
+            AuditKind::InviteCreated,
+            Some(&user.key),
+            user.ip.as_deref(),
+            Some(&details),
+        )
+        .await;
+    Ok(Redirect::to(uri!(invite_created(k1))))
+}
+
+#[derive(Serialize)]
+struct InviteContext {
+    #[serde(flatten)]
+    base: BaseContext,
+    k1: String,
+    link: String,
+    invite_role: String,
+}
+
+#[openapi(skip)]
+#[get("/users/invite?<k1>")]
+pub async fn invite_created(
+    cache_mutex: &State<AuthCache>,
+    domain: &State<String>,
+    user: AuthedPage<Admin>,
+    k1: String,
+) -> Result<Template, Status> {
+    let mut cache = cache_mutex.lock().await;
+    match cache.has_invite(&k1) {
+        Some(invite) => {
+            let role = invite
+                .permissions
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let context = InviteContext {
+                base: BaseContext::new("Invite", &user.session, user.csrf()),
+                link: format!("{}/invite?k1={}", domain.as_str(), k1),
+                k1,
+                invite_role: role,
+            };
+            Ok(Template::render("invite_created", context))
+        }
+        None => Err(Status::NotFound),
+    }
+}
+
+#[derive(FromForm)]
+pub struct RemoveUserForm {
+    csrf: String,
+    key: String,
+}
+
+#[openapi(skip)]
+#[post("/users/remove", data = "<form>")]
+pub async fn remove_user(
+    db: &State<DataBase>,
+    audit_log: &State<AuditLog>,
+    user: AuthedPage<Admin>,
+    form: Form<RemoveUserForm>,
+) -> Result<Redirect, Status> {
+    user.check_csrf(&form.csrf)?;
+    let mut db = db.lock().await;
+    let res = db
+        .update(SystemUpdate::RemoveUser(RemoveUser {
+            key: form.key.clone(),
+            timestamp: Utc::now().naive_utc(),
+        }))
+        .await;
+    match res {
+        Ok(_) => {
+            info!("User {} unlinked {}", user.key, form.key);
+            audit_log
+                .record(
+                    AuditKind::UserRemoved,
+                    Some(&user.key),
+                    user.ip.as_deref(),
+                    Some(&form.key),
+                )
+                .await;
+            Ok(Redirect::to(uri!(users)))
+        }
+        Err(e) => {
+            warn!("Failed to unlink {}: {}", form.key, e);
+            Err(Status::BadRequest)
+        }
+    }
+}
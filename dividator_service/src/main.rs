@@ -1,11 +1,19 @@
 pub mod api;
 
+use crate::api::auth::cache::Cache;
+use crate::api::auth::limit::{HolderLimiter, LimitConfig, RateLimiter};
+use crate::api::auth::session::{
+    MemoryStore, PostgresStore, SessionStore, SessionStoreKind, Sessions,
+};
+use crate::api::auth::stellar::{StellarAuth, PUBLIC_NETWORK};
+use crate::api::internal::serve_internal_api;
+use crate::api::qr::{parse_color, QrEcLevel, QrStyle};
 use crate::api::serve_api;
 use clap::Parser;
 use dividator::db::create_db_pool;
 use dividator::db::{AppendDb, Postgres};
 use dividator::state::{SystemState, SystemUpdate};
-use futures::future::{AbortHandle, Abortable};
+use futures::future::{select, AbortHandle, Abortable, Either};
 use log::*;
 use std::error::Error;
 use std::path::PathBuf;
@@ -54,8 +62,63 @@ pub struct Args {
     /// Base64 encoded 64 byte secret key for encoding cookies. Required in release profile.
     #[clap(long, env = "COOKIES_SECRET_KEY", hide_env_values = true)]
     cookies_secret_key: Option<String>,
+    /// Where to keep sessions of logged users. `postgres` allows sessions
+    /// to survive restarts and to be shared between instances.
+    #[clap(long, value_enum, default_value = "memory", env = "SESSION_STORE")]
+    session_store: SessionStoreKind,
+    /// Stellar secret seed (S...) that signs SEP-10 challenges. Login with
+    /// Stellar wallets is disabled if not set.
+    #[clap(long, env = "STELLAR_SIGNING_KEY", hide_env_values = true)]
+    stellar_signing_key: Option<String>,
+    /// Passphrase of Stellar network that is used for SEP-10 challenges
+    /// and links to payment transactions in the explorer
+    #[clap(long, default_value = PUBLIC_NETWORK, env = "STELLAR_NETWORK")]
+    stellar_network: String,
+    /// Header with the client IP that is set by the reverse proxy, e.g.
+    /// `X-Real-IP`. Clients can send any value in it when they reach the
+    /// service directly, so it is not trusted by default and rate limits
+    /// and sessions use the address of the connection. Failed logins don't
+    /// lock anybody out and holder lookups are not limited without it.
+    #[clap(long, env = "IP_HEADER")]
+    ip_header: Option<String>,
+    /// How many requests to auth endpoints a single IP can make per minute
+    #[clap(long, default_value = "60", env = "AUTH_RATE_LIMIT")]
+    auth_rate_limit: u32,
+    /// How many lookups of holder dividends a single IP can make per
+    /// minute, applied only with `--ip-header`
+    #[clap(long, default_value = "60", env = "HOLDER_RATE_LIMIT")]
+    holder_rate_limit: u32,
+    /// How many failed login attempts from a single IP lead to the lockout
+    #[clap(long, default_value = "5", env = "AUTH_MAX_FAILURES")]
+    auth_max_failures: u32,
+    /// For how many seconds the IP is locked out after too many failed attempts
+    #[clap(long, default_value = "900", env = "AUTH_LOCKOUT")]
+    auth_lockout: i64,
+    /// How many k1 nonces can wait for signature at once. The oldest ones
+    /// are evicted when the limit is reached.
+    #[clap(long, default_value = "10000", env = "MAX_PENDING_K1")]
+    max_pending_k1: usize,
+    /// Color of dark modules of QR codes as hex RGB
+    #[clap(long, default_value = "#44204d", env = "QR_COLOR")]
+    qr_color: String,
+    /// Background color of QR codes as hex RGB
+    #[clap(long, default_value = "#ffffff", env = "QR_BACKGROUND")]
+    qr_background: String,
+    /// Default size of QR codes in pixels
+    #[clap(long, default_value = "300", env = "QR_SIZE")]
+    qr_size: u32,
+    /// Error correction level of QR codes
+    #[clap(long, value_enum, default_value = "medium", env = "QR_EC_LEVEL")]
+    qr_ec_level: QrEcLevel,
+    /// Max size in bytes of CSV files with holder lists that are uploaded
+    /// to the import API. Uploads from the import page are also limited by
+    /// the `data-form` limit of Rocket, see `ROCKET_LIMITS`.
+    #[clap(long, default_value = "10485760", env = "IMPORT_LIMIT")]
+    import_limit: u64,
     /// If the flag set to true, cleans admin information on startup.
     /// That allows to reassign admin account without dropping database.
+    /// Use it only for emergency recovery when the admin wallet is lost,
+    /// otherwise rotate the key from the users page.
     #[clap(long)]
     clean_admin: bool,
 }
@@ -69,12 +132,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let default_secret_key =
         "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=="
             .to_owned();
+    let domain = args
+        .host_domain
+        .clone()
+        .unwrap_or(format!("http://127.0.0.1:{}", args.port).to_owned());
+    let stellar_auth = match &args.stellar_signing_key {
+        Some(seed) => {
+            let home_domain = domain.split("://").last().unwrap_or(&domain);
+            let home_domain = home_domain.split('/').next().unwrap_or(home_domain);
+            let auth = StellarAuth::new(seed, &args.stellar_network, home_domain)?;
+            info!(
+                "Stellar login is enabled, signing account {}",
+                auth.account()
+            );
+            Some(auth)
+        }
+        None => None,
+    };
+    let limits = LimitConfig {
+        requests_per_minute: args.auth_rate_limit,
+        max_failures: args.auth_max_failures,
+        lockout: chrono::Duration::seconds(args.auth_lockout),
+        trusted_ip: args.ip_header.is_some(),
+        max_pending_k1: args.max_pending_k1,
+    };
+    let qr_style = QrStyle {
+        dark: parse_color(&args.qr_color)?,
+        light: parse_color(&args.qr_background)?,
+        size: args.qr_size,
+        ec_level: args.qr_ec_level,
+    };
     let figment_public = rocket::Config::figment()
-        .merge((
-            "domain",
-            args.host_domain
-                .unwrap_or(format!("http://127.0.0.1:{}", args.port).to_owned()),
-        ))
+        .merge(("domain", domain))
+        .merge(("stellar_network", args.stellar_network.clone()))
         .merge((
             "static_path",
             args.static_path
@@ -91,11 +181,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .clone()
                 .unwrap_or(default_secret_key.clone()),
         ))
+        .merge(("import_limit", args.import_limit))
         .merge(("address", args.host))
         .merge(("port", args.port));
+    // Rocket trusts X-Real-IP unless the header is disabled
+    let figment_public = match &args.ip_header {
+        Some(header) => figment_public.merge(("ip_header", header)),
+        None => {
+            warn!(
+                "NO --ip-header IS SET. Clients are told apart by the address of the \
+                connection, behind a reverse proxy all of them share its IP, so \
+                lockouts after failed logins and the holder lookup limit are \
+                disabled. Set --ip-header to the header with the client IP that \
+                the proxy sets."
+            );
+            figment_public.merge(("ip_header", false))
+        }
+    };
+    let figment_internal = rocket::Config::figment()
+        .merge((
+            "secret_key",
+            args.cookies_secret_key
+                .clone()
+                .unwrap_or(default_secret_key.clone()),
+        ))
+        .merge(("address", args.internal_host))
+        .merge(("port", args.internal_port));
 
     info!("Connecting to database");
     let pool = create_db_pool(&args.dbconnect).await?;
+    let session_store: Box<dyn SessionStore> = match args.session_store {
+        SessionStoreKind::Memory => Box::new(MemoryStore::default()),
+        SessionStoreKind::Postgres => Box::new(PostgresStore::new(pool.clone())),
+    };
+    let audit_pool = pool.clone();
     let mut adb = AppendDb::new(Postgres::new(pool), SystemState::default());
     info!("Loading database");
     adb.load().await?;
@@ -106,13 +225,50 @@ async fn main() -> Result<(), Box<dyn Error>> {
     } else {
         let db = Arc::new(Mutex::new(adb));
         let cache = Arc::new(Mutex::new(dividator::cache::Cache::default()));
+        let mut auth_cache = Cache::default();
+        auth_cache.max_keys = limits.max_pending_k1;
+        let auth_cache = Arc::new(Mutex::new(auth_cache));
+        let auth_sessions = Arc::new(Sessions::new(session_store));
+        let holder_limiter = HolderLimiter::new(LimitConfig {
+            requests_per_minute: args.holder_rate_limit,
+            ..limits.clone()
+        });
+        let auth_limiter = Arc::new(Mutex::new(RateLimiter::new(limits)));
 
         info!("Starting listening...");
         let start_notify_public = Arc::new(Notify::new());
-        let public_api_fut = serve_api(start_notify_public, figment_public, db.clone(), cache);
+        let public_api_fut = serve_api(
+            start_notify_public,
+            figment_public,
+            db.clone(),
+            audit_pool,
+            cache,
+            auth_cache.clone(),
+            auth_sessions.clone(),
+            auth_limiter.clone(),
+            holder_limiter,
+            stellar_auth,
+            qr_style,
+        );
+        let start_notify_internal = Arc::new(Notify::new());
+        let internal_api_fut = serve_internal_api(
+            start_notify_internal,
+            figment_internal,
+            db.clone(),
+            auth_cache,
+            auth_sessions,
+            auth_limiter,
+        );
         let (abort_handle, abort_registration) = AbortHandle::new_pair();
         ctrlc::set_handler(move || abort_handle.abort()).expect("Error setting Ctrl-C handler");
-        let joined_fut = public_api_fut;
+        // Both APIs are stopped when any of them stops or fails, the other
+        // server is dropped with its future
+        let joined_fut = async {
+            match select(Box::pin(public_api_fut), Box::pin(internal_api_fut)).await {
+                Either::Left((res, _)) => res,
+                Either::Right((res, _)) => res,
+            }
+        };
         Abortable::new(joined_fut, abort_registration).await??;
     }
     Ok(())
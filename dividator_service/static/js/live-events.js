@@ -0,0 +1,36 @@
+/*
+ * Live updates from the server over server-sent events
+ *
+ * Elements with `data-events` attribute subscribe to the event stream
+ * at the given URL. `data-on-<event>` attribute sets the location to
+ * go to when the event arrives. Other scripts can subscribe with
+ * `liveEvents.subscribe` to handle events themselves.
+ */
+
+const liveEvents = {
+  subscribe(url, handlers) {
+    const source = new EventSource(url);
+    for (const [name, handler] of Object.entries(handlers)) {
+      source.addEventListener(name, (event) => {
+        handler(JSON.parse(event.data), source);
+      });
+    }
+    return source;
+  },
+
+  init() {
+    document.querySelectorAll("[data-events]").forEach((element) => {
+      const handlers = {};
+      for (const [attr, location] of Object.entries(element.dataset)) {
+        if (!attr.startsWith("on")) continue;
+        handlers[attr.slice(2).toLowerCase()] = (_, source) => {
+          source.close();
+          window.location = location;
+        };
+      }
+      this.subscribe(element.dataset.events, handlers);
+    });
+  },
+};
+
+liveEvents.init();
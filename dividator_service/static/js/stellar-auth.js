@@ -0,0 +1,70 @@
+/*
+ * Login with Stellar wallet (SEP-10)
+ *
+ * Requests challenge for the account, signs it with Freighter if the
+ * extension is available, otherwise lets user sign it with SEP-7 wallet
+ * or paste the signed transaction manually.
+ */
+
+const stellarAuth = {
+  formTarget: "#stellar-auth",
+
+  init() {
+    this.form = document.querySelector(this.formTarget);
+    if (!this.form) return;
+    this.k1 = this.form.dataset.k1;
+    this.error = this.form.querySelector(".error");
+    this.form
+      .querySelector(".get-challenge")
+      .addEventListener("click", (event) => {
+        event.preventDefault();
+        this.challenge();
+      });
+    this.form.addEventListener("submit", (event) => {
+      event.preventDefault();
+      this.submit(this.form.elements.signed.value.trim());
+    });
+  },
+
+  async challenge() {
+    const account = this.form.elements.account.value.trim();
+    const params = new URLSearchParams({ account: account, k1: this.k1 });
+    const response = await fetch(`/auth/stellar?${params}`);
+    if (!response.ok) {
+      this.error.textContent = "Invalid Stellar account";
+      return;
+    }
+    const body = await response.json();
+    this.error.textContent = "";
+    this.form.elements.challenge.value = body.transaction;
+    this.form.querySelector(".sep7").href = body.sep7;
+    this.form.querySelector(".signing").hidden = false;
+
+    if (window.freighterApi) {
+      try {
+        const signed = await window.freighterApi.signTransaction(body.transaction, {
+          networkPassphrase: body.network_passphrase,
+        });
+        this.submit(signed);
+      } catch (e) {
+        this.error.textContent = "Freighter didn't sign the challenge, sign it with another wallet";
+      }
+    }
+  },
+
+  async submit(transaction) {
+    const response = await fetch("/auth/stellar", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ transaction: transaction, k1: this.k1 }),
+    });
+    const body = await response.json();
+    if (body.status === "OK") {
+      window.location = "/";
+    } else {
+      this.error.textContent = body.reason;
+    }
+  },
+};
+
+stellarAuth.init();